@@ -49,6 +49,17 @@ mod tests {
                 symbol_type: SymbolType::Define
             })
         );
+        assert_eq!(
+            tags.next(),
+            Some(Symbol {
+                name: "Point".into(),
+                file: "example.c".into(),
+                expression: "/^struct Point {$/".into(),
+                line_num: Some(9),
+                symbol_type: SymbolType::Struct
+            })
+        );
+        assert_eq!(tags.next(), None);
     }
 }
 
@@ -67,12 +78,15 @@ pub struct Symbol {
     pub symbol_type: SymbolType,
 }
 
-#[derive(Debug, Eq, PartialEq, Hash, Clone, Copy)]
+#[derive(Debug, Eq, PartialEq, Hash, Clone, Copy, PartialOrd, Ord)]
 pub enum SymbolType {
     Unknown = 0,
     Function = 1,
     Define = 2,
     Variable = 3,
+    Struct = 4,
+    /// Not emitted by ctags, detected from `EXPORT_SYMBOL(...)` lines by the indexer
+    Export = 5,
 }
 
 impl<R: Read> Ctags<R> {
@@ -119,6 +133,7 @@ impl<R: Read> Iterator for Ctags<R> {
             "f" => SymbolType::Function,
             "d" => SymbolType::Define,
             "v" => SymbolType::Variable,
+            "s" => SymbolType::Struct,
             _ => SymbolType::Unknown,
         };
 
@@ -130,7 +145,7 @@ impl<R: Read> Iterator for Ctags<R> {
                 if self.current_file.is_none() || code_path != self.current_file.as_ref().unwrap().0 {
                     self.current_file.replace((
                         code_path.clone(),
-                        BufReader::new(File::open(&code_path).unwrap()).split('\n' as u8).enumerate()
+                        BufReader::new(File::open(&code_path).unwrap()).split(b'\n').enumerate()
                     ));
                 }
 
@@ -145,7 +160,7 @@ impl<R: Read> Iterator for Ctags<R> {
                             // println!("{}", expression);
                             // println!("||||||||||||||||||||||");
                             if contents == regex.replace_all(
-                                &expression[2..expression.len() - 2].as_bytes(),
+                                &expression.as_bytes()[2..expression.len() - 2],
                                 "$1".as_bytes()
                             ).as_ref() {
                                 Some((line as u64) + 1)
@@ -160,7 +175,7 @@ impl<R: Read> Iterator for Ctags<R> {
                     // Reopen file
                     self.current_file.replace((
                         code_path.clone(),
-                        BufReader::new(File::open(&code_path).unwrap()).split('\n' as u8).enumerate()
+                        BufReader::new(File::open(&code_path).unwrap()).split(b'\n').enumerate()
                     ));
 
                     let line_num = look_for_line(self, &regex, expression);
@@ -191,12 +206,12 @@ impl<R: Read> Iterator for Ctags<R> {
         }
 
         // default case, expression was not parsed correctly
-        return Some(Symbol {
+        Some(Symbol {
             name: name.to_string(),
             file: file.to_string(),
             expression: expression.to_string(),
             line_num: None,
             symbol_type,
-        });
+        })
     }
 }
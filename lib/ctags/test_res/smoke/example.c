@@ -4,4 +4,9 @@ int Hello() {
 }
 
 int World() {
-}
\ No newline at end of file
+}
+
+struct Point {
+    int x;
+    int y;
+};
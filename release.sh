@@ -16,7 +16,18 @@ for proj in sourcedigger-db/{linux,vim,git,musl,glibc}; do
   cp "$proj"/config.toml release/"$proj"/
   cp "$proj"/logo.svg release/"$proj"/
   cp "$proj"/autocomplete_db release/"$proj"/
-  cp -r "$proj"/diffs release/"$proj"/
+  cp "$proj"/versions release/"$proj"/
+  cp "$proj"/lifetimes release/"$proj"/
+  cp "$proj"/name_index release/"$proj"/
+  # The server reads the diffs from diffs.bin, so the TSV diffs/ aren't shipped
+  cp "$proj"/diffs.bin release/"$proj"/
+  cp "$proj"/stats release/"$proj"/
+  cp -r "$proj"/snapshots release/"$proj"/
+  cp -r "$proj"/files release/"$proj"/
+  # References are opt-in, and are kept at the end of each blob's tags
+  if grep -q '^references *= *true' "$proj"/config.toml; then
+    cp -r "$proj"/tags release/"$proj"/
+  fi
 done
 
 echo '--- Compressing'
@@ -34,10 +34,19 @@ pub fn admin_add_repo_post(form_data: Form<AddRepo>) -> Template {
 }
 
 #[get("/repo/<repo_name>")]
+#[allow(unused_variables)]
 pub fn admin_view_repo(repo_name: String) -> Template {
     // let mut context = HashMap::<String, String>::new();
     let proj = Project {
-        repo: ProjectRepo { name: "git".to_string(), origin: "git@github.com:git/git.git".to_string() },
+        repo: ProjectRepo {
+            name: "git".to_string(),
+            origin: "git@github.com:git/git.git".to_string(),
+            source_viewer: "https://github.com/git/git/blob/{tag}/{path}#L{line}".to_string(),
+            version_code: None,
+            repo_path: None,
+            references: false,
+            code_search: false,
+        },
         index: ProjectIndex { initial_ver: "v0.99".to_string(), latest_ver: "v2.9.5".to_string() },
         stats: ProjectStats { searches: 1111, autocompletes: 2222 }
     };
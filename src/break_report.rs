@@ -0,0 +1,169 @@
+use crate::history::{self, DiffAction, NetChange, SymbolData};
+use crate::html;
+use ctags::SymbolType;
+use serde::Serialize;
+use std::path::Path;
+
+/**
+ * Net API/ABI changes between two versions, for answering "what breaks if we upgrade"
+ */
+#[derive(Serialize, Debug)]
+pub struct BreakReport {
+    pub from: String,
+    pub to: String,
+    pub removed_functions: Vec<BreakEntry>,
+    pub changed_signatures: Vec<BreakEntry>,
+    pub removed_exports: Vec<BreakEntry>,
+    pub changed_structs: Vec<BreakEntry>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct BreakEntry {
+    pub name: String,
+    pub old: Vec<SymbolData>,
+    pub new: Vec<SymbolData>,
+}
+
+impl From<NetChange> for BreakEntry {
+    fn from(change: NetChange) -> Self {
+        BreakEntry {
+            name: change.name,
+            old: change.old,
+            new: change.new,
+        }
+    }
+}
+
+/// Only the signatures matter, a function moving between files doesn't break anyone
+fn signatures_differ(change: &NetChange) -> bool {
+    let mut old = change.old.iter().map(|d| &d.extra).collect::<Vec<_>>();
+    let mut new = change.new.iter().map(|d| &d.extra).collect::<Vec<_>>();
+    old.sort();
+    old.dedup();
+    new.sort();
+    new.dedup();
+    old != new
+}
+
+impl BreakReport {
+    pub fn compute(db_path: &Path, from: &str, to: &str) -> Result<Self, String> {
+        let mut report = BreakReport {
+            from: from.to_string(),
+            to: to.to_string(),
+            removed_functions: vec![],
+            changed_signatures: vec![],
+            removed_exports: vec![],
+            changed_structs: vec![],
+        };
+
+        for change in history::net_changes(db_path, from, to)? {
+            match (change.action(), change.symbol_type) {
+                (DiffAction::Remove, SymbolType::Function) => report.removed_functions.push(change.into()),
+                (DiffAction::Remove, SymbolType::Export) => report.removed_exports.push(change.into()),
+                (DiffAction::Modify, SymbolType::Function) if signatures_differ(&change) => {
+                    report.changed_signatures.push(change.into())
+                }
+                (DiffAction::Modify, SymbolType::Struct) if signatures_differ(&change) => {
+                    report.changed_structs.push(change.into())
+                }
+                _ => {}
+            }
+        }
+
+        Ok(report)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    /// `project` is used for linking symbols back to the search page, if the report is served
+    pub fn to_html(&self, project: Option<&str>) -> String {
+        let mut output = format!(
+            "<!DOCTYPE html>\n<meta charset=\"UTF-8\">\n<title>{0} &rarr; {1}</title>\n<link rel=\"stylesheet\" href=\"/static/results-inner.css\">\n<h1>Breaking changes from {0} to {1}</h1>\n",
+            html::escape(&self.from),
+            html::escape(&self.to),
+        );
+
+        let sections = [
+            ("Removed functions", SymbolType::Function, &self.removed_functions),
+            ("Changed signatures", SymbolType::Function, &self.changed_signatures),
+            ("Removed exported symbols", SymbolType::Export, &self.removed_exports),
+            ("Changed struct layouts", SymbolType::Struct, &self.changed_structs),
+        ];
+        for (title, symbol_type, entries) in sections.iter() {
+            output += &format!("<h2 class=h>{} ({})</h2>\n", title, entries.len());
+            for entry in entries.iter() {
+                let name = match project {
+                    Some(project) => format!(
+                        "<a href=\"/{}?q={}\">{}</a>",
+                        project,
+                        url::form_urlencoded::byte_serialize(entry.name.as_bytes()).collect::<String>(),
+                        html::escape(&entry.name)
+                    ),
+                    None => html::escape(&entry.name),
+                };
+                for (class, sign, datas) in [("r", "-", &entry.old), ("a", "+", &entry.new)].iter() {
+                    for data in datas.iter() {
+                        output += &format!(
+                            "<div class={}><a class={}>{}</a><span>{}</span> &nbsp;<code>{}</code><hr><a>{}:{}</a></div>\n",
+                            class,
+                            class,
+                            sign,
+                            name,
+                            html::escape(&history::format_signature(&entry.name, *symbol_type, &data.extra)),
+                            html::escape(&data.file),
+                            data.line
+                        );
+                    }
+                }
+            }
+        }
+
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(entries: &[BreakEntry]) -> Vec<&str> {
+        entries.iter().map(|entry| entry.name.as_str()).collect()
+    }
+
+    #[test]
+    fn categories() {
+        let db_path = std::env::temp_dir().join(format!("sourcedigger-break-report-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&db_path);
+        std::fs::create_dir_all(db_path.join("diffs")).unwrap();
+        let diffs = [
+            ("v1", "a\tgone\tFunction\ta.c\t1\tint {name}(void)\n\
+                    a\tchanged\tFunction\ta.c\t5\tint {name}(void)\n\
+                    a\tmoved\tFunction\ta.c\t9\tint {name}(void)\n\
+                    a\tgone\tExport\ta.c\t3\t\n\
+                    a\tlayout\tStruct\ta.h\t1\tint a;\n\
+                    a\tstatic_fn\tFunction\tx.c\t1\tint {name}(void)\n\
+                    a\tstatic_fn\tFunction\ty.c\t1\tint {name}(void)\n"),
+            ("v2", "r\tgone\tFunction\ta.c\t1\tint {name}(void)\n\
+                    m\tchanged\tFunction\ta.c\t5\tint {name}(int)\n\
+                    m\tmoved\tFunction\tb.c\t2\tint {name}(void)\n\
+                    r\tgone\tExport\ta.c\t3\t\n\
+                    m\tlayout\tStruct\ta.h\t1\tint a; int b;\n\
+                    m\tstatic_fn\tFunction\tz.c\t1\tlong {name}(void)\n"),
+        ];
+        for (version, diff) in diffs.iter() {
+            std::fs::write(db_path.join("diffs").join(version), diff).unwrap();
+        }
+        history::write_versions(&db_path, &["v1".to_string(), "v2".to_string()]);
+
+        let report = BreakReport::compute(&db_path, "v1", "v2").unwrap();
+        assert_eq!(names(&report.removed_functions), vec!["gone"]);
+        // Moving files isn't a break, and modifications of one of several definitions can't be told apart
+        assert_eq!(names(&report.changed_signatures), vec!["changed"]);
+        assert_eq!(report.changed_signatures[0].new[0].extra, "int {name}(int)");
+        assert_eq!(names(&report.removed_exports), vec!["gone"]);
+        assert_eq!(names(&report.changed_structs), vec!["layout"]);
+        std::fs::remove_dir_all(&db_path).unwrap();
+    }
+}
@@ -0,0 +1,320 @@
+use memmap2::Mmap;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/**
+ * Which extracted objects contain each trigram, in the segments of `code_index/`. Each update adds
+ * segments for the objects that aren't indexed yet, so only their postings are ever in memory.
+ * A segment, `code_index/<n>.bin`, is little endian throughout:
+ *
+ * - Header: `MAGIC`, then the offsets of the object table and the trigram table (u64 each)
+ * - Postings: for every trigram, the sorted indices of the objects containing it (u32 each)
+ * - Object table: the object count (u32), then every object's oid (20 bytes each), sorted
+ * - Trigram table: the trigram count (u32), then the trigram, its posting count (u32 each)
+ *   and the offset of its postings (u64) for every trigram, sorted by trigram
+ *
+ * Trigrams are three bytes of ASCII lowercased text in a u32, so one index serves case sensitive
+ * and insensitive searches alike. Candidates still have to be searched, the index only rules out
+ * the objects that can't match.
+ */
+pub struct CodeIndex {
+    segments: Vec<Segment>,
+}
+
+struct Segment {
+    mmap: Mmap,
+    objects_offset: usize,
+    object_count: usize,
+    trigrams_offset: usize,
+    trigram_count: usize,
+}
+
+const MAGIC: &[u8; 8] = b"SDCODE1\0";
+const HEADER_SIZE: usize = 24;
+const OID_SIZE: usize = 20;
+const TRIGRAM_ENTRY_SIZE: usize = 16;
+/// Bounds the postings held in memory while writing
+const OBJECTS_PER_SEGMENT: usize = 20_000;
+
+/// The distinct trigrams of some text, skipping the ones that span lines since searches are per line
+pub fn trigrams(text: &[u8]) -> Vec<u32> {
+    let mut trigrams = text.windows(3)
+        .filter(|w| !w.contains(&b'\n'))
+        .map(|w| {
+            (w[0].to_ascii_lowercase() as u32) << 16 | (w[1].to_ascii_lowercase() as u32) << 8 | w[2].to_ascii_lowercase() as u32
+        })
+        .collect::<Vec<_>>();
+    trigrams.sort_unstable();
+    trigrams.dedup();
+    trigrams
+}
+
+fn write_segment(db_path: &Path, number: usize, objects: &[Vec<u8>]) {
+    let mut postings: HashMap<u32, Vec<u32>> = HashMap::new();
+    for (i, oid) in objects.iter().enumerate() {
+        let content = match std::fs::read(db_path.join("objects").join(hex::encode(oid))) {
+            Ok(content) => content,
+            Err(_) => continue,
+        };
+        if content.contains(&0) {
+            continue;
+        }
+        for trigram in trigrams(&content) {
+            postings.entry(trigram).or_default().push(i as u32);
+        }
+    }
+    let mut trigram_table = postings.into_iter().collect::<Vec<_>>();
+    trigram_table.sort_unstable_by_key(|(trigram, _)| *trigram);
+
+    let posting_bytes = trigram_table.iter().map(|(_, objects)| 4 * objects.len()).sum::<usize>();
+    let objects_offset = HEADER_SIZE + posting_bytes;
+    let trigrams_offset = objects_offset + 4 + OID_SIZE * objects.len();
+
+    // Only complete segments are renamed into place, the server picks them up once they're there
+    let tmp_path = db_path.join("code_index").join(format!("{}.bin.tmp", number));
+    let mut out_file = BufWriter::new(File::create(&tmp_path).unwrap());
+    out_file.write_all(MAGIC).unwrap();
+    out_file.write_all(&(objects_offset as u64).to_le_bytes()).unwrap();
+    out_file.write_all(&(trigrams_offset as u64).to_le_bytes()).unwrap();
+    for (_, object_ids) in trigram_table.iter() {
+        for id in object_ids {
+            out_file.write_all(&id.to_le_bytes()).unwrap();
+        }
+    }
+
+    out_file.write_all(&(objects.len() as u32).to_le_bytes()).unwrap();
+    for oid in objects.iter() {
+        out_file.write_all(oid).unwrap();
+    }
+
+    out_file.write_all(&(trigram_table.len() as u32).to_le_bytes()).unwrap();
+    let mut postings_offset = HEADER_SIZE as u64;
+    for (trigram, object_ids) in trigram_table.iter() {
+        out_file.write_all(&trigram.to_le_bytes()).unwrap();
+        out_file.write_all(&(object_ids.len() as u32).to_le_bytes()).unwrap();
+        out_file.write_all(&postings_offset.to_le_bytes()).unwrap();
+        postings_offset += 4 * object_ids.len() as u64;
+    }
+    out_file.flush().unwrap();
+    drop(out_file);
+    std::fs::rename(&tmp_path, db_path.join("code_index").join(format!("{}.bin", number))).unwrap();
+}
+
+/// Indexes the text files in `objects/` that the existing segments don't have yet.
+/// Binary files, with a NUL byte, are left out.
+#[allow(dead_code)]
+pub fn write_code_index(db_path: &Path) {
+    std::fs::create_dir_all(db_path.join("code_index")).unwrap();
+    // From before the index was split into segments
+    let _ = std::fs::remove_file(db_path.join("code_index.bin"));
+    let numbers = segment_numbers(db_path);
+    let existing = CodeIndex::open(db_path).map_or(vec![], |index| index.segments);
+    let indexed = existing.iter()
+        .flat_map(|segment| (0..segment.object_count as u32).filter_map(move |id| segment.oid(id)))
+        .collect::<HashSet<_>>();
+
+    let mut objects = std::fs::read_dir(db_path.join("objects")).unwrap()
+        .filter_map(|entry| hex::decode(entry.unwrap().file_name().to_string_lossy().as_bytes()).ok())
+        .filter(|oid| oid.len() == OID_SIZE && !indexed.contains(oid.as_slice()))
+        .collect::<Vec<_>>();
+    objects.sort();
+
+    for (i, chunk) in objects.chunks(OBJECTS_PER_SEGMENT).enumerate() {
+        write_segment(db_path, numbers.last().map_or(0, |last| last + 1) + i, chunk);
+    }
+}
+
+impl Segment {
+    /// `None` if it isn't in this format or its tables don't fit in it
+    fn open(path: &Path) -> Option<Self> {
+        let file = File::open(path).ok()?;
+        // Segments are never modified once they're renamed into place, so the mapping stays valid
+        let mmap = unsafe { Mmap::map(&file) }.ok()?;
+        if mmap.len() < HEADER_SIZE || &mmap[..8] != MAGIC {
+            return None;
+        }
+        let objects_offset = u64::from_le_bytes(mmap[8..16].try_into().unwrap()) as usize;
+        let trigrams_offset = u64::from_le_bytes(mmap[16..24].try_into().unwrap()) as usize;
+        let mut segment = Segment { mmap, objects_offset, object_count: 0, trigrams_offset, trigram_count: 0 };
+        segment.object_count = segment.u32_at(objects_offset)? as usize;
+        if objects_offset.checked_add(4 + OID_SIZE * segment.object_count)? > trigrams_offset {
+            return None;
+        }
+        segment.trigram_count = segment.u32_at(trigrams_offset)? as usize;
+        if trigrams_offset.checked_add(4 + TRIGRAM_ENTRY_SIZE * segment.trigram_count)? > segment.mmap.len() {
+            return None;
+        }
+        Some(segment)
+    }
+
+    fn u32_at(&self, offset: usize) -> Option<u32> {
+        Some(u32::from_le_bytes(self.mmap.get(offset..offset.checked_add(4)?)?.try_into().unwrap()))
+    }
+
+    fn oid(&self, id: u32) -> Option<&[u8]> {
+        if id as usize >= self.object_count {
+            return None;
+        }
+        let offset = self.objects_offset + 4 + OID_SIZE * id as usize;
+        self.mmap.get(offset..offset + OID_SIZE)
+    }
+
+    /// The sorted indices of the objects containing `trigram`, empty if the postings don't fit in the file
+    fn postings(&self, trigram: u32) -> Vec<u32> {
+        let entry = |i: usize| self.trigrams_offset + 4 + TRIGRAM_ENTRY_SIZE * i;
+        let (mut low, mut high) = (0, self.trigram_count);
+        while low < high {
+            let middle = (low + high) / 2;
+            if self.u32_at(entry(middle)).unwrap_or(u32::MAX) < trigram {
+                low = middle + 1;
+            } else {
+                high = middle;
+            }
+        }
+        if low == self.trigram_count || self.u32_at(entry(low)) != Some(trigram) {
+            return vec![];
+        }
+        let count = self.u32_at(entry(low) + 4).unwrap_or(0) as usize;
+        let offset = u64::from_le_bytes(self.mmap[entry(low) + 8..entry(low) + 16].try_into().unwrap()) as usize;
+        match offset.checked_add(4 * count) {
+            Some(end) if end <= self.objects_offset => (0..count).filter_map(|i| self.u32_at(offset + 4 * i)).collect(),
+            _ => vec![],
+        }
+    }
+
+    /// Whether the object is in the segment, by binary search since the object table is sorted
+    fn contains(&self, oid: &[u8]) -> bool {
+        let (mut low, mut high) = (0, self.object_count as u32);
+        while low < high {
+            let middle = low + (high - low) / 2;
+            match self.oid(middle) {
+                Some(middle_oid) if middle_oid < oid => low = middle + 1,
+                Some(middle_oid) if middle_oid == oid => return true,
+                Some(_) => high = middle,
+                None => return false,
+            }
+        }
+        false
+    }
+
+    /// The hex oids of the segment's objects containing every trigram in `wanted`
+    fn candidates(&self, wanted: &[u32]) -> Vec<String> {
+        let mut lists = wanted.iter().map(|trigram| self.postings(*trigram)).collect::<Vec<_>>();
+        // Intersecting from the rarest trigram keeps the sets small
+        lists.sort_by_key(|list| list.len());
+        let mut ids = lists[0].iter().cloned().collect::<HashSet<_>>();
+        for list in lists[1..].iter() {
+            if ids.is_empty() {
+                break;
+            }
+            let list = list.iter().cloned().collect::<HashSet<_>>();
+            ids.retain(|id| list.contains(id));
+        }
+        ids.into_iter().filter_map(|id| self.oid(id)).map(hex::encode).collect()
+    }
+}
+
+/// The numbers of the segments in `code_index/`, sorted
+fn segment_numbers(db_path: &Path) -> Vec<usize> {
+    let mut numbers = match std::fs::read_dir(db_path.join("code_index")) {
+        Ok(read_dir) => read_dir
+            .filter_map(|entry| entry.ok()?.file_name().to_string_lossy().strip_suffix(".bin")?.parse().ok())
+            .collect::<Vec<_>>(),
+        Err(_) => vec![],
+    };
+    numbers.sort_unstable();
+    numbers
+}
+
+impl CodeIndex {
+    /// `None` if the project has no `code_index/`. Segments that can't be read are left out.
+    pub fn open(db_path: &Path) -> Option<Self> {
+        if !db_path.join("code_index").is_dir() {
+            return None;
+        }
+        let segments = segment_numbers(db_path).into_iter()
+            .filter_map(|number| Segment::open(&db_path.join("code_index").join(format!("{}.bin", number))))
+            .collect();
+        Some(CodeIndex { segments })
+    }
+
+    /// The hex oids of the indexed objects containing every trigram of every literal, `None` if the
+    /// literals are too short to have any, in which case every object is a candidate.
+    /// Objects extracted since the last update aren't ruled out, see `is_indexed`.
+    #[allow(dead_code)]
+    pub fn candidates(&self, literals: &[String]) -> Option<HashSet<String>> {
+        let mut wanted = literals.iter().flat_map(|literal| trigrams(literal.as_bytes())).collect::<Vec<_>>();
+        wanted.sort_unstable();
+        wanted.dedup();
+        if wanted.is_empty() {
+            return None;
+        }
+        Some(self.segments.iter().flat_map(|segment| segment.candidates(&wanted)).collect())
+    }
+
+    /// Whether a segment has the object, so that `candidates` leaving it out rules it out
+    #[allow(dead_code)]
+    pub fn is_indexed(&self, oid: &str) -> bool {
+        match hex::decode(oid) {
+            Ok(oid) => self.segments.iter().any(|segment| segment.contains(&oid)),
+            Err(_) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{write_code_index, CodeIndex};
+    use std::collections::HashSet;
+
+    #[test]
+    fn updates_add_segments() {
+        let db_path = std::env::temp_dir().join(format!("sourcedigger-code-index-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&db_path);
+        std::fs::create_dir_all(db_path.join("objects")).unwrap();
+        let (a, b) = ("aa".repeat(20), "bb".repeat(20));
+        std::fs::write(db_path.join("objects").join(&a), "void *kmalloc(size_t size, gfp_t flags);\n").unwrap();
+        write_code_index(&db_path);
+        std::fs::write(db_path.join("objects").join(&b), "static int KFREE;\n\0binary").unwrap();
+        std::fs::write(db_path.join("objects").join("c".repeat(40)), "void kmalloc_array(void);\n").unwrap();
+        write_code_index(&db_path);
+        write_code_index(&db_path);
+
+        let index = CodeIndex::open(&db_path).unwrap();
+        assert_eq!(index.segments.len(), 2);
+        let expected = vec![a.clone(), "c".repeat(40)].into_iter().collect::<HashSet<_>>();
+        assert_eq!(index.candidates(&["KMALLOC".to_string()]), Some(expected));
+        // Binary objects aren't indexed
+        assert_eq!(index.candidates(&["kfree".to_string()]), Some(HashSet::new()));
+        assert_eq!(index.candidates(&["gfp_t".to_string(), "size_t".to_string()]), Some(vec![a].into_iter().collect()));
+        assert_eq!(index.candidates(&["ab".to_string()]), None);
+        assert!(index.is_indexed(&"bb".repeat(20)) && index.is_indexed(&"c".repeat(40)));
+
+        // Extracted after the last update
+        std::fs::write(db_path.join("objects").join("dd".repeat(20)), "void kmalloc_node(void);\n").unwrap();
+        let index = CodeIndex::open(&db_path).unwrap();
+        assert!(!index.is_indexed(&"dd".repeat(20)));
+        assert!(!index.is_indexed("not hex"));
+        std::fs::remove_dir_all(&db_path).unwrap();
+    }
+
+    #[test]
+    fn truncated_segments_are_left_out() {
+        let db_path = std::env::temp_dir().join(format!("sourcedigger-code-index-truncated-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&db_path);
+        std::fs::create_dir_all(db_path.join("objects")).unwrap();
+        std::fs::write(db_path.join("objects").join("aa".repeat(20)), "void *kmalloc(size_t size);\n").unwrap();
+        write_code_index(&db_path);
+        let segment_path = db_path.join("code_index").join("0.bin");
+        let content = std::fs::read(&segment_path).unwrap();
+        for len in [4, 30, content.len() - 30, content.len() - 1].iter() {
+            std::fs::write(&segment_path, &content[..*len]).unwrap();
+            let index = CodeIndex::open(&db_path).unwrap();
+            assert_eq!(index.segments.len(), 0, "opened {} of {} bytes", len, content.len());
+        }
+        std::fs::remove_dir_all(&db_path).unwrap();
+    }
+}
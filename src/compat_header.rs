@@ -0,0 +1,264 @@
+use crate::data::{ProjectRepo, VersionCode};
+use crate::history::{self, SymbolSet};
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+/// Used when the project's config doesn't say how its tags map to version codes
+fn default_version_code(project_name: &str) -> VersionCode {
+    let prefix = macro_name(project_name);
+    VersionCode {
+        pattern: r"(\d+)\.(\d+)(?:\.(\d+))?".to_string(),
+        code_macro: format!("{}_VERSION_CODE", prefix),
+        version_macro: format!("{}_VERSION", prefix),
+    }
+}
+
+fn macro_name(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+        .collect()
+}
+
+/// A line of comment, with any `*/` in the names and signatures broken up so it can't end early
+fn comment(text: &str) -> String {
+    format!("/* {} */\n", text.replace("*/", "* /"))
+}
+
+/// e.g. `v4.13` -> `KERNEL_VERSION(4,13,0)`
+fn version_code_of(version_code: &VersionCode, pattern: &Regex, tag: &str) -> Option<String> {
+    let caps = pattern.captures(tag)?;
+    let components = (1..=3)
+        .map(|i| caps.get(i).map(|m| m.as_str()).unwrap_or("0"))
+        .collect::<Vec<_>>();
+    Some(format!("{}({})", version_code.version_macro, components.join(",")))
+}
+
+/// Consecutive versions (as indices into the range) for which `present` holds. The end is exclusive.
+fn runs<F: Fn(usize) -> bool>(len: usize, present: F) -> Vec<(usize, Option<usize>)> {
+    let mut result = vec![];
+    let mut start = None;
+    for i in 0..len {
+        match (start, present(i)) {
+            (None, true) => start = Some(i),
+            (Some(s), false) => {
+                result.push((s, Some(i)));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(s) = start {
+        result.push((s, None));
+    }
+    result
+}
+
+/// Every signature of every symbol with this name, as one comparable variant
+fn signatures_of(symbols: &SymbolSet, name: &str) -> Option<Vec<String>> {
+    let mut signatures = symbols.symbols.iter()
+        .filter(|(key, _)| key.0 == name)
+        .flat_map(|(key, datas)| datas.iter().map(move |d| history::format_signature(&key.0, key.1, &d.extra)))
+        .collect::<Vec<_>>();
+    if signatures.is_empty() {
+        return None;
+    }
+    signatures.sort();
+    signatures.dedup();
+    Some(signatures)
+}
+
+/// The distinct signature variants of a symbol in the range, in order of first appearance
+fn variants(timeline: &[Option<Vec<String>>]) -> Vec<&Vec<String>> {
+    let mut variants = vec![];
+    for signatures in timeline.iter().flatten() {
+        if !variants.contains(&signatures) {
+            variants.push(signatures);
+        }
+    }
+    variants
+}
+
+/**
+ * Generates `HAVE_<SYMBOL>` macros for out-of-tree code, guarded by the version each symbol
+ * (or each of its signature variants) appeared in. Names whose macros would clash, e.g. `foo`
+ * and `FOO`, are rejected rather than given conflicting guards.
+ */
+pub fn compat_header(db_path: &Path, config: &ProjectRepo, symbols: &[String], from: &str, to: &str) -> Result<String, String> {
+    let mut unique = HashSet::new();
+    let symbols = symbols.iter().filter(|name| unique.insert(name.as_str())).cloned().collect::<Vec<_>>();
+    let versions = history::read_versions(db_path);
+    let from_idx = history::version_index(&versions, from).ok_or_else(|| format!("Unknown version: {}", from))?;
+    let to_idx = history::version_index(&versions, to).ok_or_else(|| format!("Unknown version: {}", to))?;
+    if from_idx > to_idx {
+        return Err(format!("{} is newer than {}", from, to));
+    }
+
+    let version_code = config.version_code.clone().unwrap_or_else(|| default_version_code(&config.name));
+    let pattern = Regex::new(&version_code.pattern)
+        .map_err(|e| format!("Invalid version code pattern: {}", e))?;
+
+    // The state of each symbol at every version in the range
+    let wanted = symbols.iter().map(String::as_str).collect::<HashSet<_>>();
+    let mut symbol_set = history::symbols_at(db_path, &versions, from_idx, |r| wanted.contains(r.name.as_str()));
+    let mut timelines: Vec<Vec<Option<Vec<String>>>> = vec![vec![]; symbols.len()];
+    let mut diffs = history::DiffReader::new(db_path);
+    for (i, version) in versions.iter().enumerate().take(to_idx + 1).skip(from_idx) {
+        if i != from_idx {
+            for record in diffs.read(version) {
+                if wanted.contains(record.name.as_str()) {
+                    symbol_set.apply(&record);
+                }
+            }
+        }
+        for (timeline, name) in timelines.iter_mut().zip(symbols.iter()) {
+            timeline.push(signatures_of(&symbol_set, name));
+        }
+    }
+
+    let mut macro_owners: HashMap<String, &str> = HashMap::new();
+    for (timeline, name) in timelines.iter().zip(symbols.iter()) {
+        let have_macro = format!("HAVE_{}", macro_name(name));
+        let variant_count = variants(timeline).len();
+        let variant_macros = (1..=variant_count).filter(|_| variant_count > 1).map(|i| format!("{}_SIG{}", have_macro, i));
+        for macro_name in std::iter::once(have_macro.clone()).chain(variant_macros) {
+            if let Some(owner) = macro_owners.insert(macro_name.clone(), name) {
+                return Err(format!("`{}` and `{}` would both define {}", owner, name, macro_name));
+            }
+        }
+    }
+
+    let range = &versions[from_idx..=to_idx];
+    let condition = |runs: &[(usize, Option<usize>)]| -> Result<String, String> {
+        let mut alternatives = vec![];
+        for (start, end) in runs {
+            let mut bounds = vec![];
+            // Present at the start of the range, so it doesn't matter when exactly it appeared
+            if *start != 0 {
+                let code = version_code_of(&version_code, &pattern, &range[*start])
+                    .ok_or_else(|| range[*start].clone())?;
+                bounds.push(format!("{} >= {}", version_code.code_macro, code));
+            }
+            if let Some(end) = end {
+                let code = version_code_of(&version_code, &pattern, &range[*end])
+                    .ok_or_else(|| range[*end].clone())?;
+                bounds.push(format!("{} < {}", version_code.code_macro, code));
+            }
+            if bounds.is_empty() {
+                return Ok("1".to_string());
+            }
+            alternatives.push(bounds.join(" && "));
+        }
+        if alternatives.len() == 1 {
+            Ok(alternatives.remove(0))
+        } else {
+            Ok(alternatives.iter().map(|a| format!("({})", a)).collect::<Vec<_>>().join(" || "))
+        }
+    };
+    let define = |macro_name: &str, runs: &[(usize, Option<usize>)]| -> String {
+        match condition(runs) {
+            Ok(cond) if cond == "1" => format!("#define {} 1\n", macro_name),
+            Ok(cond) => format!("#if {}\n#define {} 1\n#endif\n", cond, macro_name),
+            Err(tag) => comment(&format!("{}: can't map version {} to a version code", macro_name, tag)),
+        }
+    };
+
+    let mut output = format!(
+        "{}#ifndef SOURCEDIGGER_COMPAT_H\n#define SOURCEDIGGER_COMPAT_H\n",
+        comment(&format!("Generated by SourceDigger for {} {}...{}", config.name, from, to))
+    );
+    for (timeline, name) in timelines.iter().zip(symbols.iter()) {
+        let have_macro = format!("HAVE_{}", macro_name(name));
+        output += "\n";
+
+        let presence = runs(timeline.len(), |i| timeline[i].is_some());
+        if presence.is_empty() {
+            output += &comment(&format!("{}: not present between {} and {}", name, from, to));
+            continue;
+        }
+        output += &comment(name);
+        output += &define(&have_macro, &presence);
+
+        // Signature variants, numbered by first appearance
+        let variants = variants(timeline);
+        if variants.len() > 1 {
+            for (i, variant) in variants.iter().enumerate() {
+                output += &comment(&variant.join(" / "));
+                let variant_runs = runs(timeline.len(), |j| timeline[j].as_ref() == Some(*variant));
+                output += &define(&format!("{}_SIG{}", have_macro, i + 1), &variant_runs);
+            }
+        }
+    }
+    output += "\n#endif /* SOURCEDIGGER_COMPAT_H */\n";
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signature_variants_are_guarded() {
+        let db_path = std::env::temp_dir().join(format!("sourcedigger-compat-header-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&db_path);
+        std::fs::create_dir_all(db_path.join("diffs")).unwrap();
+        let diffs = [
+            ("v4.0", "a\tbar\tFunction\ta.c\t1\tvoid {name}(void)\n"),
+            ("v4.5", "a\tfoo\tFunction\ta.c\t5\tint {name}(void)\n"),
+            ("v5.0", "m\tfoo\tFunction\ta.c\t5\tint {name}(int)\n"),
+            ("v5.2", ""),
+        ];
+        for (version, diff) in diffs.iter() {
+            std::fs::write(db_path.join("diffs").join(version), diff).unwrap();
+        }
+        history::write_versions(&db_path, &diffs.iter().map(|(version, _)| version.to_string()).collect::<Vec<_>>());
+        let kernel = ProjectRepo {
+            name: "Linux".to_string(),
+            origin: String::new(),
+            source_viewer: String::new(),
+            version_code: Some(VersionCode {
+                pattern: r"^v(\d+)\.(\d+)(?:\.(\d+))?".to_string(),
+                code_macro: "LINUX_VERSION_CODE".to_string(),
+                version_macro: "KERNEL_VERSION".to_string(),
+            }),
+            repo_path: None,
+            references: false,
+            code_search: false,
+        };
+        let symbols = ["bar", "foo", "baz", "foo"].iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(compat_header(&db_path, &kernel, &symbols, "v4.0", "v5.2").unwrap(), "\
+            /* Generated by SourceDigger for Linux v4.0...v5.2 */\n\
+            #ifndef SOURCEDIGGER_COMPAT_H\n#define SOURCEDIGGER_COMPAT_H\n\n\
+            /* bar */\n#define HAVE_BAR 1\n\n\
+            /* foo */\n#if LINUX_VERSION_CODE >= KERNEL_VERSION(4,5,0)\n#define HAVE_FOO 1\n#endif\n\
+            /* int foo(void) */\n#if LINUX_VERSION_CODE >= KERNEL_VERSION(4,5,0) && LINUX_VERSION_CODE < KERNEL_VERSION(5,0,0)\n#define HAVE_FOO_SIG1 1\n#endif\n\
+            /* int foo(int) */\n#if LINUX_VERSION_CODE >= KERNEL_VERSION(5,0,0)\n#define HAVE_FOO_SIG2 1\n#endif\n\n\
+            /* baz: not present between v4.0 and v5.2 */\n\
+            \n#endif /* SOURCEDIGGER_COMPAT_H */\n");
+
+        let clashing = ["foo", "FOO"].iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert!(compat_header(&db_path, &kernel, &clashing, "v4.0", "v5.2").is_err());
+        let clashing_variant = ["foo", "foo_sig1"].iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert!(compat_header(&db_path, &kernel, &clashing_variant, "v4.0", "v5.2").is_err());
+        std::fs::remove_dir_all(&db_path).unwrap();
+    }
+
+    #[test]
+    fn default_version_codes() {
+        let version_code = default_version_code("my-lib");
+        let pattern = Regex::new(&version_code.pattern).unwrap();
+        assert_eq!(version_code.code_macro, "MY_LIB_VERSION_CODE");
+        assert_eq!(version_code_of(&version_code, &pattern, "release-2.35").as_deref(), Some("MY_LIB_VERSION(2,35,0)"));
+        assert_eq!(version_code_of(&version_code, &pattern, "v1.2.3").as_deref(), Some("MY_LIB_VERSION(1,2,3)"));
+        assert_eq!(version_code_of(&version_code, &pattern, "latest"), None);
+    }
+
+    #[test]
+    fn comments_cant_be_closed_early() {
+        assert_eq!(comment("kmalloc"), "/* kmalloc */\n");
+        assert_eq!(comment("void f(int /* flags */)"), "/* void f(int /* flags * /) */\n");
+        assert_eq!(comment("*/#define X"), "/* * /#define X */\n");
+    }
+}
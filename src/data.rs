@@ -1,24 +1,57 @@
 use serde::{Serialize, Deserialize};
+use std::path::Path;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ProjectRepo {
     pub name: String,
     pub origin: String,
     pub source_viewer: String,
+    pub version_code: Option<VersionCode>,
+    /// The bare repository the project was indexed from, for the built-in source viewer
+    pub repo_path: Option<String>,
+    /// Whether the indexer appends identifier references to `tags/`, which takes about as much space as the sources
+    #[serde(default)]
+    pub references: bool,
+    /// Whether the indexer builds `code_index/` for searching the sources, a segment of trigrams for the new objects on every update
+    #[serde(default)]
+    pub code_search: bool,
 }
 
+impl ProjectRepo {
+    #[allow(dead_code)]
+    pub fn load(db_path: &Path) -> Result<ProjectRepo, String> {
+        let data_string = std::fs::read_to_string(db_path.join("config.toml"))
+            .map_err(|e| format!("Failed to read config: {}", e))?;
+        toml::from_str(&data_string).map_err(|e| format!("Invalid config: {}", e))
+    }
+}
+
+/// How tag names map to numeric version codes, for compatibility headers
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VersionCode {
+    /// Captures up to 3 numeric components, e.g. `^v(\d+)\.(\d+)(?:\.(\d+))?`
+    pub pattern: String,
+    /// The macro holding the version being compiled against, e.g. `LINUX_VERSION_CODE`
+    pub code_macro: String,
+    /// The macro building a version code from its components, e.g. `KERNEL_VERSION`
+    pub version_macro: String,
+}
+
+#[allow(dead_code)]
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ProjectIndex {
     pub initial_ver: String,
     pub latest_ver: String,
 }
 
+#[allow(dead_code)]
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ProjectStats {
     pub searches: u64,
     pub autocompletes: u64,
 }
 
+#[allow(dead_code)]
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Project {
     pub repo: ProjectRepo,
@@ -0,0 +1,317 @@
+use crate::history::{self, DiffAction, DiffRecord, SymbolData};
+use ctags::SymbolType;
+use memmap2::Mmap;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/**
+ * All of a project's diffs in one file, `diffs.bin`. Little endian throughout:
+ *
+ * - Header: `MAGIC`, then the offsets of the string table and the version table (u64 each)
+ * - Records: `RECORD_SIZE` bytes each, grouped by version in the same order as the TSV files:
+ *   action (u8), symbol type (u8), 2 unused bytes, then the name, file, line and extra (u32 each),
+ *   where the name, file and extra are indices into the string table
+ * - String table: the string count (u32), the end offset of every string (u32 each), then the strings
+ * - Version table: the version count (u32), then the name, first record and record count of every version (u32 each)
+ *
+ * File paths and signatures repeat across thousands of records, so each is only stored once.
+ */
+pub struct DiffStore {
+    mmap: Mmap,
+    record_count: usize,
+    strings_offset: usize,
+    string_count: usize,
+    versions_offset: usize,
+    version_count: usize,
+}
+
+const MAGIC: &[u8; 8] = b"SDDIFF1\0";
+const HEADER_SIZE: usize = 24;
+const RECORD_SIZE: usize = 20;
+
+fn action_code(action: DiffAction) -> u8 {
+    match action {
+        DiffAction::Add => 0,
+        DiffAction::Remove => 1,
+        DiffAction::Modify => 2,
+    }
+}
+
+fn action_from_code(code: u8) -> DiffAction {
+    match code {
+        0 => DiffAction::Add,
+        1 => DiffAction::Remove,
+        _ => DiffAction::Modify,
+    }
+}
+
+fn symbol_type_from_code(code: u8) -> SymbolType {
+    match code {
+        1 => SymbolType::Function,
+        2 => SymbolType::Define,
+        3 => SymbolType::Variable,
+        4 => SymbolType::Struct,
+        5 => SymbolType::Export,
+        _ => SymbolType::Unknown,
+    }
+}
+
+#[derive(Default)]
+struct Interner {
+    ids: HashMap<String, u32>,
+    strings: Vec<String>,
+}
+
+impl Interner {
+    fn intern(&mut self, s: &str) -> u32 {
+        if let Some(id) = self.ids.get(s) {
+            return *id;
+        }
+        let id = self.strings.len() as u32;
+        self.ids.insert(s.to_string(), id);
+        self.strings.push(s.to_string());
+        id
+    }
+}
+
+/// Converts the TSV `diffs/` of these versions into `diffs.bin`
+#[allow(dead_code)]
+pub fn write_diff_store(db_path: &Path, versions: &[String]) {
+    let mut strings = Interner::default();
+    let mut records = vec![];
+    let mut version_table = vec![];
+    for version in versions {
+        let first_record = records.len() / RECORD_SIZE;
+        for record in history::read_diff(db_path, version) {
+            records.push(action_code(record.action));
+            records.push(record.symbol_type as u8);
+            records.extend_from_slice(&[0, 0]);
+            records.extend_from_slice(&strings.intern(&record.name).to_le_bytes());
+            records.extend_from_slice(&strings.intern(&record.data.file).to_le_bytes());
+            records.extend_from_slice(&(record.data.line as u32).to_le_bytes());
+            records.extend_from_slice(&strings.intern(&record.data.extra).to_le_bytes());
+        }
+        let record_count = records.len() / RECORD_SIZE - first_record;
+        version_table.push((strings.intern(version), first_record as u32, record_count as u32));
+    }
+
+    let strings_offset = HEADER_SIZE + records.len();
+    let mut string_ends = vec![];
+    let mut string_end = 0u32;
+    for s in strings.strings.iter() {
+        string_end += s.len() as u32;
+        string_ends.push(string_end);
+    }
+    let versions_offset = strings_offset + 4 + 4 * string_ends.len() + string_end as usize;
+
+    // The server may have the old file mapped, so it's replaced rather than overwritten
+    let tmp_path = db_path.join("diffs.bin.tmp");
+    let mut out_file = BufWriter::new(File::create(&tmp_path).unwrap());
+    out_file.write_all(MAGIC).unwrap();
+    out_file.write_all(&(strings_offset as u64).to_le_bytes()).unwrap();
+    out_file.write_all(&(versions_offset as u64).to_le_bytes()).unwrap();
+    out_file.write_all(&records).unwrap();
+
+    out_file.write_all(&(string_ends.len() as u32).to_le_bytes()).unwrap();
+    for end in string_ends {
+        out_file.write_all(&end.to_le_bytes()).unwrap();
+    }
+    for s in strings.strings.iter() {
+        out_file.write_all(s.as_bytes()).unwrap();
+    }
+
+    out_file.write_all(&(version_table.len() as u32).to_le_bytes()).unwrap();
+    for (name, first_record, record_count) in version_table {
+        for n in [name, first_record, record_count].iter() {
+            out_file.write_all(&n.to_le_bytes()).unwrap();
+        }
+    }
+    out_file.flush().unwrap();
+    drop(out_file);
+    std::fs::rename(&tmp_path, db_path.join("diffs.bin")).unwrap();
+}
+
+/**
+ * One fixed-width record, with its strings still interned
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct StoredRecord {
+    pub action: DiffAction,
+    pub symbol_type: SymbolType,
+    pub name: u32,
+    pub file: u32,
+    pub line: u32,
+    pub extra: u32,
+}
+
+impl DiffStore {
+    /// `None` if the project has no `diffs.bin`, it isn't in this format or its tables don't fit in it
+    pub fn open(db_path: &Path) -> Option<Self> {
+        let file = File::open(db_path.join("diffs.bin")).ok()?;
+        // The indexer replaces the file rather than modifying it, so the mapping stays valid
+        let mmap = unsafe { Mmap::map(&file) }.ok()?;
+        if mmap.len() < HEADER_SIZE || &mmap[..8] != MAGIC {
+            return None;
+        }
+        let strings_offset = u64::from_le_bytes(mmap[8..16].try_into().unwrap()) as usize;
+        let versions_offset = u64::from_le_bytes(mmap[16..24].try_into().unwrap()) as usize;
+        if strings_offset < HEADER_SIZE || (strings_offset - HEADER_SIZE) % RECORD_SIZE != 0 || versions_offset < strings_offset {
+            return None;
+        }
+        let mut store = DiffStore {
+            mmap,
+            record_count: (strings_offset - HEADER_SIZE) / RECORD_SIZE,
+            strings_offset,
+            string_count: 0,
+            versions_offset,
+            version_count: 0,
+        };
+        store.string_count = store.checked_u32_at(strings_offset)? as usize;
+        if strings_offset.checked_add(4 + 4 * store.string_count)? > versions_offset {
+            return None;
+        }
+        store.version_count = store.checked_u32_at(versions_offset)? as usize;
+        if versions_offset.checked_add(4 + 12 * store.version_count)? > store.mmap.len() {
+            return None;
+        }
+        for i in 0..store.version_count {
+            let entry = store.versions_offset + 4 + 12 * i;
+            let (first_record, record_count) = (store.u32_at(entry + 4) as usize, store.u32_at(entry + 8) as usize);
+            if store.u32_at(entry) as usize >= store.string_count || first_record + record_count > store.record_count {
+                return None;
+            }
+        }
+        Some(store)
+    }
+
+    fn checked_u32_at(&self, offset: usize) -> Option<u32> {
+        Some(u32::from_le_bytes(self.mmap.get(offset..offset.checked_add(4)?)?.try_into().unwrap()))
+    }
+
+    /// Only for offsets within the tables `open` checked
+    fn u32_at(&self, offset: usize) -> u32 {
+        u32::from_le_bytes(self.mmap[offset..offset + 4].try_into().unwrap())
+    }
+
+    /// Empty for ids and string ends outside the table, which only a corrupt file has
+    pub fn string(&self, id: u32) -> &str {
+        if id as usize >= self.string_count {
+            return "";
+        }
+        let ends = self.strings_offset + 4;
+        let data = ends + 4 * self.string_count;
+        let start = if id == 0 { 0 } else { self.u32_at(ends + 4 * (id as usize - 1)) as usize };
+        let end = self.u32_at(ends + 4 * id as usize) as usize;
+        self.mmap.get(data + start..data + end)
+            .filter(|_| data + end <= self.versions_offset)
+            .and_then(|bytes| std::str::from_utf8(bytes).ok())
+            .unwrap_or("")
+    }
+
+    /// The stored versions, with the index of their first record and their record count
+    pub fn versions(&self) -> HashMap<String, (usize, usize)> {
+        (0..self.version_count)
+            .map(|i| {
+                let entry = self.versions_offset + 4 + 12 * i;
+                (
+                    self.string(self.u32_at(entry)).to_string(),
+                    (self.u32_at(entry + 4) as usize, self.u32_at(entry + 8) as usize),
+                )
+            })
+            .collect()
+    }
+
+    /// `None` past the last record
+    pub fn record(&self, i: usize) -> Option<StoredRecord> {
+        if i >= self.record_count {
+            return None;
+        }
+        let offset = HEADER_SIZE + RECORD_SIZE * i;
+        Some(StoredRecord {
+            action: action_from_code(self.mmap[offset]),
+            symbol_type: symbol_type_from_code(self.mmap[offset + 1]),
+            name: self.u32_at(offset + 4),
+            file: self.u32_at(offset + 8),
+            line: self.u32_at(offset + 12),
+            extra: self.u32_at(offset + 16),
+        })
+    }
+
+    /// `record_count` records from `first_record` on, as listed in `versions`, the way
+    /// `history::read_diff` reads them from `diffs/`
+    pub fn records(&self, first_record: usize, record_count: usize) -> Vec<DiffRecord> {
+        (first_record..first_record + record_count)
+            .filter_map(|i| self.record(i))
+            .map(|record| self.to_diff_record(&record))
+            .collect()
+    }
+
+    pub fn to_diff_record(&self, record: &StoredRecord) -> DiffRecord {
+        DiffRecord {
+            action: record.action,
+            name: self.string(record.name).to_string(),
+            symbol_type: record.symbol_type,
+            data: SymbolData {
+                file: self.string(record.file).to_string(),
+                line: record.line as u64,
+                extra: self.string(record.extra).to_string(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DiffStore;
+    use crate::history;
+    use std::path::PathBuf;
+
+    fn test_db(name: &str) -> PathBuf {
+        let db_path = std::env::temp_dir().join(format!("sourcedigger-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&db_path);
+        std::fs::create_dir_all(db_path.join("diffs")).unwrap();
+        std::fs::write(db_path.join("diffs").join("v1"), "a\tkmalloc\tFunction\tmm/slab.c\t10\tvoid *{name}(size_t size)\na\tPAGE_SIZE\tDefine\tinclude/page.h\t3\t4096\n").unwrap();
+        std::fs::write(db_path.join("diffs").join("v2"), "m\tkmalloc\tFunction\tmm/slab.c\t12\tvoid *{name}(size_t size, gfp_t flags)\n").unwrap();
+        db_path
+    }
+
+    #[test]
+    fn round_trip() {
+        let db_path = test_db("diff-store");
+        let versions = vec!["v1".to_string(), "v2".to_string()];
+        super::write_diff_store(&db_path, &versions);
+        let store = DiffStore::open(&db_path).unwrap();
+        let stored_versions = store.versions();
+        for version in versions.iter() {
+            let (first_record, record_count) = stored_versions[version];
+            let stored = store.records(first_record, record_count);
+            let read = history::read_diff(&db_path, version);
+            assert_eq!(stored.len(), read.len());
+            for (stored, read) in stored.iter().zip(read.iter()) {
+                assert_eq!((stored.action, &stored.name, &stored.data), (read.action, &read.name, &read.data));
+            }
+        }
+        assert!(!stored_versions.contains_key("v3"));
+        assert!(store.record(3).is_none());
+
+        // Without the TSV diffs, they're read from the store
+        std::fs::remove_dir_all(db_path.join("diffs")).unwrap();
+        assert_eq!(history::read_diff(&db_path, "v2")[0].data.line, 12);
+        std::fs::remove_dir_all(&db_path).unwrap();
+    }
+
+    #[test]
+    fn truncated_files_are_rejected() {
+        let db_path = test_db("diff-store-truncated");
+        super::write_diff_store(&db_path, &["v1".to_string(), "v2".to_string()]);
+        let content = std::fs::read(db_path.join("diffs.bin")).unwrap();
+        for len in [10, 30, content.len() - 20, content.len() - 1].iter() {
+            std::fs::write(db_path.join("diffs.bin"), &content[..*len]).unwrap();
+            assert!(DiffStore::open(&db_path).is_none(), "opened {} of {} bytes", len, content.len());
+        }
+        std::fs::remove_dir_all(&db_path).unwrap();
+    }
+}
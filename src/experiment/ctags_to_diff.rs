@@ -1,4 +1,10 @@
+use crate::history;
+use crate::references;
+use crate::lifetimes;
+use crate::diff_store;
+use crate::name_index;
 use crate::repo_to_ctags;
+use crate::version_stats;
 use ctags::SymbolType;
 use git2::{Oid, Repository};
 use regex::Regex;
@@ -29,13 +35,13 @@ pub struct TagID {
 
 impl PartialOrd for TagID {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(alphanumeric_sort::compare_str(&self.name.to_ascii_lowercase(), &other.name.to_ascii_lowercase()))
+        Some(self.cmp(other))
     }
 }
 
 impl Ord for TagID {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        alphanumeric_sort::compare_str(&self.name.to_ascii_lowercase(), &other.name.to_ascii_lowercase())
+        alphanumeric_sort::compare_str(self.name.to_ascii_lowercase(), other.name.to_ascii_lowercase())
     }
 }
 
@@ -70,6 +76,10 @@ where
 
     for line in tags {
         let line = line.unwrap();
+        // The references come after the symbols
+        if references::is_reference_line(&line) {
+            break;
+        }
         let mut parts = line.splitn(4, "\t");
         let (name, tag_type, line_num, extra_data) = (
             parts.next().unwrap(),
@@ -77,6 +87,8 @@ where
                 "Function" => SymbolType::Function,
                 "Define" => SymbolType::Define,
                 "Variable" => SymbolType::Variable,
+                "Struct" => SymbolType::Struct,
+                "Export" => SymbolType::Export,
                 _ => SymbolType::Unknown,
             },
             parts.next().unwrap().parse::<u64>().unwrap(),
@@ -87,6 +99,45 @@ where
     }
 }
 
+/**
+ * Pairs up the definitions of a symbol that exists in both versions.
+ * Line numbers are ignored, otherwise every unrelated edit above a symbol would show up as a change.
+ */
+fn diff_common_symbol(prev_datas: &[TagData], new_datas: &[TagData]) -> Vec<(TagAction, TagData)> {
+    let mut prev_left: Vec<&TagData> = prev_datas.iter().collect();
+    let mut new_left: Vec<&TagData> = vec![];
+
+    // Drop definitions that didn't change
+    for new_data in new_datas {
+        let same = prev_left.iter().position(|p| p.file == new_data.file && p.extra_data == new_data.extra_data);
+        if let Some(idx) = same {
+            prev_left.remove(idx);
+        } else {
+            new_left.push(new_data);
+        }
+    }
+
+    // A single definition that changed its file is a modification too (it moved)
+    if prev_left.len() == 1 && new_left.len() == 1 {
+        return vec![(TagAction::Modify, new_left[0].clone())];
+    }
+
+    let mut result = vec![];
+    for new_data in new_left {
+        let same_file = prev_left.iter().position(|p| p.file == new_data.file);
+        if let Some(idx) = same_file {
+            prev_left.remove(idx);
+            result.push((TagAction::Modify, new_data.clone()));
+        } else {
+            result.push((TagAction::Add, new_data.clone()));
+        }
+    }
+    for prev_data in prev_left {
+        result.push((TagAction::Remove, prev_data.clone()));
+    }
+    result
+}
+
 pub fn ctags_to_diff(
     repo: &Repository,
     db_path: &PathBuf,
@@ -120,11 +171,12 @@ pub fn ctags_to_diff(
             let common_ids = prev_ids.intersection(&new_ids);
             let removed_ids = prev_ids.difference(&new_ids);
 
-            let common_count = common_ids.count();
-            if common_count != 0 {
-                println!("TODO: ignoring {} changes", common_count);
+            for common_id in common_ids {
+                for (action, data) in diff_common_symbol(&prev_ctags[common_id], &new_ctags[common_id]) {
+                    diff_counter.fetch_add(1, Ordering::SeqCst);
+                    diffs.push((action, common_id.clone(), data));
+                }
             }
-
             for removed_id in removed_ids {
                 for removed_data in prev_ctags[removed_id].iter() {
                     diff_counter.fetch_add(1, Ordering::SeqCst);
@@ -162,7 +214,7 @@ pub fn ctags_to_diff(
         current_tag = next_tag_name.to_string();
     };
 
-    let tags = repo_to_ctags::collect_tags(&repo, tag_pattern, tag_time_sort);
+    let tags = repo_to_ctags::collect_tags(repo, tag_pattern, tag_time_sort);
     let non_existent_tags = tags.iter().enumerate().filter_map(|(i, tag)| {
         let diff_path = Path::new(db_path)
             .join("diffs")
@@ -212,7 +264,7 @@ pub fn ctags_to_diff(
                         name: name.to_string(),
                         tag_type,
                     })
-                    .or_insert_with(Vec::new)
+                    .or_default()
                     .push(TagData {
                         file: file_path.to_string(),
                         line_num,
@@ -222,6 +274,17 @@ pub fn ctags_to_diff(
         },
     );
 
+    // The server needs the same version ordering that was used here
+    let versions = tags.iter()
+        .map(|tag| tag.replace("/", "-"))
+        .filter(|tag| Path::new(db_path).join("diffs").join(tag).exists())
+        .collect::<Vec<_>>();
+    history::write_versions(db_path, &versions);
+    lifetimes::write_lifetimes(db_path, &versions);
+    name_index::write_name_index(db_path, &versions);
+    diff_store::write_diff_store(db_path, &versions);
+    version_stats::write_stats(db_path, &versions);
+
     println!(
         "[progress:100%] Created {} comparisons of {} objects in {} ms",
         diff_counter.into_inner(),
@@ -1,5 +1,32 @@
 mod ctags_to_diff;
 mod repo_to_ctags;
+mod snapshots;
+#[path="../history.rs"]
+mod history;
+#[path="../html.rs"]
+mod html;
+#[path="../lifetimes.rs"]
+mod lifetimes;
+#[path="../break_report.rs"]
+mod break_report;
+#[path="../data.rs"]
+mod data;
+#[path="../compat_header.rs"]
+mod compat_header;
+#[path="../name_index.rs"]
+mod name_index;
+#[path="../diff_store.rs"]
+mod diff_store;
+#[path="../version_stats.rs"]
+mod version_stats;
+#[path="../version_files.rs"]
+mod version_files;
+#[path="../references.rs"]
+mod references;
+#[path="../sorted_lines.rs"]
+mod sorted_lines;
+#[path="../code_index.rs"]
+mod code_index;
 use git2::Repository;
 use regex::Regex;
 use std::ffi::OsString;
@@ -9,7 +36,7 @@ use std::os::unix::ffi::OsStrExt;
 
 fn osstr_to_regex(s: OsString) -> Regex {
     Regex::new(
-        &String::from_utf8(s.as_bytes().to_vec()).expect("Regex contained invalid UTF-8")
+        std::str::from_utf8(s.as_bytes()).expect("Regex contained invalid UTF-8")
     ).expect("Invalid regex")
 }
 
@@ -30,7 +57,75 @@ fn read_args() -> (OsString, PathBuf, Regex, Regex) {
     (project_name, repo_path, tag_pattern, file_pattern)
 }
 
-fn main() {
+/// ./sourcedigger-experiment <ProjectName> break-report <FromVersion> <ToVersion> [html|json]
+fn break_report_command(args: &[String]) {
+    if args.len() != 5 && args.len() != 6 {
+        println!("Usage: ./sourcedigger-process <ProjectName> break-report <FromVersion> <ToVersion> [html|json]");
+        std::process::exit(1);
+    }
+    let db_path = Path::new("sourcedigger-db").join(&args[1]);
+    let report = match break_report::BreakReport::compute(&db_path, &args[3], &args[4]) {
+        Ok(report) => report,
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    match args.get(5).map(String::as_str) {
+        Some("json") => println!("{}", report.to_json()),
+        _ => println!("{}", report.to_html(None)),
+    }
+}
+
+/// ./sourcedigger-experiment <ProjectName> compat-header <FromVersion> <ToVersion> <Symbol>...
+fn compat_header_command(args: &[String]) {
+    if args.len() < 6 {
+        println!("Usage: ./sourcedigger-process <ProjectName> compat-header <FromVersion> <ToVersion> <Symbol>...");
+        std::process::exit(1);
+    }
+    let db_path = Path::new("sourcedigger-db").join(&args[1]);
+    let header = data::ProjectRepo::load(&db_path)
+        .and_then(|config| compat_header::compat_header(&db_path, &config, &args[5..], &args[3], &args[4]));
+    match header {
+        Ok(header) => print!("{}", header),
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// ./sourcedigger-experiment <ProjectName> convert-diffs
+/// Writes `diffs.bin` for databases indexed before it existed, and the name index pointing into it
+fn convert_diffs_command(args: &[String]) {
+    let db_path = Path::new("sourcedigger-db").join(&args[1]);
+    let start = Instant::now();
+    let versions = history::read_versions(&db_path);
+    diff_store::write_diff_store(&db_path, &versions);
+    name_index::write_name_index(&db_path, &versions);
+    println!("Converted {} versions in {}ms", versions.len(), start.elapsed().as_millis());
+}
+
+/// ./sourcedigger-experiment <ProjectName> stats
+/// Writes `stats` for databases indexed before it existed
+fn stats_command(args: &[String]) {
+    let db_path = Path::new("sourcedigger-db").join(&args[1]);
+    let start = Instant::now();
+    let versions = history::read_versions(&db_path);
+    version_stats::write_stats(&db_path, &versions);
+    println!("Counted {} versions in {}ms", versions.len(), start.elapsed().as_millis());
+}
+
+/// ./sourcedigger-experiment <ProjectName> code-index
+/// Adds the extracted objects that aren't in `code_index/` yet to it, without updating the rest
+fn code_index_command(args: &[String]) {
+    let db_path = Path::new("sourcedigger-db").join(&args[1]);
+    let start = Instant::now();
+    code_index::write_code_index(&db_path);
+    println!("Indexed the sources in {}ms", start.elapsed().as_millis());
+}
+
+fn update_index() {
     // Params
     let (project_name, repo_path, tag_pattern, file_pattern) = read_args();
     let tag_time_sort = false;
@@ -38,6 +133,8 @@ fn main() {
     // Open repo
     let db_path = Path::new("sourcedigger-db").join(&project_name);
     let repo = Repository::open(&repo_path).unwrap();
+    let config = data::ProjectRepo::load(&db_path).ok();
+    let with_references = config.as_ref().is_some_and(|config| config.references);
 
     // Symbols for each object
     let start = Instant::now();
@@ -47,13 +144,31 @@ fn main() {
         &repo,
         Some(&tag_pattern),
         Some(&file_pattern),
-        tag_time_sort
+        tag_time_sort,
+        with_references
     );
     ctags_to_diff::ctags_to_diff(&repo,
                                  &db_path,Some(&tag_pattern), Some(&file_pattern),
                                  tag_time_sort);
+    snapshots::write_snapshots(&db_path);
+    if config.as_ref().is_some_and(|config| config.code_search) {
+        println!("[progress_title] Indexing sources");
+        code_index::write_code_index(&db_path);
+    }
     println!(
         "[footer] Finished update in {}ms",
         start.elapsed().as_millis()
     );
 }
+
+fn main() {
+    let args = std::env::args().collect::<Vec<_>>();
+    match args.get(2).map(String::as_str) {
+        Some("break-report") => break_report_command(&args),
+        Some("compat-header") => compat_header_command(&args),
+        Some("convert-diffs") => convert_diffs_command(&args),
+        Some("stats") => stats_command(&args),
+        Some("code-index") => code_index_command(&args),
+        _ => update_index(),
+    }
+}
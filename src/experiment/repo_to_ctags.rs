@@ -4,6 +4,7 @@ use std::io::{BufReader, BufWriter, Write, Read};
 use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
 use std::time::Instant;
 
 use ctags::{Ctags, SymbolType};
@@ -12,6 +13,9 @@ use regex::Regex;
 use std::fs::File;
 use subprocess::ExitStatus;
 
+use crate::references;
+use crate::version_files;
+
 pub fn collect_tags(repo: &Repository, pattern: Option<&Regex>, time_sort: bool) -> Vec<String> {
     let mut tags = vec![];
     let mut timed_tags = vec![];
@@ -27,10 +31,10 @@ pub fn collect_tags(repo: &Repository, pattern: Option<&Regex>, time_sort: bool)
             if time_sort {
                 timed_tags.push((
                     commit.time().seconds(),
-                    String::from_utf8_lossy(name).split('/').last().unwrap().to_string()
+                    String::from_utf8_lossy(name).split('/').next_back().unwrap().to_string()
                 ));
             } else {
-                tags.push(String::from_utf8_lossy(name).split('/').last().unwrap().to_string());
+                tags.push(String::from_utf8_lossy(name).split('/').next_back().unwrap().to_string());
             }
         }
         true
@@ -79,7 +83,7 @@ fn tag_to_commit<'repo>(
 
 pub fn iter_objects_in_tags<TagCB1, TagCB2, FileCB>(
     repo: &Repository,
-    tags: &Vec<&str>,
+    tags: &[&str],
     file_pattern: Option<&Regex>,
     mut pre_tag_callback: TagCB1,
     mut post_tag_callback: TagCB2,
@@ -119,24 +123,38 @@ where
     obj_count
 }
 
+/// Also writes each tag's `files/<version>` list if it's missing
 fn collect_objects(
+    db_path: &Path,
     repo: &Repository,
-    tags: &Vec<&str>,
+    tags: &[&str],
     file_pattern: Option<&Regex>,
 ) -> HashSet<Oid> {
     let start = Instant::now();
     println!("[progress_title] Collecting objects");
 
+    std::fs::create_dir_all(db_path.join("files")).unwrap();
     let mut objects = HashSet::new();
+    let files_out = Mutex::new(None);
     let obj_count = iter_objects_in_tags(repo, tags, file_pattern, |i, tag_name| {
         println!(
             "[progress:{:.2}%] Scanning tag: {}",
             (i as f64) / (tags.len() as f64) * 100.,
             tag_name
         );
+        let files_path = version_files::version_files_path(db_path, tag_name);
+        if !files_path.exists() {
+            files_out.lock().unwrap().replace(BufWriter::new(File::create(files_path).unwrap()));
+        }
     }, |_, _| {
-    }, |_tag_name, obj_id, _file_path| {
+        if let Some(mut out) = files_out.lock().unwrap().take() {
+            out.flush().unwrap();
+        }
+    }, |_tag_name, obj_id, file_path| {
         objects.insert(obj_id);
+        if let Some(out) = files_out.lock().unwrap().as_mut() {
+            out.write_all(format!("{}\t{}\n", file_path, hex::encode(obj_id.as_bytes())).as_bytes()).unwrap();
+        }
     });
 
     println!(
@@ -165,7 +183,7 @@ fn write_objects(project_name: &OsStr, repo: &Repository, objects: &HashSet<Oid>
         let obj_path = &objects_path.join(hex::encode(obj.as_bytes()));
         // TODO: racy code
         if !obj_path.exists() {
-            created_objects.insert(obj.clone());
+            created_objects.insert(*obj);
             // Print only about once every 0.1% of progress
             let i = counter.fetch_add(1, Ordering::SeqCst);
             if (((i - last_print_counter.load(Ordering::SeqCst)) as f64) / (objects_len as f64))
@@ -176,7 +194,7 @@ fn write_objects(project_name: &OsStr, repo: &Repository, objects: &HashSet<Oid>
                 println!(
                     "[progress:{:.2}%] Extracting object: {}",
                     (i as f64) / (objects_len as f64) * 100.,
-                    hex::encode(&obj.as_bytes())
+                    hex::encode(obj.as_bytes())
                 );
             }
 
@@ -306,7 +324,7 @@ impl RetvalState {
         if self.is_const {
             result += "const ";
         }
-        if self.type_name.len() != 0 {
+        if !self.type_name.is_empty() {
             result += &self.type_name;
         } else {
             result += "int";
@@ -318,7 +336,7 @@ impl RetvalState {
     }
 
     fn try_regex(&mut self, pattern: Regex, line: &str) -> bool {
-        if let Some(p1_res) = pattern.captures(&line) {
+        if let Some(p1_res) = pattern.captures(line) {
             let (is_const, type_prefix, type_name, ptr_level) =
                 (
                     p1_res.get(1).map(|m| !m.as_str().is_empty()).unwrap_or(false),
@@ -327,7 +345,7 @@ impl RetvalState {
                     p1_res.get(4).unwrap().as_str().len()
                 );
             self.is_const = is_const;
-            if type_prefix.len() == 0 {
+            if type_prefix.is_empty() {
                 self.type_name = type_name.to_string();
             } else {
                 self.type_name = format!("{} {}", type_prefix, type_name);
@@ -377,7 +395,7 @@ fn get_func_ret_at_line(symbol_name: &str, source_code: &str, line_num: Option<u
             return "unknown_t".to_string();
         };
         let initial_chunk = caps.get(1)
-            .expect(&format!("line with function ({}) didn't have open parenthesis: {}", symbol_name, first_line))
+            .unwrap_or_else(|| panic!("line with function ({}) didn't have open parenthesis: {}", symbol_name, first_line))
             .as_str().trim();
 
         let mut retval = RetvalState { is_const: false, type_name: "".to_string(), ptr_level: 0 };
@@ -390,10 +408,8 @@ fn get_func_ret_at_line(symbol_name: &str, source_code: &str, line_num: Option<u
                         return result;
                     }
                     break
-                } else {
-                    if let Some(result) = retval.feed(line) {
-                        return result;
-                    }
+                } else if let Some(result) = retval.feed(line) {
+                    return result;
                 }
             }
         }
@@ -436,11 +452,168 @@ fn get_var_contents_at_line(source_code: &str, line_num: Option<u64>) -> String
     }
 }
 
+/// Everything after the macro's name, e.g. `(x) ((x) + 1)` or ` 0`, without comments
+fn get_define_value_at_line(symbol_name: &str, source_code: &str, line_num: Option<u64>) -> String {
+    if let Some(line_num) = line_num {
+        let mut definition = String::new();
+        for line in source_code.lines().skip((line_num - 1) as usize).take(20) {
+            if let Some(continued) = line.strip_suffix('\\') {
+                definition += continued;
+                definition += " ";
+            } else {
+                definition += line;
+                break;
+            }
+        }
+
+        let mut without_comments = String::new();
+        let mut rest = definition.as_str();
+        while let Some(start) = rest.find("/*") {
+            without_comments += &rest[..start];
+            rest = match rest[start..].find("*/") {
+                Some(end) => &rest[start + end + 2..],
+                None => "",
+            };
+        }
+        without_comments += rest;
+        if let Some(start) = without_comments.find("//") {
+            without_comments.truncate(start);
+        }
+
+        let value = match without_comments.find(symbol_name) {
+            Some(idx) => &without_comments[idx + symbol_name.len()..],
+            None => return "".to_string(),
+        };
+        // Function-like macros have their arguments right after the name
+        if value.starts_with('(') {
+            fix_whitespace(value.trim())
+        } else {
+            let value = fix_whitespace(value.trim());
+            if value.is_empty() { value } else { " ".to_string() + &value }
+        }
+    } else {
+        "".to_string()
+    }
+}
+
+fn get_struct_members_at_line(source_code: &str, line_num: Option<u64>) -> String {
+    if let Some(line_num) = line_num {
+        let mut body = String::new();
+        let mut depth = 0;
+        let mut done = false;
+        let mut in_comment = false;
+        for line in source_code.lines().skip((line_num - 1) as usize).take(200) {
+            if done {
+                break;
+            }
+            let mut chars = line.chars().peekable();
+            while let Some(c) = chars.next() {
+                if in_comment {
+                    if c == '*' && chars.peek() == Some(&'/') {
+                        chars.next();
+                        in_comment = false;
+                    }
+                    continue;
+                }
+                if c == '/' && chars.peek() == Some(&'*') {
+                    chars.next();
+                    in_comment = true;
+                    continue;
+                }
+                if c == '/' && chars.peek() == Some(&'/') {
+                    break;
+                }
+                if c == '{' {
+                    depth += 1;
+                    if depth == 1 {
+                        continue;
+                    }
+                } else if c == '}' {
+                    depth -= 1;
+                    if depth == 0 {
+                        done = true;
+                        break;
+                    }
+                }
+                if depth != 0 {
+                    body.push(c);
+                }
+            }
+            if depth != 0 {
+                body.push(' ');
+            }
+        }
+        if !done {
+            return "???".to_string();
+        }
+
+        // Preprocessor lines inside the body are kept as-is, they're part of the layout too
+        body.split(';')
+            .map(|member| fix_whitespace(member.trim()))
+            .filter(|member| !member.is_empty())
+            .collect::<Vec<_>>()
+            .join("; ")
+    } else {
+        "???".to_string()
+    }
+}
+
+/// Finds `EXPORT_SYMBOL(name)` style lines, which ctags doesn't report
+fn get_exported_symbols(source_code: &str) -> Vec<(String, u64)> {
+    let pattern = Regex::new(r"^\s*EXPORT_SYMBOL[A-Z_]*\(\s*([a-zA-Z_][a-zA-Z0-9_]*)\s*[,)]").unwrap();
+    source_code.lines()
+        .enumerate()
+        .filter_map(|(i, line)| {
+            pattern.captures(line).map(|cap| (cap[1].to_string(), (i + 1) as u64))
+        })
+        .collect()
+}
+
+/// Adds references to the `tags/<oid>` of the objects that don't have them yet, including ones extracted
+/// before references were enabled. Runs after the symbols were written, objects without any get a tags file here.
+fn write_references(db_path: &Path, objects: &HashSet<Oid>) {
+    let start = Instant::now();
+    println!("[progress_title] Extracting references");
+
+    let tags_path = db_path.join("tags");
+    std::fs::create_dir_all(&tags_path).unwrap();
+    let mut counter = 0usize;
+    let mut last_print_counter = 0usize;
+    for (i, obj) in objects.iter().enumerate() {
+        let obj_name = hex::encode(obj.as_bytes());
+        let out_path = tags_path.join(&obj_name);
+        if references::has_references(&out_path) {
+            continue;
+        }
+        // Print only about once every 0.1% of progress
+        if ((i - last_print_counter) as f64) / (objects.len() as f64) > 0.001 {
+            last_print_counter = i;
+            println!(
+                "[progress:{:.2}%] Extracting references: {}",
+                (i as f64) / (objects.len() as f64) * 100.,
+                obj_name
+            );
+        }
+
+        let mut contents = vec![];
+        File::open(db_path.join("objects").join(&obj_name)).unwrap().read_to_end(&mut contents).unwrap();
+        references::append_references(&out_path, &String::from_utf8_lossy(&contents));
+        counter += 1;
+    }
+
+    println!(
+        "[progress:100%] Extracted references of {} objects in {} ms",
+        counter,
+        start.elapsed().as_millis()
+    );
+}
+
 fn get_extra_info_at_line(symbol_type: SymbolType, symbol_name: &str, source_code: &str, line_num: Option<u64>) -> String {
     match symbol_type {
         SymbolType::Function => get_func_ret_at_line(symbol_name, source_code, line_num) + " {name}" + &get_func_args_at_line(source_code, line_num),
         SymbolType::Variable => get_var_contents_at_line(source_code, line_num),
-        // SymbolType::Define => {},
+        SymbolType::Struct => get_struct_members_at_line(source_code, line_num),
+        SymbolType::Define => get_define_value_at_line(symbol_name, source_code, line_num),
         _ => "".to_string(),
     }
 }
@@ -452,10 +625,11 @@ pub fn repo_to_ctags(
     tag_pattern: Option<&Regex>,
     file_pattern: Option<&Regex>,
     tag_time_sort: bool,
+    with_references: bool,
 ) -> usize {
-    let tags = collect_tags(&repo, tag_pattern, tag_time_sort);
-    let objects = collect_objects(&repo, &tags.iter().map(String::as_str).collect::<Vec<_>>(), file_pattern);
-    let new_objects = write_objects(project_name, &repo, &objects);
+    let tags = collect_tags(repo, tag_pattern, tag_time_sort);
+    let objects = collect_objects(db_path, repo, &tags.iter().map(String::as_str).collect::<Vec<_>>(), file_pattern);
+    let new_objects = write_objects(project_name, repo, &objects);
     let ctags_file = parse_objects(project_name, &new_objects);
 
     // Split ctags per git object
@@ -482,6 +656,10 @@ pub fn repo_to_ctags(
         if symbol.symbol_type == SymbolType::Unknown {
             continue;
         }
+        // Anonymous structs get generated names, which aren't useful across versions
+        if symbol.symbol_type == SymbolType::Struct && symbol.name.starts_with("__anon") {
+            continue;
+        }
         if current_obj != symbol.file {
             current_obj = symbol.file.clone();
             let out_path = tags_basepath.join(Path::new(&current_obj).file_name().unwrap());
@@ -498,6 +676,13 @@ pub fn repo_to_ctags(
                 current_out_file.replace(File::create(out_path).unwrap());
                 is_file_skipped = false;
 
+                for (name, line_num) in get_exported_symbols(&current_inp_file) {
+                    current_out_file.as_mut().unwrap().write_all(
+                        format!("{}\t{:?}\t{}\t\n", name, SymbolType::Export, line_num).as_bytes(),
+                    ).unwrap();
+                    sym_counter += 1;
+                }
+
                 // Print only about once every 0.1% of progress
                 let i = file_counter.fetch_add(1, Ordering::SeqCst);
                 if (((i - last_print_counter.load(Ordering::SeqCst)) as f64)
@@ -536,5 +721,8 @@ pub fn repo_to_ctags(
         start.elapsed().as_millis()
     );
 
+    if with_references {
+        write_references(db_path, &objects);
+    }
     sym_counter
 }
@@ -0,0 +1,75 @@
+use crate::history::{self, SymbolSet};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+/// A full copy of the symbols every this many versions, so the server doesn't have to replay everything
+const SNAPSHOT_INTERVAL: usize = 16;
+
+fn write_snapshot(db_path: &Path, version: &str, symbols: &SymbolSet) {
+    let mut out_file = BufWriter::new(File::create(history::snapshot_path(db_path, version)).unwrap());
+    out_file.write_all(history::snapshot_header().as_bytes()).unwrap();
+    for ((name, tag_type), datas) in symbols.symbols.iter() {
+        for data in datas {
+            out_file.write_all(format!(
+                "a\t{}\t{:?}\t{}\t{}\t{}\n",
+                name,
+                tag_type,
+                data.file,
+                data.line,
+                data.extra
+            ).as_bytes()).unwrap();
+        }
+    }
+}
+
+pub fn write_snapshots(db_path: &Path) {
+    let start = Instant::now();
+    println!("[progress_title] Writing snapshots");
+    std::fs::create_dir_all(db_path.join("snapshots")).unwrap();
+
+    let versions = history::read_versions(db_path);
+    let is_checkpoint = |i: usize| i % SNAPSHOT_INTERVAL == SNAPSHOT_INTERVAL - 1;
+
+    // Continue from the last snapshot we already have, unless it's from older diffs or the diffs
+    // before it were rewritten since
+    let modified = |path: PathBuf| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let is_current = |i: usize| {
+        let written = match modified(history::snapshot_path(db_path, &versions[i])) {
+            Some(written) => written,
+            None => return false,
+        };
+        history::is_current_snapshot(db_path, &versions[i])
+            && versions[..=i].iter().all(|v| modified(db_path.join("diffs").join(v)).is_some_and(|m| m <= written))
+    };
+    let resume_idx = (0..versions.len()).rev()
+        .find(|i| is_checkpoint(*i) && is_current(*i));
+    let (mut symbols, first_idx) = match resume_idx {
+        Some(idx) => (history::symbols_at(db_path, &versions, idx, |_| true), idx + 1),
+        None => (SymbolSet::default(), 0),
+    };
+
+    let mut snapshot_counter = 0usize;
+    let mut diffs = history::DiffReader::new(db_path);
+    for (i, version) in versions.iter().enumerate().skip(first_idx) {
+        for record in diffs.read(version) {
+            symbols.apply(&record);
+        }
+        if is_checkpoint(i) {
+            println!(
+                "[progress:{:.2}%] Writing snapshot: {}",
+                (i as f64) / (versions.len() as f64) * 100.,
+                version
+            );
+            write_snapshot(db_path, version, &symbols);
+            snapshot_counter += 1;
+        }
+    }
+
+    println!(
+        "[progress:100%] Wrote {} snapshots in {} ms",
+        snapshot_counter,
+        start.elapsed().as_millis()
+    );
+}
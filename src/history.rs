@@ -0,0 +1,427 @@
+use crate::diff_store::DiffStore;
+use ctags::SymbolType;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+/**
+ * One line of a `diffs/<version>` file
+ */
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Serialize)]
+pub enum DiffAction {
+    Add,
+    Remove,
+    Modify,
+}
+
+impl DiffAction {
+    pub fn from_letter(letter: &str) -> Option<Self> {
+        match letter {
+            "a" => Some(DiffAction::Add),
+            "r" => Some(DiffAction::Remove),
+            "m" => Some(DiffAction::Modify),
+            _ => None,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn letter(&self) -> &'static str {
+        match self {
+            DiffAction::Add => "a",
+            DiffAction::Remove => "r",
+            DiffAction::Modify => "m",
+        }
+    }
+}
+
+pub fn parse_symbol_type(name: &str) -> SymbolType {
+    match name {
+        "Function" => SymbolType::Function,
+        "Define" => SymbolType::Define,
+        "Variable" => SymbolType::Variable,
+        "Struct" => SymbolType::Struct,
+        "Export" => SymbolType::Export,
+        _ => SymbolType::Unknown,
+    }
+}
+
+/**
+ * Information that may change across versions
+ */
+#[derive(Debug, Eq, PartialEq, Clone, Serialize)]
+pub struct SymbolData {
+    pub file: String,
+    pub line: u64,
+    pub extra: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct DiffRecord {
+    pub action: DiffAction,
+    pub name: String,
+    pub symbol_type: SymbolType,
+    pub data: SymbolData,
+}
+
+impl DiffRecord {
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut components = line.splitn(6, '\t');
+        Some(DiffRecord {
+            action: DiffAction::from_letter(components.next()?)?,
+            name: components.next()?.to_string(),
+            symbol_type: parse_symbol_type(components.next()?),
+            data: SymbolData {
+                file: components.next()?.to_string(),
+                line: components.next()?.parse().ok()?,
+                extra: components.next().unwrap_or("").to_string(),
+            },
+        })
+    }
+}
+
+/// Reads the version list in the order the indexer processed it.
+/// Older databases don't have one, so fall back to sorting the diff names.
+pub fn read_versions(db_path: &Path) -> Vec<String> {
+    if let Ok(file) = File::open(db_path.join("versions")) {
+        BufReader::new(file).lines()
+            .map(|l| l.unwrap())
+            .filter(|l| !l.is_empty())
+            .collect()
+    } else if let Ok(read_dir) = std::fs::read_dir(db_path.join("diffs")) {
+        let mut versions = read_dir
+            .map(|t| t.unwrap().file_name().to_string_lossy().to_string())
+            .collect::<Vec<String>>();
+        alphanumeric_sort::sort_path_slice(&mut versions);
+        versions
+    } else {
+        vec![]
+    }
+}
+
+#[allow(dead_code)]
+pub fn write_versions(db_path: &Path, versions: &[String]) {
+    let mut out_file = BufWriter::new(File::create(db_path.join("versions")).unwrap());
+    for version in versions {
+        out_file.write_all(format!("{}\n", version).as_bytes()).unwrap();
+    }
+}
+
+/// Finds a version by tag name, tags are stored with '/' replaced by '-'
+pub fn version_index(versions: &[String], version: &str) -> Option<usize> {
+    let version = version.replace("/", "-");
+    versions.iter().position(|v| *v == version)
+}
+
+fn read_records(path: &Path) -> Vec<DiffRecord> {
+    if let Ok(file) = File::open(path) {
+        BufReader::new(file).lines()
+            .filter_map(|l| DiffRecord::parse(&l.unwrap()))
+            .collect()
+    } else {
+        vec![]
+    }
+}
+
+/// From `diffs/<version>`, or from `diffs.bin` for databases shipped without the TSV diffs.
+/// Replays that read many versions use a `DiffReader` instead.
+pub fn read_diff(db_path: &Path, version: &str) -> Vec<DiffRecord> {
+    DiffReader::new(db_path).read(version)
+}
+
+/**
+ * Reads the diffs of one version after another. For databases shipped without the TSV diffs,
+ * `diffs.bin` and its version table are only loaded once.
+ */
+pub struct DiffReader<'a> {
+    db_path: &'a Path,
+    /// Opened when the first TSV diff turns out to be missing
+    store: Option<Option<DiffStore>>,
+    /// The store's versions, with the index of their first record and their record count
+    store_versions: HashMap<String, (usize, usize)>,
+}
+
+impl<'a> DiffReader<'a> {
+    pub fn new(db_path: &'a Path) -> Self {
+        DiffReader { db_path, store: None, store_versions: HashMap::new() }
+    }
+
+    pub fn read(&mut self, version: &str) -> Vec<DiffRecord> {
+        let path = self.db_path.join("diffs").join(version);
+        if !path.exists() {
+            if self.store.is_none() {
+                let store = DiffStore::open(self.db_path);
+                self.store_versions = store.as_ref().map(DiffStore::versions).unwrap_or_default();
+                self.store = Some(store);
+            }
+            if let (Some(Some(store)), Some((first_record, record_count))) = (&self.store, self.store_versions.get(version)) {
+                return store.records(*first_record, *record_count);
+            }
+        }
+        read_records(&path)
+    }
+}
+
+pub type SymbolKey = (String, SymbolType);
+
+/**
+ * The full set of symbols at some version, built by replaying diffs
+ */
+#[derive(Debug, Default)]
+pub struct SymbolSet {
+    pub symbols: BTreeMap<SymbolKey, Vec<SymbolData>>,
+}
+
+impl SymbolSet {
+    pub fn apply(&mut self, record: &DiffRecord) {
+        let key = (record.name.clone(), record.symbol_type);
+        let datas = self.symbols.entry(key.clone()).or_default();
+        match record.action {
+            DiffAction::Add => datas.push(record.data.clone()),
+            DiffAction::Remove => {
+                let idx = datas.iter().position(|d| d.file == record.data.file && d.extra == record.data.extra)
+                    .or_else(|| datas.iter().position(|d| d.file == record.data.file));
+                if let Some(idx) = idx {
+                    datas.remove(idx);
+                }
+            }
+            DiffAction::Modify => {
+                let idx = datas.iter().position(|d| d.file == record.data.file);
+                if let Some(idx) = idx {
+                    datas[idx] = record.data.clone();
+                } else if datas.len() == 1 {
+                    // Moved to another file
+                    datas[0] = record.data.clone();
+                } else if datas.is_empty() {
+                    // Added before the records that were replayed
+                    datas.push(record.data.clone());
+                }
+                // With several definitions in other files, it can't tell which one was modified
+            }
+        }
+        if datas.is_empty() {
+            self.symbols.remove(&key);
+        }
+    }
+}
+
+/// Bumped when the indexer changes what goes into the diffs, e.g. modifications and structs,
+/// so the snapshots replayed from older diffs get rewritten
+pub const DIFF_FORMAT: u32 = 2;
+
+/// Snapshots are written in the diff format, with every symbol added,
+/// after a `format` line with the `DIFF_FORMAT` of the diffs they were replayed from
+pub fn snapshot_path(db_path: &Path, version: &str) -> PathBuf {
+    db_path.join("snapshots").join(version)
+}
+
+pub fn snapshot_header() -> String {
+    format!("format\t{}\n", DIFF_FORMAT)
+}
+
+/// Whether the snapshot of `version` exists and is in the current format
+pub fn is_current_snapshot(db_path: &Path, version: &str) -> bool {
+    let file = match File::open(snapshot_path(db_path, version)) {
+        Ok(file) => file,
+        Err(_) => return false,
+    };
+    let mut header = String::new();
+    BufReader::new(file).read_line(&mut header).is_ok() && header == snapshot_header()
+}
+
+/// The symbols at `versions[idx]`, rebuilt from the nearest snapshot and the diffs that follow it.
+/// Only records accepted by `filter` are kept.
+pub fn symbols_at<F: Fn(&DiffRecord) -> bool>(db_path: &Path, versions: &[String], idx: usize, filter: F) -> SymbolSet {
+    let mut symbols = SymbolSet::default();
+    let snapshot_idx = (0..=idx).rev().find(|i| is_current_snapshot(db_path, &versions[*i]));
+    let first_diff = match snapshot_idx {
+        Some(snapshot_idx) => {
+            for record in read_records(&snapshot_path(db_path, &versions[snapshot_idx])) {
+                if filter(&record) {
+                    symbols.apply(&record);
+                }
+            }
+            snapshot_idx + 1
+        }
+        None => 0,
+    };
+    let mut diffs = DiffReader::new(db_path);
+    for version in &versions[first_diff..=idx] {
+        for record in diffs.read(version) {
+            if filter(&record) {
+                symbols.apply(&record);
+            }
+        }
+    }
+    symbols
+}
+
+/**
+ * How a symbol differs between two versions, after cancelling out everything in between
+ */
+#[derive(Debug)]
+pub struct NetChange {
+    pub name: String,
+    pub symbol_type: SymbolType,
+    pub old: Vec<SymbolData>,
+    pub new: Vec<SymbolData>,
+}
+
+impl NetChange {
+    pub fn action(&self) -> DiffAction {
+        if self.old.is_empty() {
+            DiffAction::Add
+        } else if self.new.is_empty() {
+            DiffAction::Remove
+        } else {
+            DiffAction::Modify
+        }
+    }
+}
+
+/// Same definitions, ignoring line numbers
+fn same_definitions(a: &[SymbolData], b: &[SymbolData]) -> bool {
+    let mut a = a.iter().map(|d| (&d.file, &d.extra)).collect::<Vec<_>>();
+    let mut b = b.iter().map(|d| (&d.file, &d.extra)).collect::<Vec<_>>();
+    a.sort();
+    b.sort();
+    a == b
+}
+
+/// Rebuilds the symbols at `from`, then records the original state of everything touched until `to`
+pub fn net_changes(db_path: &Path, from: &str, to: &str) -> Result<Vec<NetChange>, String> {
+    net_changes_until(db_path, from, to, None).map(|(changes, _)| changes)
+}
+
+/// `net_changes`, stopping at the first version replayed past `deadline`.
+/// If it ran out of time, also returns the last version it replayed.
+pub fn net_changes_until(db_path: &Path, from: &str, to: &str, deadline: Option<Instant>) -> Result<(Vec<NetChange>, Option<String>), String> {
+    let versions = read_versions(db_path);
+    let from_idx = version_index(&versions, from).ok_or_else(|| format!("Unknown version: {}", from))?;
+    let to_idx = version_index(&versions, to).ok_or_else(|| format!("Unknown version: {}", to))?;
+    if from_idx > to_idx {
+        return Err(format!("{} is newer than {}", from, to));
+    }
+
+    let mut symbols = symbols_at(db_path, &versions, from_idx, |_| true);
+
+    let mut touched: HashMap<SymbolKey, Vec<SymbolData>> = HashMap::new();
+    let mut diffs = DiffReader::new(db_path);
+    let mut timed_out_at = None;
+    for (i, version) in versions.iter().enumerate().take(to_idx + 1).skip(from_idx + 1) {
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            timed_out_at = Some(versions[i - 1].clone());
+            break;
+        }
+        for record in diffs.read(version) {
+            let key = (record.name.clone(), record.symbol_type);
+            touched.entry(key).or_insert_with_key(|key| symbols.symbols.get(key).cloned().unwrap_or_default());
+            symbols.apply(&record);
+        }
+    }
+
+    let mut changes = touched.into_iter()
+        .filter_map(|(key, old)| {
+            let new = symbols.symbols.get(&key).cloned().unwrap_or_default();
+            if same_definitions(&old, &new) {
+                None
+            } else {
+                Some(NetChange { name: key.0, symbol_type: key.1, old, new })
+            }
+        })
+        .collect::<Vec<_>>();
+    changes.sort_by(|a, b| (&a.name, a.symbol_type).cmp(&(&b.name, b.symbol_type)));
+    Ok((changes, timed_out_at))
+}
+
+/// Human readable declaration of a symbol, e.g. `void *kmalloc(size_t size, gfp_t flags)`
+pub fn format_signature(name: &str, symbol_type: SymbolType, extra: &str) -> String {
+    match symbol_type {
+        SymbolType::Function => extra.replace("{name}", name),
+        SymbolType::Define => format!("#define {}{}", name, extra),
+        SymbolType::Variable => format!("{}{}", name, extra),
+        SymbolType::Struct => format!("struct {} {{ {} }}", name, extra),
+        SymbolType::Export => format!("EXPORT_SYMBOL({})", name),
+        SymbolType::Unknown => name.to_string(),
+    }
+}
+
+/// The short names used by the search filters and result badges
+#[allow(dead_code)]
+pub fn symbol_type_letter(symbol_type: SymbolType) -> &'static str {
+    match symbol_type {
+        SymbolType::Function => "f",
+        SymbolType::Define => "d",
+        SymbolType::Variable => "v",
+        SymbolType::Struct => "s",
+        SymbolType::Export => "x",
+        SymbolType::Unknown => "u",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(line: &str) -> DiffRecord {
+        DiffRecord::parse(line).unwrap()
+    }
+
+    fn files(symbols: &SymbolSet, name: &str) -> Vec<(String, String)> {
+        symbols.symbols.get(&(name.to_string(), SymbolType::Function)).map_or(vec![], |datas| {
+            datas.iter().map(|d| (d.file.clone(), d.extra.clone())).collect()
+        })
+    }
+
+    #[test]
+    fn modifications_replace_their_definition() {
+        let mut symbols = SymbolSet::default();
+        symbols.apply(&record("a\tfoo\tFunction\ta.c\t1\tint {name}(void)"));
+        symbols.apply(&record("m\tfoo\tFunction\tb.c\t1\tint {name}(int)"));
+        assert_eq!(files(&symbols, "foo"), vec![("b.c".to_string(), "int {name}(int)".to_string())]);
+
+        symbols.apply(&record("a\tfoo\tFunction\tc.c\t1\tint {name}(int)"));
+        symbols.apply(&record("m\tfoo\tFunction\tc.c\t1\tlong {name}(int)"));
+        // Ambiguous between the two definitions, so it's left out rather than added
+        symbols.apply(&record("m\tfoo\tFunction\td.c\t1\tvoid {name}(int)"));
+        assert_eq!(files(&symbols, "foo"), vec![
+            ("b.c".to_string(), "int {name}(int)".to_string()),
+            ("c.c".to_string(), "long {name}(int)".to_string()),
+        ]);
+
+        symbols.apply(&record("r\tfoo\tFunction\tb.c\t1\tint {name}(int)"));
+        symbols.apply(&record("r\tfoo\tFunction\tc.c\t1\tlong {name}(int)"));
+        assert!(symbols.symbols.is_empty());
+    }
+
+    #[test]
+    fn churn_cancels_out() {
+        let db_path = std::env::temp_dir().join(format!("sourcedigger-net-changes-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&db_path);
+        std::fs::create_dir_all(db_path.join("diffs")).unwrap();
+        let diffs = [
+            ("v1", "a\tkept\tFunction\ta.c\t1\tint {name}(void)\na\tgone\tFunction\ta.c\t5\tint {name}(void)\na\tchanged\tFunction\ta.c\t9\tint {name}(void)\n"),
+            ("v2", "a\ttemporary\tFunction\tb.c\t1\tint {name}(void)\nr\tgone\tFunction\ta.c\t5\tint {name}(void)\nm\tkept\tFunction\ta.c\t2\tint {name}(void)\n"),
+            // Older indexers didn't record changed definitions at all, so `changed` isn't reported
+            ("v3", "r\ttemporary\tFunction\tb.c\t1\tint {name}(void)\n"),
+        ];
+        for (version, diff) in diffs.iter() {
+            std::fs::write(db_path.join("diffs").join(version), diff).unwrap();
+        }
+        write_versions(&db_path, &["v1".to_string(), "v2".to_string(), "v3".to_string()]);
+
+        let changes = net_changes(&db_path, "v1", "v3").unwrap();
+        let changes = changes.iter().map(|c| (c.name.as_str(), c.action())).collect::<Vec<_>>();
+        assert_eq!(changes, vec![("gone", DiffAction::Remove)]);
+        assert!(net_changes(&db_path, "v3", "v1").is_err());
+
+        // Out of time before replaying anything
+        let (changes, timed_out_at) = net_changes_until(&db_path, "v1", "v3", Some(Instant::now())).unwrap();
+        assert!(changes.is_empty());
+        assert_eq!(timed_out_at.as_deref(), Some("v1"));
+        assert_eq!(net_changes_until(&db_path, "v1", "v3", None).unwrap().1, None);
+        std::fs::remove_dir_all(&db_path).unwrap();
+    }
+}
@@ -0,0 +1,165 @@
+use crate::history::{self, SymbolData, SymbolKey, SymbolSet};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/**
+ * When a symbol existed, one line of the `lifetimes` file
+ */
+#[derive(Serialize, Debug, Clone)]
+pub struct Lifetime {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub symbol_type: String,
+    pub introduced: String,
+    /// `None` if it still exists
+    pub removed: Option<String>,
+    /// (removed, re-introduced) pairs
+    pub gaps: Vec<(String, String)>,
+    /// The latest definition, or the last one before it was removed
+    pub file: String,
+    pub line: u64,
+    pub signature: String,
+}
+
+#[allow(dead_code)]
+impl Lifetime {
+    /// Gaps are stored as `removed..reintroduced`, which can't appear in git tag names
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut components = line.splitn(8, '\t');
+        let name = components.next()?.to_string();
+        let symbol_type = components.next()?.to_string();
+        let introduced = components.next()?.to_string();
+        let removed = Some(components.next()?.to_string()).filter(|r| !r.is_empty());
+        let gaps = components.next()?
+            .split(' ')
+            .filter_map(|gap| {
+                let mut parts = gap.splitn(2, "..");
+                Some((parts.next()?.to_string(), parts.next()?.to_string()))
+            })
+            .collect();
+        let file = components.next()?.to_string();
+        let line = components.next()?.parse().ok()?;
+        let extra = components.next().unwrap_or("");
+        let signature = history::format_signature(&name, history::parse_symbol_type(&symbol_type), extra);
+        Some(Lifetime { name, symbol_type, introduced, removed, gaps, file, line, signature })
+    }
+
+    /// e.g. "Function, introduced in v2.6.12, missing from v3.1 until v3.4, still present"
+    pub fn summary(&self) -> String {
+        let mut summary = format!("{}, introduced in {}", self.symbol_type, self.introduced);
+        for (removed, reintroduced) in self.gaps.iter() {
+            summary += &format!(", missing from {} until {}", removed, reintroduced);
+        }
+        match &self.removed {
+            Some(removed) => summary += &format!(", removed in {}", removed),
+            None => summary += ", still present",
+        }
+        summary
+    }
+}
+
+struct LifetimeState {
+    introduced: usize,
+    removed: Option<usize>,
+    gaps: Vec<(usize, usize)>,
+    last_data: SymbolData,
+}
+
+/// Replays every diff, noting when each symbol appears and disappears
+#[allow(dead_code)]
+pub fn write_lifetimes(db_path: &Path, versions: &[String]) {
+    let mut symbols = SymbolSet::default();
+    let mut states: BTreeMap<SymbolKey, LifetimeState> = BTreeMap::new();
+
+    let mut diffs = history::DiffReader::new(db_path);
+    for (i, version) in versions.iter().enumerate() {
+        let mut touched = HashSet::new();
+        for record in diffs.read(version) {
+            touched.insert((record.name.clone(), record.symbol_type));
+            symbols.apply(&record);
+        }
+
+        for key in touched {
+            let current = symbols.symbols.get(&key).and_then(|datas| datas.first());
+            match (states.get_mut(&key), current) {
+                (None, Some(data)) => {
+                    states.insert(key, LifetimeState { introduced: i, removed: None, gaps: vec![], last_data: data.clone() });
+                }
+                (Some(state), Some(data)) => {
+                    if let Some(removed) = state.removed.take() {
+                        state.gaps.push((removed, i));
+                    }
+                    state.last_data = data.clone();
+                }
+                (Some(state), None) => {
+                    if state.removed.is_none() {
+                        state.removed = Some(i);
+                    }
+                }
+                (None, None) => {}
+            }
+        }
+    }
+
+    // The server may be reading the old file, so it's replaced rather than overwritten
+    let tmp_path = db_path.join("lifetimes.tmp");
+    let mut out_file = BufWriter::new(File::create(&tmp_path).unwrap());
+    for ((name, symbol_type), state) in states.iter() {
+        out_file.write_all(format!(
+            "{}\t{:?}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+            name,
+            symbol_type,
+            versions[state.introduced],
+            state.removed.map(|r| versions[r].as_str()).unwrap_or(""),
+            state.gaps.iter()
+                .map(|(removed, reintroduced)| format!("{}..{}", versions[*removed], versions[*reintroduced]))
+                .collect::<Vec<_>>()
+                .join(" "),
+            state.last_data.file,
+            state.last_data.line,
+            state.last_data.extra,
+        ).as_bytes()).unwrap();
+    }
+    out_file.flush().unwrap();
+    drop(out_file);
+    std::fs::rename(&tmp_path, db_path.join("lifetimes")).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gaps_and_removals() {
+        let db_path = std::env::temp_dir().join(format!("sourcedigger-lifetimes-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&db_path);
+        std::fs::create_dir_all(db_path.join("diffs")).unwrap();
+        let diffs = [
+            ("v1", "a\tback\tFunction\ta.c\t1\tint {name}(void)\na\tgone\tFunction\ta.c\t5\tint {name}(void)\n"),
+            ("v2", "r\tback\tFunction\ta.c\t1\tint {name}(void)\nr\tgone\tFunction\ta.c\t5\tint {name}(void)\n"),
+            ("v3", "a\tback\tFunction\tb.c\t7\tlong {name}(void)\n"),
+            ("v4", "a\tnew\tDefine\tb.h\t2\t\n"),
+        ];
+        for (version, diff) in diffs.iter() {
+            std::fs::write(db_path.join("diffs").join(version), diff).unwrap();
+        }
+        let versions = diffs.iter().map(|(version, _)| version.to_string()).collect::<Vec<_>>();
+        write_lifetimes(&db_path, &versions);
+
+        let lifetimes = std::fs::read_to_string(db_path.join("lifetimes")).unwrap()
+            .lines()
+            .map(|line| Lifetime::parse(line).unwrap())
+            .map(|l| (l.name, l.introduced, l.removed, l.gaps, l.file, l.line))
+            .collect::<Vec<_>>();
+        assert_eq!(lifetimes, vec![
+            ("back".to_string(), "v1".to_string(), None, vec![("v2".to_string(), "v3".to_string())], "b.c".to_string(), 7),
+            ("gone".to_string(), "v1".to_string(), Some("v2".to_string()), vec![], "a.c".to_string(), 5),
+            ("new".to_string(), "v4".to_string(), None, vec![], "b.h".to_string(), 2),
+        ]);
+        assert!(!db_path.join("lifetimes.tmp").exists());
+        std::fs::remove_dir_all(&db_path).unwrap();
+    }
+}
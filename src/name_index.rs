@@ -0,0 +1,175 @@
+use crate::history::DiffRecord;
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Where a record is, as (index into `versions`, byte offset in `diffs/<version>`, index among the
+/// version's records in `diffs.bin`). Indexes written before `diffs.bin` existed don't have the last one.
+pub type Posting = (u32, u64, Option<u32>);
+
+/**
+ * Symbol names -> the diff records that mention them, so searches don't need to scan every diff.
+ * Each line of the `name_index` file is `name\tversion:offset:record version:offset:record ...`, sorted by name.
+ * Only the names are kept in memory, the postings are read from the file when a name matches.
+ */
+pub struct NameIndex {
+    path: PathBuf,
+    /// (lowercase name, name, offset of its line in the file), sorted by lowercase name
+    names: Vec<(String, String, u64)>,
+}
+
+/// Replays the diffs, noting the offset of every record
+#[allow(dead_code)]
+pub fn write_name_index(db_path: &Path, versions: &[String]) {
+    let mut postings: BTreeMap<String, Vec<Posting>> = BTreeMap::new();
+    for (i, version) in versions.iter().enumerate() {
+        let file = match File::open(db_path.join("diffs").join(version)) {
+            Ok(file) => file,
+            Err(_) => continue,
+        };
+        let mut reader = BufReader::new(file);
+        let mut offset = 0;
+        let mut record = 0;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let len = reader.read_line(&mut line).unwrap();
+            if len == 0 {
+                break;
+            }
+            // Counted the way `diffs.bin` is written, which skips the lines that don't parse
+            if let Some(parsed) = DiffRecord::parse(line.trim_end_matches('\n')) {
+                postings.entry(parsed.name).or_default().push((i as u32, offset, Some(record)));
+                record += 1;
+            }
+            offset += len as u64;
+        }
+    }
+
+    // The server may be reading the old file, so it's replaced rather than overwritten
+    let tmp_path = db_path.join("name_index.tmp");
+    let mut out_file = BufWriter::new(File::create(&tmp_path).unwrap());
+    for (name, postings) in postings.iter() {
+        let postings = postings.iter()
+            .map(|(version, offset, record)| format!("{}:{}:{}", version, offset, record.unwrap_or(0)))
+            .collect::<Vec<_>>()
+            .join(" ");
+        out_file.write_all(format!("{}\t{}\n", name, postings).as_bytes()).unwrap();
+    }
+    out_file.flush().unwrap();
+    drop(out_file);
+    std::fs::rename(&tmp_path, db_path.join("name_index")).unwrap();
+}
+
+#[allow(dead_code)]
+impl NameIndex {
+    /// `None` for databases indexed before the name index existed
+    pub fn load(db_path: &Path) -> Option<Self> {
+        let path = db_path.join("name_index");
+        let mut reader = BufReader::new(File::open(&path).ok()?);
+        let mut names = vec![];
+        let mut offset = 0;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let len = reader.read_line(&mut line).ok()?;
+            if len == 0 {
+                break;
+            }
+            let name = line.split('\t').next()?;
+            names.push((name.to_lowercase(), name.to_string(), offset));
+            offset += len as u64;
+        }
+        names.sort();
+        Some(NameIndex { path, names })
+    }
+
+    /// The names starting with `prefix` (in lowercase) and matching `name_regex`.
+    /// An empty prefix checks every name.
+    fn matching<'a>(&'a self, prefix: &str, name_regex: &'a Regex) -> impl Iterator<Item = &'a (String, String, u64)> + 'a {
+        let prefix = prefix.to_lowercase();
+        let start = self.names.partition_point(|(lowercase, _, _)| *lowercase < prefix);
+        self.names[start..].iter()
+            .take_while(move |(lowercase, _, _)| lowercase.starts_with(&prefix))
+            .filter(move |(_, name, _)| name_regex.is_match(name))
+    }
+
+    pub fn find_names<'a>(&'a self, prefix: &str, name_regex: &'a Regex) -> impl Iterator<Item = &'a str> + 'a {
+        self.matching(prefix, name_regex).map(|(_, name, _)| name.as_str())
+    }
+
+    /// The records of every matching name, ordered by version and then by offset
+    pub fn lookup(&self, prefix: &str, name_regex: &Regex) -> Vec<Posting> {
+        let mut file = BufReader::new(File::open(&self.path).unwrap());
+        let mut postings = vec![];
+        let mut line = String::new();
+        for (_, name, offset) in self.matching(prefix, name_regex) {
+            file.seek(SeekFrom::Start(*offset)).unwrap();
+            line.clear();
+            file.read_line(&mut line).unwrap();
+            let mut parts = line.trim_end().splitn(2, '\t');
+            // The index is reloaded once it's replaced, until then a name may have moved
+            if parts.next() != Some(name.as_str()) {
+                continue;
+            }
+            let postings_str = parts.next().unwrap_or("");
+            postings.extend(postings_str.split(' ').filter_map(|posting| {
+                let mut parts = posting.splitn(3, ':');
+                Some((
+                    parts.next()?.parse().ok()?,
+                    parts.next()?.parse().ok()?,
+                    parts.next().and_then(|record| record.parse().ok()),
+                ))
+            }));
+        }
+        postings.sort();
+        postings
+    }
+}
+
+/// Reads the records at `offsets` (sorted) from a diff file
+#[allow(dead_code)]
+pub fn read_lines_at(path: &Path, offsets: &[u64]) -> Vec<String> {
+    let mut file = match File::open(path) {
+        Ok(file) => BufReader::new(file),
+        Err(_) => return vec![],
+    };
+    let mut lines = vec![];
+    for offset in offsets {
+        let mut line = String::new();
+        file.seek(SeekFrom::Start(*offset)).unwrap();
+        file.read_line(&mut line).unwrap();
+        lines.push(line.trim_end_matches('\n').to_string());
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_lines_at, write_name_index, NameIndex};
+    use regex::Regex;
+
+    #[test]
+    fn postings_point_at_records() {
+        let db_path = std::env::temp_dir().join(format!("sourcedigger-name-index-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&db_path);
+        std::fs::create_dir_all(db_path.join("diffs")).unwrap();
+        std::fs::write(db_path.join("diffs").join("v1"), "a\tkmalloc\tFunction\tmm/slab.c\t10\t\nnot a record\na\tkfree\tFunction\tmm/slab.c\t20\t\n").unwrap();
+        std::fs::write(db_path.join("diffs").join("v2"), "r\tkfree\tFunction\tmm/slab.c\t20\t\n").unwrap();
+        write_name_index(&db_path, &["v1".to_string(), "v2".to_string()]);
+
+        let index = NameIndex::load(&db_path).unwrap();
+        let name_regex = Regex::new("^kfree$").unwrap();
+        let postings = index.lookup("kfree", &name_regex);
+        // The line that doesn't parse isn't counted as a record
+        assert_eq!(postings, vec![(0, 46, Some(1)), (1, 0, Some(0))]);
+        assert_eq!(
+            read_lines_at(&db_path.join("diffs").join("v1"), &[postings[0].1]),
+            vec!["a\tkfree\tFunction\tmm/slab.c\t20\t"]
+        );
+        assert_eq!(index.find_names("k", &Regex::new("^k").unwrap()).collect::<Vec<_>>(), vec!["kfree", "kmalloc"]);
+        std::fs::remove_dir_all(&db_path).unwrap();
+    }
+}
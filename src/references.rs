@@ -0,0 +1,254 @@
+use crate::sorted_lines;
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Words that look like identifiers but never reference a symbol
+const KEYWORDS: [&str; 40] = [
+    "auto", "break", "case", "char", "const", "continue", "default", "do", "double", "else", "enum", "extern",
+    "float", "for", "goto", "if", "inline", "int", "long", "register", "restrict", "return", "short", "signed",
+    "sizeof", "static", "struct", "switch", "typedef", "union", "unsigned", "void", "volatile", "while", "_Bool",
+    "asm", "__asm__", "typeof", "__attribute__", "__inline__",
+];
+
+/// The type of the reference lines in `tags/<oid>`
+pub const REFERENCE_TYPE: &str = "Reference";
+
+/// The last line of a `tags/<oid>` file with references, followed by the offset of the first one
+const REFERENCES_TRAILER: &str = "\tReferences\t";
+
+/**
+ * Where one identifier is used in a blob.
+ * They're kept in `tags/<oid>` after the blob's symbols, sorted by name: `name\tReference\tcall lines\tother lines`,
+ * with comma separated line numbers. The last line is `\tReferences\t<offset of the first one>`.
+ */
+#[derive(Debug, Default, PartialEq)]
+pub struct References {
+    pub name: String,
+    /// Lines where it's followed by `(`, which for functions and function-like macros are call sites
+    pub calls: Vec<u64>,
+    pub others: Vec<u64>,
+}
+
+impl References {
+    #[allow(dead_code)]
+    pub fn to_line(&self) -> String {
+        let join = |lines: &[u64]| lines.iter().map(|l| l.to_string()).collect::<Vec<_>>().join(",");
+        format!("{}\t{}\t{}\t{}\n", self.name, REFERENCE_TYPE, join(&self.calls), join(&self.others))
+    }
+
+    #[allow(dead_code)]
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut parts = line.splitn(4, '\t');
+        let split = |lines: &str| lines.split(',').filter_map(|l| l.parse().ok()).collect::<Vec<_>>();
+        let name = parts.next()?.to_string();
+        if parts.next()? != REFERENCE_TYPE {
+            return None;
+        }
+        Some(References {
+            name,
+            calls: split(parts.next()?),
+            others: split(parts.next()?),
+        })
+    }
+}
+
+/// Whether a `tags/<oid>` line is one of the references or the line after them, rather than a symbol
+pub fn is_reference_line(line: &str) -> bool {
+    line.starts_with('\t') || line.split('\t').nth(1) == Some(REFERENCE_TYPE)
+}
+
+fn is_identifier_start(c: u8) -> bool {
+    c.is_ascii_alphabetic() || c == b'_'
+}
+
+fn is_identifier_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'_'
+}
+
+/**
+ * Every identifier used in C source, with its line numbers.
+ * Comments, string and character literals, `#include` paths and struct members (after `.` or `->`) are skipped.
+ */
+#[allow(dead_code)]
+pub fn extract_references(source_code: &str) -> Vec<References> {
+    let bytes = source_code.as_bytes();
+    let mut references = BTreeMap::<&str, References>::new();
+    let mut line = 1u64;
+    // The line's first token, to spot `#include`
+    let mut line_start = true;
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        if c == b'\n' {
+            line += 1;
+            line_start = true;
+            i += 1;
+        } else if c.is_ascii_whitespace() {
+            i += 1;
+        } else if bytes[i..].starts_with(b"//") {
+            while i < bytes.len() && bytes[i] != b'\n' {
+                i += 1;
+            }
+        } else if bytes[i..].starts_with(b"/*") {
+            let end = source_code[i + 2..].find("*/").map(|e| i + e + 4).unwrap_or(bytes.len());
+            line += bytes[i..end].iter().filter(|b| **b == b'\n').count() as u64;
+            i = end;
+        } else if c == b'"' || c == b'\'' {
+            i += 1;
+            while i < bytes.len() && bytes[i] != c && bytes[i] != b'\n' {
+                if bytes[i] == b'\\' && bytes.get(i + 1) == Some(&b'\n') {
+                    line += 1;
+                }
+                i += if bytes[i] == b'\\' { 2 } else { 1 };
+            }
+            // An unterminated literal, e.g. an apostrophe in `#error`, ends at the newline, which is still counted
+            if bytes.get(i) == Some(&c) {
+                i += 1;
+            }
+            line_start = false;
+        } else if c == b'#' && line_start {
+            let directive = source_code[i + 1..].trim_start_matches([' ', '\t'].as_ref());
+            if directive.starts_with("include") {
+                while i < bytes.len() && bytes[i] != b'\n' {
+                    i += 1;
+                }
+            } else {
+                // The directive's name isn't a reference, but the rest of the line is
+                i = bytes.len() - directive.len();
+                while i < bytes.len() && is_identifier_char(bytes[i]) {
+                    i += 1;
+                }
+            }
+            line_start = false;
+        } else if is_identifier_start(c) {
+            let start = i;
+            while i < bytes.len() && is_identifier_char(bytes[i]) {
+                i += 1;
+            }
+            let identifier = &source_code[start..i];
+            let before = source_code[..start].trim_end();
+            let is_member = before.ends_with('.') || before.ends_with("->");
+            if !is_member && !KEYWORDS.contains(&identifier) {
+                let is_call = source_code[i..].trim_start_matches([' ', '\t'].as_ref()).starts_with('(');
+                let entry = references.entry(identifier).or_insert_with(|| References {
+                    name: identifier.to_string(),
+                    ..Default::default()
+                });
+                let lines = if is_call { &mut entry.calls } else { &mut entry.others };
+                if lines.last() != Some(&line) {
+                    lines.push(line);
+                }
+            }
+            line_start = false;
+        } else if c.is_ascii_digit() {
+            // Numbers, including suffixes like `10UL` or `0x1f`
+            while i < bytes.len() && (is_identifier_char(bytes[i]) || bytes[i] == b'.') {
+                i += 1;
+            }
+            line_start = false;
+        } else {
+            i += 1;
+            line_start = false;
+        }
+    }
+    references.into_values().collect()
+}
+
+/// Where the references of a tags file are, from its last line. `None` if they weren't extracted.
+fn reference_range(file: &mut File) -> Option<(u64, u64)> {
+    let len = file.metadata().ok()?.len();
+    // The trailer is short, so it's within the last few bytes
+    let tail_start = len.saturating_sub(64);
+    file.seek(SeekFrom::Start(tail_start)).ok()?;
+    let mut tail = vec![];
+    file.read_to_end(&mut tail).ok()?;
+    let tail = String::from_utf8_lossy(&tail);
+    let tail = tail.strip_suffix('\n')?;
+    let last_line = &tail[tail.rfind('\n').map_or(0, |idx| idx + 1)..];
+    let start = last_line.strip_prefix(REFERENCES_TRAILER)?.parse::<u64>().ok()?;
+    let end = len - last_line.len() as u64 - 1;
+    Some((start, end)).filter(|(start, end)| start <= end)
+}
+
+/// Whether the references of the blob with these tags were extracted
+#[allow(dead_code)]
+pub fn has_references(tags_path: &Path) -> bool {
+    File::open(tags_path).ok().and_then(|mut file| reference_range(&mut file)).is_some()
+}
+
+/// Adds the references of a blob to its tags, creating them if the blob has no symbols
+#[allow(dead_code)]
+pub fn append_references(tags_path: &Path, source_code: &str) {
+    let out_file = OpenOptions::new().create(true).append(true).open(tags_path).unwrap();
+    let start = out_file.metadata().unwrap().len();
+    let mut out_file = BufWriter::new(out_file);
+    for references in extract_references(source_code) {
+        out_file.write_all(references.to_line().as_bytes()).unwrap();
+    }
+    out_file.write_all(format!("{}{}\n", REFERENCES_TRAILER, start).as_bytes()).unwrap();
+}
+
+/// The uses of `name` in the blob, `None` if there are none or they weren't extracted
+#[allow(dead_code)]
+pub fn read_references(db_path: &Path, oid: &str, name: &str) -> Option<References> {
+    let mut file = File::open(db_path.join("tags").join(oid)).ok()?;
+    let (start, end) = reference_range(&mut file)?;
+    sorted_lines::find_lines(&mut BufReader::new(file), start, end, name).first()
+        .and_then(|line| References::parse(line))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn comments_strings_and_members_are_skipped() {
+        let source_code = "#include <linux/slab.h>\n/* kfree(p) */\nvoid *f(struct s *s)\n{\n\tchar *t = \"kfree(\";\n\treturn kmalloc(s->size,\n\t\tGFP_KERNEL);\n}\n";
+        let references = extract_references(source_code).into_iter()
+            .map(|r| (r.name, r.calls, r.others))
+            .collect::<Vec<_>>();
+        assert_eq!(references, vec![
+            ("GFP_KERNEL".to_string(), vec![], vec![7]),
+            ("f".to_string(), vec![3], vec![]),
+            ("kmalloc".to_string(), vec![6], vec![]),
+            ("s".to_string(), vec![], vec![3, 6]),
+            ("t".to_string(), vec![], vec![5]),
+        ]);
+
+        let source_code = "#error don't do this\nint a;\nvoid f(void)\n{\n\tg();\n}\n";
+        let references = extract_references(source_code).into_iter()
+            .map(|r| (r.name, r.calls, r.others))
+            .collect::<Vec<_>>();
+        assert_eq!(references, vec![
+            ("a".to_string(), vec![], vec![2]),
+            ("don".to_string(), vec![], vec![1]),
+            ("f".to_string(), vec![3], vec![]),
+            ("g".to_string(), vec![5], vec![]),
+        ]);
+    }
+
+    #[test]
+    fn references_follow_the_symbols() {
+        let db_path = std::env::temp_dir().join(format!("sourcedigger-references-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&db_path);
+        std::fs::create_dir_all(db_path.join("tags")).unwrap();
+        let tags_path = db_path.join("tags").join("0123");
+        std::fs::write(&tags_path, "f\tFunction\t1\tvoid {name}(void)\n").unwrap();
+        assert!(!has_references(&tags_path));
+
+        append_references(&tags_path, "void f(void)\n{\n\tg();\n\th(g);\n}\n");
+        assert!(has_references(&tags_path));
+        let g = read_references(&db_path, "0123", "g").unwrap();
+        assert_eq!((g.calls, g.others), (vec![3], vec![4]));
+        assert!(read_references(&db_path, "0123", "Function").is_none());
+        let content = std::fs::read_to_string(&tags_path).unwrap();
+        assert_eq!(content.lines().filter(|line| !is_reference_line(line)).collect::<Vec<_>>(), vec!["f\tFunction\t1\tvoid {name}(void)"]);
+
+        // Blobs without symbols don't have tags yet
+        append_references(&db_path.join("tags").join("4567"), "int x;\n");
+        assert!(read_references(&db_path, "4567", "x").is_some());
+        std::fs::remove_dir_all(&db_path).unwrap();
+    }
+}
@@ -1,43 +1,181 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
 use std::path::Path;
-use std::io::{BufReader, BufRead};
-use std::sync::mpsc::Receiver;
+use std::sync::Arc;
 use std::time::Instant;
-use regex::Regex;
+use crate::history;
+use crate::lifetimes::Lifetime;
+use crate::{fuzzy, projects, query};
 
-pub fn complete(project: String, query: String, count: u64) -> Receiver<String> {
-    assert!(!project.contains("/"));
-    let (send, recv) = std::sync::mpsc::channel();
+/**
+ * A symbol name with what's known about its history, for ranking completions
+ */
+struct Completion {
+    lowercase: String,
+    name: String,
+    /// How many versions it exists in, counting every symbol type with this name
+    version_count: usize,
+    /// Whether it exists in the latest version
+    alive: bool,
+}
 
-    std::thread::spawn(move || {
+/**
+ * All of a project's symbol names, sorted by lowercase name so a prefix is a contiguous range
+ */
+pub struct Completions {
+    names: Vec<Completion>,
+}
+
+impl Completions {
+    /// Ranked by the `lifetimes` file, databases without it only have the names from `autocomplete_db`
+    pub fn load(db_path: &Path) -> Self {
         let start = Instant::now();
+        let versions = history::read_versions(db_path);
+        let version_idx = versions.iter().enumerate()
+            .map(|(i, v)| (v.as_str(), i))
+            .collect::<HashMap<_, _>>();
+        let idx_of = |v: &str| version_idx.get(v).copied().unwrap_or(0);
 
-        let query_expander = Regex::new("(^|[^.\\]])([*+])").unwrap();
+        let mut by_name: HashMap<String, (usize, bool)> = HashMap::new();
+        if let Ok(file) = File::open(db_path.join("lifetimes")) {
+            for lifetime in BufReader::new(file).lines().filter_map(|l| Lifetime::parse(&l.unwrap())) {
+                let end = lifetime.removed.as_ref().map(|r| idx_of(r)).unwrap_or(versions.len());
+                let missing = lifetime.gaps.iter()
+                    .map(|(removed, reintroduced)| idx_of(reintroduced).saturating_sub(idx_of(removed)))
+                    .sum::<usize>();
+                let version_count = end.saturating_sub(idx_of(&lifetime.introduced)).saturating_sub(missing);
+                let entry = by_name.entry(lifetime.name).or_insert((0, false));
+                entry.0 = entry.0.max(version_count);
+                entry.1 |= lifetime.removed.is_none();
+            }
+        } else if let Ok(file) = File::open(db_path.join("autocomplete_db")) {
+            for name in BufReader::new(file).lines() {
+                by_name.insert(name.unwrap(), (0, false));
+            }
+        }
 
-        let query = query_expander.replace_all(&query, "$1.$2");
+        let mut names = by_name.into_iter()
+            .map(|(name, (version_count, alive))| Completion { lowercase: name.to_lowercase(), name, version_count, alive })
+            .collect::<Vec<_>>();
+        names.sort_by(|a, b| a.lowercase.cmp(&b.lowercase));
+        println!("- loaded {} completions from {} in {:?}", names.len(), db_path.display(), start.elapsed());
+        Completions { names }
+    }
 
-        let rg_query = if query.chars().all(|x| x.is_alphanumeric() || x == '_') {
-            format!(r"^{}$", &query)
-        } else {
-            format!(r"^({})$", &query)
-        };
+    /// Whether there's a symbol with exactly this name
+    pub fn contains(&self, name: &str) -> bool {
+        let lowercase = name.to_lowercase();
+        let first = self.names.partition_point(|c| c.lowercase < lowercase);
+        self.names[first..].iter()
+            .take_while(|c| c.lowercase == lowercase)
+            .any(|c| c.name == name)
+    }
+}
 
-        let rg_args = ["--smart-case", &rg_query, "autocomplete_db"];
+/// The project's names, loaded on first use and again after a reindex replaces `lifetimes`
+pub fn get(project: &str) -> Arc<Completions> {
+    projects::completions(project)
+}
 
-        println!("autocomplete rg_args: {:?}", &rg_args);
-        let mut rg_proc = subprocess::Exec::cmd("rg")
-            .args(&rg_args)
-            .stdout(subprocess::Redirection::Pipe)
-            .cwd(Path::new("sourcedigger-db").join(&project))
-            .popen()
-            .unwrap();
+/// Loads every project's names, so the first keystrokes don't have to wait
+pub fn load_all() {
+    for project in projects::list() {
+        get(&project);
+    }
+}
 
-        let rg_out = rg_proc.stdout.take().unwrap();
-        for line in BufReader::new(rg_out).lines().take(count as usize) {
-            send.send(line.unwrap() + "\n").unwrap();
-        }
-        println!("- autocomplete took {:?}", start.elapsed());
+/// The `count` best names matching the query (a glob or a regex): names starting with the query as typed
+/// come first, then the ones that exist in more versions, then the ones still present
+pub fn complete(project: &str, query: &str, count: u64) -> Vec<String> {
+    assert!(!project.contains("/"));
+    let start = Instant::now();
+    let name_regex = match query::name_regex(query) {
+        Ok(name_regex) => name_regex,
+        Err(_) => return vec![],
+    };
+    let prefix = query::literal_prefix(query);
+    let lowercase_prefix = prefix.to_lowercase();
+
+    let completions = get(project);
+    let first = completions.names.partition_point(|c| c.lowercase < lowercase_prefix);
+    let mut matches = completions.names[first..].iter()
+        .take_while(|c| c.lowercase.starts_with(&lowercase_prefix))
+        .filter(|c| name_regex.is_match(&c.name))
+        .collect::<Vec<_>>();
+    let rank = |c: &Completion| (!c.name.starts_with(&prefix), Reverse(c.version_count), !c.alive, c.name.len());
+    let compare = |a: &&Completion, b: &&Completion| rank(a).cmp(&rank(b)).then_with(|| a.name.cmp(&b.name));
+    // Only the top results need to be in order
+    let count = count as usize;
+    if matches.len() > count && count != 0 {
+        matches.select_nth_unstable_by(count - 1, compare);
+        matches.truncate(count);
+    }
+    matches.sort_by(compare);
+
+    println!("- autocomplete took {:?}", start.elapsed());
+    matches.iter().take(count).map(|c| c.name.clone()).collect()
+}
+
+/// Typo tolerant completion: names starting with the query give or take a few typos, then names containing
+/// the query's characters in order. Closer matches come first, then the usual ranking.
+pub fn complete_fuzzy(project: &str, query: &str, count: u64) -> Vec<String> {
+    assert!(!project.contains("/"));
+    if query.is_empty() {
+        return vec![];
+    }
+    let start = Instant::now();
+    let max_typos = fuzzy::max_typos(query);
+    let lowercase_query = query.to_lowercase();
+    let query_len = lowercase_query.chars().count();
+    let first_char = lowercase_query.chars().next().unwrap();
+    let leading_chars = lowercase_query.chars().take(max_typos + 1).collect::<Vec<_>>();
+
+    let completions = get(project);
+    let mut matches = completions.names.iter()
+        // Neither kind of match can be much shorter than the query
+        .filter(|c| c.lowercase.len() + max_typos >= query_len)
+        .filter_map(|c| {
+            // With at most `max_typos` edits, one of the query's first few characters is kept near the start of the name
+            if c.lowercase.chars().take(2 * max_typos + 1).any(|c| leading_chars.contains(&c)) {
+                let prefix_end = c.lowercase.char_indices().nth(query_len).map_or(c.lowercase.len(), |(idx, _)| idx);
+                if let Some(typos) = fuzzy::edit_distance(&lowercase_query, &c.lowercase[..prefix_end], max_typos) {
+                    return Some(((0, typos), c));
+                }
+            }
+            if c.lowercase.contains(first_char) {
+                fuzzy::subsequence_gaps(&lowercase_query, &c.lowercase).map(|gaps| ((1, gaps), c))
+            } else {
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+    matches.sort_by(|(a_score, a), (b_score, b)| {
+        (a_score, Reverse(a.version_count), !a.alive, a.name.len(), &a.name)
+            .cmp(&(b_score, Reverse(b.version_count), !b.alive, b.name.len(), &b.name))
     });
 
-    recv
+    println!("- fuzzy autocomplete took {:?}", start.elapsed());
+    matches.iter().take(count as usize).map(|(_, c)| c.name.clone()).collect()
 }
 
+/// Known names within a few typos of the whole query, for when a search finds nothing
+pub fn did_you_mean(project: &str, query: &str, count: u64) -> Vec<String> {
+    assert!(!project.contains("/"));
+    if !query.chars().all(|c| c.is_alphanumeric() || c == '_') || query.is_empty() {
+        return vec![];
+    }
+    let max_typos = fuzzy::max_typos(query);
+
+    let completions = get(project);
+    let mut matches = completions.names.iter()
+        .filter_map(|c| fuzzy::edit_distance(query, &c.name, max_typos).map(|typos| (typos, c)))
+        .filter(|(_, c)| c.name != query)
+        .collect::<Vec<_>>();
+    matches.sort_by(|(a_typos, a), (b_typos, b)| {
+        (a_typos, Reverse(a.version_count), !a.alive, &a.name)
+            .cmp(&(b_typos, Reverse(b.version_count), !b.alive, &b.name))
+    });
+    matches.iter().take(count as usize).map(|(_, c)| c.name.clone()).collect()
+}
@@ -0,0 +1,247 @@
+use crate::diffs::{self, DiffQuery, Truncation};
+use crate::history::{self, DiffAction, DiffRecord};
+use crate::{html, lifetime, projects, references, source};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+/**
+ * A symbol defined in the file, with when it appeared and when its signature last changed
+ */
+#[derive(Serialize)]
+pub struct BlameLine {
+    pub name: String,
+    pub kind: String,
+    pub line: u64,
+    pub signature: String,
+    /// The version it first appeared in, anywhere in the project
+    pub introduced: Option<String>,
+    /// The latest version up to the blamed one that modified it, `None` if it never changed
+    pub last_changed: Option<String>,
+    /// Whether that modification was of the definition in this file, rather than one elsewhere
+    pub changed_in_file: bool,
+}
+
+#[derive(Serialize)]
+pub struct Blame {
+    pub version: String,
+    pub path: String,
+    pub symbols: Vec<BlameLine>,
+    /// Whether looking for modifications timed out, leaving some `last_changed` empty that shouldn't be
+    pub timed_out: bool,
+}
+
+/// A blob's `tags/<oid>` file: `name\tType\tline\textra` lines, `None` if it wasn't kept
+fn read_tags(db_path: &Path, oid: &str) -> Option<Vec<(String, String, u64, String)>> {
+    let file = File::open(db_path.join("tags").join(oid)).ok()?;
+    let tags = BufReader::new(file).lines()
+        .map(|line| line.unwrap())
+        // The references come after the symbols
+        .take_while(|line| !references::is_reference_line(line))
+        .filter_map(|line| {
+            let mut parts = line.splitn(4, '\t');
+            Some((
+                parts.next()?.to_string(),
+                parts.next()?.to_string(),
+                parts.next()?.parse().ok()?,
+                parts.next().unwrap_or("").to_string(),
+            ))
+        })
+        .collect();
+    Some(tags)
+}
+
+/// The symbols of the file at `version`: from the blob's tags if its oid can be found and they
+/// were kept, otherwise rebuilt from the diffs
+fn file_symbols(project: &str, version: &str, path: &str) -> Result<Vec<(String, String, u64, String)>, String> {
+    let db_path = Path::new("sourcedigger-db").join(project);
+    if let Ok(oid) = source::blob_id(project, version, path) {
+        // Released databases only ship the tags of projects indexed with references
+        if let Some(tags) = read_tags(&db_path, &oid) {
+            return Ok(tags);
+        }
+    }
+
+    rebuilt_symbols(&db_path, version, path)
+}
+
+/// The symbols of the file at `version`, replayed from the diffs
+fn rebuilt_symbols(db_path: &Path, version: &str, path: &str) -> Result<Vec<(String, String, u64, String)>, String> {
+    let versions = history::read_versions(db_path);
+    let idx = history::version_index(&versions, version).ok_or_else(|| format!("Unknown version: {}", version))?;
+    let symbols = history::symbols_at(db_path, &versions, idx, |record| record.data.file == path);
+    Ok(symbols.symbols.iter()
+        .flat_map(|((name, symbol_type), datas)| datas.iter()
+            .map(move |data| (name.clone(), format!("{:?}", symbol_type), data.line, data.extra.clone())))
+        .collect())
+}
+
+/// The latest modification of each symbol, by (name, kind), and whether it was in `path`.
+/// Records come newest first, and one in the file wins over a later one elsewhere.
+fn note_change(last_changed: &mut HashMap<(String, String), (String, bool)>, version: &str, record: &DiffRecord, path: &str) {
+    if record.action != DiffAction::Modify {
+        return;
+    }
+    let in_file = record.data.file == path;
+    let key = (record.name.clone(), format!("{:?}", record.symbol_type));
+    match last_changed.get(&key) {
+        Some((_, true)) => {}
+        Some((_, false)) if !in_file => {}
+        _ => {
+            last_changed.insert(key, (version.to_string(), in_file));
+        }
+    }
+}
+
+impl Blame {
+    pub fn compute(project: &str, version: &str, path: &str) -> Result<Self, String> {
+        let mut symbols = file_symbols(project, version, path)?;
+        symbols.sort_by_key(|(_, _, line, _)| *line);
+        if symbols.is_empty() {
+            return Err(format!("No symbols in {} at {}", path, version));
+        }
+
+        let mut names = symbols.iter().map(|(name, _, _, _)| name.as_str()).collect::<Vec<_>>();
+        names.sort();
+        names.dedup();
+
+        // One lookup for every name in the file, rather than one per symbol
+        let names_query = names.iter().map(|name| regex::escape(name)).collect::<Vec<_>>().join("|");
+        let introduced = lifetime::find_lifetimes(project, &names_query, u64::MAX)
+            .into_iter()
+            .map(|l| ((l.name, l.symbol_type), l.introduced))
+            .collect::<HashMap<_, _>>();
+        let mut last_changed = HashMap::new();
+        let status = diffs::search(project, &DiffQuery::exact_names(&names, Some(version.to_string())), |hits| {
+            for record in hits.records.iter() {
+                note_change(&mut last_changed, &hits.version, record, path);
+            }
+        });
+
+        Ok(Blame {
+            version: version.to_string(),
+            path: path.to_string(),
+            timed_out: status.truncated == Some(Truncation::Timeout),
+            symbols: symbols.into_iter()
+                .map(|(name, kind, line, extra)| {
+                    let key = (name, kind);
+                    let change = last_changed.get(&key);
+                    BlameLine {
+                        signature: history::format_signature(&key.0, history::parse_symbol_type(&key.1), &extra),
+                        introduced: introduced.get(&key).cloned(),
+                        last_changed: change.map(|(version, _)| version.clone()),
+                        changed_in_file: change.is_some_and(|(_, in_file)| *in_file),
+                        line,
+                        name: key.0,
+                        kind: key.1,
+                    }
+                })
+                .collect(),
+        })
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    pub fn to_html(&self, project: &str) -> String {
+        let project_data = projects::get(project);
+        let release_link = |version: &Option<String>| match version {
+            Some(version) => format!(
+                "<a href=\"/{}/release/{}\">{}</a>",
+                project,
+                url::form_urlencoded::byte_serialize(version.as_bytes()).collect::<String>(),
+                html::escape(version)
+            ),
+            None => String::new(),
+        };
+        let mut output = format!(
+            "<!DOCTYPE html>\n<meta charset=\"UTF-8\">\n<title>{1} - {0} - SourceDigger</title>\n<link rel=\"stylesheet\" href=\"/static/results-inner.css\">\n<h1 class=source>{1} <small>{0}</small></h1>\n",
+            html::escape(&self.version),
+            html::escape(&self.path),
+        );
+        if self.timed_out {
+            output += "<p class=summary>Looking for changes took too long, so some symbols are missing when they last changed</p>\n";
+        }
+        output += "<table class=blame>\n<tr><th>Line</th><th></th><th>Symbol</th><th>Introduced</th><th>Last changed</th></tr>\n";
+        for symbol in self.symbols.iter() {
+            let symbol_type = history::parse_symbol_type(&symbol.kind);
+            // Changes link to the definition diff when the sources are there to show it
+            let last_changed = match &symbol.last_changed {
+                Some(version) if source::has_sources(project) => {
+                    let mut params = url::form_urlencoded::Serializer::new(String::new());
+                    params.append_pair("version", version);
+                    params.append_pair("t", history::symbol_type_letter(symbol_type));
+                    // Otherwise the diff could pick another definition's modification
+                    if symbol.changed_in_file {
+                        params.append_pair("file", &self.path);
+                    }
+                    format!(
+                        "<a href=\"/{}/definition/{}?{}\">{}</a>",
+                        project,
+                        url::form_urlencoded::byte_serialize(symbol.name.as_bytes()).collect::<String>(),
+                        params.finish(),
+                        html::escape(version)
+                    )
+                }
+                version => release_link(version),
+            };
+            output += &format!(
+                "<tr><td><a href=\"{0}\">{1}</a></td><td class=kind>{2}</td><td><a href=\"/{3}?q={4}\"><code>{5}</code></a></td><td>{6}</td><td>{7}</td></tr>\n",
+                project_data.source_link(&self.version, &self.path, symbol.line),
+                symbol.line,
+                history::symbol_type_letter(symbol_type),
+                project,
+                url::form_urlencoded::byte_serialize(symbol.name.as_bytes()).collect::<String>(),
+                html::escape(&symbol.signature),
+                release_link(&symbol.introduced),
+                last_changed,
+            );
+        }
+        output + "</table>\n"
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changes_in_the_file_win() {
+        let mut last_changed = HashMap::new();
+        let record = |line: &str| DiffRecord::parse(line).unwrap();
+        // Newest first
+        note_change(&mut last_changed, "v4", &record("m\tfoo\tFunction\tother.c\t1\tlong {name}(void)"), "a.c");
+        note_change(&mut last_changed, "v3", &record("a\tbar\tFunction\ta.c\t1\tint {name}(void)"), "a.c");
+        note_change(&mut last_changed, "v2", &record("m\tfoo\tFunction\ta.c\t1\tint {name}(int)"), "a.c");
+        note_change(&mut last_changed, "v1", &record("m\tfoo\tFunction\ta.c\t1\tint {name}(void)"), "a.c");
+        let key = |name: &str| (name.to_string(), "Function".to_string());
+        assert_eq!(last_changed.get(&key("foo")), Some(&("v2".to_string(), true)));
+        assert_eq!(last_changed.get(&key("bar")), None);
+    }
+
+    #[test]
+    fn symbols_from_tags_or_diffs() {
+        let db_path = std::env::temp_dir().join(format!("sourcedigger-blame-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&db_path);
+        std::fs::create_dir_all(db_path.join("tags")).unwrap();
+        std::fs::create_dir_all(db_path.join("diffs")).unwrap();
+        std::fs::write(db_path.join("tags").join("aa"), "foo\tFunction\t3\tint {name}(void)\nfoo\tReference\t7 9\t\n").unwrap();
+        assert_eq!(read_tags(&db_path, "aa").unwrap(), vec![
+            ("foo".to_string(), "Function".to_string(), 3, "int {name}(void)".to_string()),
+        ]);
+        assert!(read_tags(&db_path, "bb").is_none());
+
+        std::fs::write(db_path.join("diffs").join("v1"), "a\tfoo\tFunction\ta.c\t3\tint {name}(void)\na\tbar\tFunction\tb.c\t1\tint {name}(void)\n").unwrap();
+        std::fs::write(db_path.join("diffs").join("v2"), "m\tfoo\tFunction\ta.c\t4\tint {name}(int)\n").unwrap();
+        history::write_versions(&db_path, &["v1".to_string(), "v2".to_string()]);
+        assert_eq!(rebuilt_symbols(&db_path, "v2", "a.c").unwrap(), vec![
+            ("foo".to_string(), "Function".to_string(), 4, "int {name}(int)".to_string()),
+        ]);
+        assert!(rebuilt_symbols(&db_path, "v3", "a.c").is_err());
+        std::fs::remove_dir_all(&db_path).unwrap();
+    }
+}
@@ -0,0 +1,335 @@
+use crate::version_files;
+use crate::{history, html, projects, query};
+use regex::Regex;
+use serde::Serialize;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Matching lines are cut off after this many bytes
+const MAX_LINE_LENGTH: usize = 300;
+
+/// Without the trigram index, or literals to look up in it, every file of the version is read
+const CODE_SEARCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/**
+ * What to look for in the sources of a version
+ */
+pub struct CodeQuery {
+    regex: Regex,
+    /// Text every match contains, for ruling out files with the trigram index
+    literals: Vec<String>,
+    path_globs: Vec<Regex>,
+    count: usize,
+}
+
+/// The literal runs a regex can't match without, e.g. `kmalloc(` and `GFP_` for `kmalloc\(.*GFP_`.
+/// Alternations could match without any of them, so they give up. Groups, classes, repetitions
+/// like `{1,3}` and escapes like `\d` or `\x41` end a run and are skipped.
+fn required_literals(pattern: &str) -> Vec<String> {
+    if pattern.contains('|') {
+        return vec![];
+    }
+    let mut literals = vec![];
+    let mut current = String::new();
+    let mut depth = 0;
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        let literal = match c {
+            '\\' => match chars.next() {
+                Some(escaped) if !escaped.is_ascii_alphanumeric() => Some(escaped),
+                // A character by its code or a Unicode class, e.g. `\x41`, `\u{1F600}` or `\pL`
+                Some(kind @ 'x') | Some(kind @ 'u') | Some(kind @ 'U') | Some(kind @ 'p') | Some(kind @ 'P') => {
+                    if chars.peek() == Some(&'{') {
+                        while chars.next().is_some_and(|c| c != '}') {}
+                    } else {
+                        let length = match kind {
+                            'x' => 2,
+                            'u' => 4,
+                            'U' => 8,
+                            _ => 1,
+                        };
+                        for _ in 0..length {
+                            chars.next();
+                        }
+                    }
+                    None
+                }
+                _ => None,
+            },
+            '[' => {
+                // A `]` right at the start of a class is part of it
+                if chars.peek() == Some(&'^') {
+                    chars.next();
+                }
+                if chars.peek() == Some(&']') {
+                    chars.next();
+                }
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => {
+                            chars.next();
+                        }
+                        ']' => break,
+                        _ => (),
+                    }
+                }
+                None
+            }
+            '{' => {
+                while chars.next().is_some_and(|c| c != '}') {}
+                None
+            }
+            '(' => {
+                depth += 1;
+                None
+            }
+            ')' => {
+                depth -= 1;
+                None
+            }
+            '.' | '^' | '$' | '*' | '+' | '?' => None,
+            c => Some(c),
+        };
+        match literal {
+            Some(literal) if depth == 0 => {
+                // A quantifier after it makes it optional, but the run before it is still required
+                if chars.peek().is_some_and(|next| ['*', '?', '{'].contains(next)) {
+                    literals.push(std::mem::take(&mut current));
+                } else {
+                    current.push(literal);
+                }
+            }
+            _ => literals.push(std::mem::take(&mut current)),
+        }
+    }
+    literals.push(current);
+    literals.retain(|literal| literal.len() >= 3);
+    literals
+}
+
+impl CodeQuery {
+    /// `q` is plain text unless `is_regex`, either way smart-case. `path` takes comma separated path globs.
+    pub fn parse(q: &str, is_regex: bool, path: Option<String>, count: Option<usize>) -> Result<Self, String> {
+        if q.is_empty() {
+            return Err("Nothing to search for".to_string());
+        }
+        let pattern = if is_regex { q.to_string() } else { regex::escape(q) };
+        let flags = if q.chars().any(char::is_uppercase) { "" } else { "(?i)" };
+        let regex = Regex::new(&format!("{}{}", flags, pattern)).map_err(|e| format!("Invalid regex `{}`: {}", q, e))?;
+        Ok(CodeQuery {
+            regex,
+            literals: if is_regex { required_literals(q) } else { vec![q.to_string()] },
+            path_globs: path.iter()
+                .flat_map(|p| p.split(','))
+                .filter(|glob| !glob.is_empty())
+                .map(query::path_glob)
+                .collect::<Result<_, _>>()?,
+            count: match count {
+                Some(0) => return Err("`count` has to be at least 1".to_string()),
+                count => count.unwrap_or(200),
+            },
+        })
+    }
+}
+
+#[derive(Serialize)]
+pub struct CodeLine {
+    pub line: u64,
+    pub text: String,
+}
+
+#[derive(Serialize)]
+pub struct CodeFile {
+    pub path: String,
+    pub lines: Vec<CodeLine>,
+}
+
+/**
+ * The matching lines of a version's files
+ */
+#[derive(Serialize)]
+pub struct CodeResults {
+    pub version: String,
+    pub files: Vec<CodeFile>,
+    /// How many files were searched after the trigram index ruled out the rest
+    pub searched: usize,
+    /// Whether the search stopped at the result count
+    pub truncated: bool,
+    /// Whether searching took too long, leaving some files out
+    pub timed_out: bool,
+}
+
+fn truncate(line: &str) -> String {
+    if line.len() <= MAX_LINE_LENGTH {
+        return line.to_string();
+    }
+    let mut end = MAX_LINE_LENGTH;
+    while !line.is_char_boundary(end) {
+        end -= 1;
+    }
+    line[..end].to_string()
+}
+
+impl CodeResults {
+    /// At `version`, or the latest one
+    pub fn search(project: &str, version: Option<&str>, code_query: &CodeQuery) -> Result<Self, String> {
+        let start = Instant::now();
+        let db_path = Path::new("sourcedigger-db").join(project);
+        if !db_path.join("objects").exists() {
+            return Err(format!("The extracted sources of {} aren't on this server", project));
+        }
+        let versions = history::read_versions(&db_path);
+        let version = match version {
+            Some(version) => versions[history::version_index(&versions, version).ok_or_else(|| format!("Unknown version: {}", version))?].clone(),
+            None => versions.last().cloned().ok_or_else(|| format!("{} has no indexed versions", project))?,
+        };
+        let files = version_files::read_version_files(&db_path, &version)
+            .ok_or_else(|| format!("The file list of {} wasn't written, updating the index writes it", version))?;
+        // Without the index every file is searched, which still works, only slower
+        let index = projects::code_index(project);
+        let candidates = index.as_ref().and_then(|index| index.candidates(&code_query.literals));
+
+        let mut results = CodeResults { version, files: vec![], searched: 0, truncated: false, timed_out: false };
+        let mut match_count = 0;
+        for (path, oid) in files {
+            if start.elapsed() > CODE_SEARCH_TIMEOUT {
+                results.timed_out = true;
+                break;
+            }
+            // Objects extracted since the index was last updated can't be ruled out
+            if let (Some(index), Some(candidates)) = (&index, &candidates) {
+                if !candidates.contains(&oid) && index.is_indexed(&oid) {
+                    continue;
+                }
+            }
+            if !code_query.path_globs.is_empty() && !code_query.path_globs.iter().any(|glob| glob.is_match(&path)) {
+                continue;
+            }
+            let content = match std::fs::read(db_path.join("objects").join(&oid)) {
+                Ok(content) => content,
+                Err(_) => continue,
+            };
+            if content.contains(&0) {
+                continue;
+            }
+            results.searched += 1;
+
+            let text = String::from_utf8_lossy(&content);
+            let mut lines = vec![];
+            for (i, line) in text.lines().enumerate() {
+                if !code_query.regex.is_match(line) {
+                    continue;
+                }
+                if match_count == code_query.count {
+                    results.truncated = true;
+                    break;
+                }
+                lines.push(CodeLine { line: i as u64 + 1, text: truncate(line) });
+                match_count += 1;
+            }
+            if !lines.is_empty() {
+                results.files.push(CodeFile { path, lines });
+            }
+            if results.truncated {
+                break;
+            }
+        }
+        Ok(results)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    /// The line with its matches in `<mark>`s
+    fn highlight(regex: &Regex, text: &str) -> String {
+        let mut output = String::new();
+        let mut last = 0;
+        for m in regex.find_iter(text).filter(|m| m.end() > m.start()) {
+            output += &html::escape(&text[last..m.start()]);
+            output += &format!("<mark>{}</mark>", html::escape(m.as_str()));
+            last = m.end();
+        }
+        output + &html::escape(&text[last..])
+    }
+
+    pub fn to_html(&self, project: &str, code_query: &CodeQuery, q: &str, is_regex: bool, path: Option<&str>) -> String {
+        // The built-in source viewer reads the same extracted objects, and has the file at exactly this version
+        let line_link = |file: &str, line: u64| format!(
+            "/{}/source/{}/{}#L{}",
+            project,
+            url::form_urlencoded::byte_serialize(self.version.as_bytes()).collect::<String>(),
+            html::escape(file),
+            line
+        );
+        let line_count = self.files.iter().map(|f| f.lines.len()).sum::<usize>();
+        let mut output = format!(
+            "<!DOCTYPE html>\n<meta charset=\"UTF-8\">\n<title>{0} - {1} - SourceDigger</title>\n<link rel=\"stylesheet\" href=\"/static/results-inner.css\">\n<h1>{0} <small>{1}</small></h1>\n\
+            <form class=filters><input type=hidden name=version value=\"{1}\"><input name=q placeholder=\"text or regex\" value=\"{0}\"> \
+            <label><input type=checkbox name=regex value=true{2}> regex</label> <input name=path placeholder=\"path globs\" value=\"{3}\"> <button>Search</button></form>\n\
+            <p class=summary>{4}{5} lines in {6} files, {7} files searched</p>\n",
+            html::escape(q),
+            html::escape(&self.version),
+            if is_regex { " checked" } else { "" },
+            html::escape(path.unwrap_or("")),
+            if self.truncated { "First " } else { "" },
+            line_count,
+            self.files.len(),
+            self.searched,
+        );
+        if self.timed_out {
+            output += "<p class=summary>Searching took too long, so some files are missing</p>\n";
+        }
+        for file in self.files.iter() {
+            output += &format!("<h2 class=h>{} <small>{}</small></h2>\n", html::escape(&file.path), file.lines.len());
+            for line in file.lines.iter() {
+                output += &format!(
+                    "<div class=usage><a href=\"{}\">{}</a><code>{}</code></div>\n",
+                    line_link(&file.path, line.line),
+                    line.line,
+                    Self::highlight(&code_query.regex, &line.text),
+                );
+            }
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{required_literals, CodeQuery};
+
+    #[test]
+    fn literal_runs() {
+        assert_eq!(required_literals("kmalloc\\(.*GFP_"), vec!["kmalloc(", "GFP_"]);
+        assert_eq!(required_literals("spin_lock_irq(save)?"), vec!["spin_lock_irq"]);
+        assert_eq!(required_literals("foo|bar"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn repetitions_are_not_literals() {
+        assert_eq!(required_literals("a{1,3}bcd"), vec!["bcd"]);
+        assert_eq!(required_literals("[0-9]{1,3}\\.[0-9]{1,3}"), Vec::<String>::new());
+        assert_eq!(required_literals("abcd{2}"), vec!["abc"]);
+    }
+
+    #[test]
+    fn escapes_are_not_literals() {
+        assert_eq!(required_literals("\\x41BCD"), vec!["BCD"]);
+        assert_eq!(required_literals("\\x{41}BCD"), vec!["BCD"]);
+        assert_eq!(required_literals("\\dfoo\\sbar"), vec!["foo", "bar"]);
+        assert_eq!(required_literals("\\pLfoo"), vec!["foo"]);
+    }
+
+    #[test]
+    fn classes_are_skipped() {
+        assert_eq!(required_literals("[]()]xyz"), vec!["xyz"]);
+        assert_eq!(required_literals("abc[^\\]]def"), vec!["abc", "def"]);
+    }
+
+    #[test]
+    fn counts_have_to_be_positive() {
+        assert!(CodeQuery::parse("kmalloc", false, None, Some(0)).is_err());
+        assert_eq!(CodeQuery::parse("kmalloc", false, None, None).unwrap().count, 200);
+    }
+}
@@ -0,0 +1,392 @@
+use crate::data::ProjectRepo;
+use crate::diffs;
+use crate::history::{self, DiffAction, NetChange, SymbolData};
+use crate::{html, projects, query};
+use regex::Regex;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Long ranges replay many diffs, what was compared by then is shown
+const COMPARE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/**
+ * Everything that changed between two versions, grouped by directory and kind
+ */
+#[derive(Serialize)]
+pub struct Comparison {
+    pub from: String,
+    pub to: String,
+    pub added: usize,
+    pub removed: usize,
+    pub modified: usize,
+    pub groups: Vec<ChangeGroup>,
+    /// Whether comparing took too long, leaving out the changes after `reached`
+    pub timed_out: bool,
+    /// The last version compared, `to` unless it timed out
+    pub reached: String,
+}
+
+#[derive(Serialize)]
+pub struct ChangeGroup {
+    /// With a trailing slash, empty for the top level
+    pub directory: String,
+    pub kind: String,
+    pub changes: Vec<Change>,
+}
+
+#[derive(Serialize)]
+pub struct Change {
+    pub name: String,
+    pub action: DiffAction,
+    /// The definitions in `from`, empty for added symbols
+    pub old: Vec<Definition>,
+    /// The definitions in `to`, empty for removed symbols
+    pub new: Vec<Definition>,
+}
+
+#[derive(Serialize)]
+pub struct Definition {
+    pub file: String,
+    pub line: u64,
+    pub signature: String,
+    pub source_link: String,
+}
+
+/**
+ * How the groups are ordered
+ */
+#[derive(Clone, Copy, PartialEq)]
+pub enum CompareSort {
+    Directory,
+    Kind,
+    /// The groups with the most changes first
+    Size,
+}
+
+/**
+ * The query parameters of the compare page and API
+ */
+pub struct CompareParams {
+    pub q: Option<String>,
+    pub a: Option<String>,
+    pub t: Option<String>,
+    pub include: Option<String>,
+    pub exclude: Option<String>,
+    /// "directory" (the default), "kind" or "size"
+    pub sort: Option<String>,
+}
+
+/**
+ * What to keep of the comparison, parsed from the same field syntax as the search box
+ */
+pub struct CompareQuery {
+    pub from: String,
+    pub to: String,
+    name_regex: Option<Regex>,
+    actions: String,
+    types: String,
+    include_paths: Vec<Vec<Regex>>,
+    exclude_paths: Vec<Regex>,
+    signature: Option<String>,
+    pub sort: CompareSort,
+    /// The raw parameters, for the filter form
+    pub params: CompareParams,
+}
+
+impl CompareQuery {
+    /// `range` is `<from>...<to>`
+    pub fn parse(range: &str, params: CompareParams) -> Result<Self, String> {
+        let mut versions = range.splitn(2, "...");
+        let (from, to) = match (versions.next(), versions.next()) {
+            (Some(from), Some(to)) if !from.is_empty() && !to.is_empty() => (from.to_string(), to.to_string()),
+            _ => return Err(format!("Expected a range like `v1.0...v2.0`, got `{}`", range)),
+        };
+
+        let fields = query::parse_fields(params.q.as_deref().unwrap_or(""))?;
+        if fields.since.is_some() || fields.until.is_some() {
+            return Err("`since:` and `until:` can't be used here, the versions are given by the range".to_string());
+        }
+        if fields.pattern.is_some() && fields.name.is_some() {
+            return Err("Give the name either as `name:` or as a plain pattern, not both".to_string());
+        }
+        let name_regex = match fields.pattern.as_ref().or(fields.name.as_ref()) {
+            Some(name) => Some(query::name_regex(name).map_err(|e| format!("Invalid name pattern `{}`: {}", name, e))?),
+            None => None,
+        };
+        let sort = match params.sort.as_deref() {
+            None | Some("directory") => CompareSort::Directory,
+            Some("kind") => CompareSort::Kind,
+            Some("size") => CompareSort::Size,
+            Some(sort) => return Err(format!("Unknown sort `{}`, expected directory, kind or size", sort)),
+        };
+
+        Ok(CompareQuery {
+            from,
+            to,
+            name_regex,
+            actions: fields.actions.or_else(|| params.a.clone()).unwrap_or_else(|| "arm".to_string()),
+            types: fields.kinds.or_else(|| params.t.clone()).unwrap_or_else(|| "fvd".to_string()),
+            include_paths: vec![diffs::path_globs(&fields.paths, &None)?, diffs::path_globs(&[], &params.include)?],
+            exclude_paths: diffs::path_globs(&fields.excluded_paths, &params.exclude)?,
+            signature: fields.signature.map(|s| s.to_lowercase()),
+            sort,
+            params,
+        })
+    }
+
+    fn matches(&self, change: &NetChange) -> bool {
+        let files = || change.old.iter().chain(change.new.iter()).map(|d| d.file.as_str());
+        let signatures = || change.old.iter().chain(change.new.iter())
+            .map(|d| history::format_signature(&change.name, change.symbol_type, &d.extra).to_lowercase());
+        self.actions.contains(change.action().letter())
+            && self.types.contains(history::symbol_type_letter(change.symbol_type))
+            && self.name_regex.as_ref().is_none_or(|r| r.is_match(&change.name))
+            && self.include_paths.iter().all(|group| group.is_empty() || files().any(|f| group.iter().any(|r| r.is_match(f))))
+            && !files().any(|f| self.exclude_paths.iter().any(|r| r.is_match(f)))
+            && self.signature.as_ref().is_none_or(|s| signatures().any(|sig| sig.contains(s.as_str())))
+    }
+}
+
+/// The directory of the symbol's current definition, or of where it was before being removed
+fn directory_of(change: &NetChange) -> String {
+    let file = change.new.first().or_else(|| change.old.first()).map(|d| d.file.as_str()).unwrap_or("");
+    match file.rfind('/') {
+        Some(idx) => file[..idx + 1].to_string(),
+        None => String::new(),
+    }
+}
+
+fn definitions(project_data: &ProjectRepo, version: &str, change: &NetChange, datas: &[SymbolData]) -> Vec<Definition> {
+    datas.iter()
+        .map(|d| Definition {
+            file: d.file.clone(),
+            line: d.line,
+            signature: history::format_signature(&change.name, change.symbol_type, &d.extra),
+            source_link: project_data.source_link(version, &d.file, d.line),
+        })
+        .collect()
+}
+
+/// Changelogs list what's new first
+fn action_order(action: DiffAction) -> u8 {
+    match action {
+        DiffAction::Add => 0,
+        DiffAction::Modify => 1,
+        DiffAction::Remove => 2,
+    }
+}
+
+impl Comparison {
+    pub fn compute(project: &str, compare_query: &CompareQuery) -> Result<Self, String> {
+        let start = Instant::now();
+        let (changes, timed_out_at) = history::net_changes_until(
+            &Path::new("sourcedigger-db").join(project),
+            &compare_query.from,
+            &compare_query.to,
+            Some(start + COMPARE_TIMEOUT),
+        )?;
+        println!("- compared {} {}...{} in {:?}", project, compare_query.from, compare_query.to, start.elapsed());
+        Ok(Self::from_changes(&projects::get(project), compare_query, &changes, timed_out_at))
+    }
+
+    /// Groups the changes that match the query, which go up to `timed_out_at` if comparing ran out of time
+    fn from_changes(project_data: &ProjectRepo, compare_query: &CompareQuery, changes: &[NetChange], timed_out_at: Option<String>) -> Self {
+        let mut comparison = Comparison {
+            from: compare_query.from.clone(),
+            to: compare_query.to.clone(),
+            added: 0,
+            removed: 0,
+            modified: 0,
+            groups: vec![],
+            timed_out: timed_out_at.is_some(),
+            reached: timed_out_at.unwrap_or_else(|| compare_query.to.clone()),
+        };
+        let mut groups: BTreeMap<(String, String), Vec<Change>> = BTreeMap::new();
+        for change in changes.iter().filter(|change| compare_query.matches(change)) {
+            match change.action() {
+                DiffAction::Add => comparison.added += 1,
+                DiffAction::Remove => comparison.removed += 1,
+                DiffAction::Modify => comparison.modified += 1,
+            }
+            groups.entry((directory_of(change), format!("{:?}", change.symbol_type)))
+                .or_default()
+                .push(Change {
+                    name: change.name.clone(),
+                    action: change.action(),
+                    old: definitions(project_data, &compare_query.from, change, &change.old),
+                    new: definitions(project_data, &comparison.reached, change, &change.new),
+                });
+        }
+
+        comparison.groups = groups.into_iter()
+            .map(|((directory, kind), mut changes)| {
+                changes.sort_by(|a, b| (action_order(a.action), &a.name).cmp(&(action_order(b.action), &b.name)));
+                ChangeGroup { directory, kind, changes }
+            })
+            .collect();
+        match compare_query.sort {
+            CompareSort::Directory => {}
+            CompareSort::Kind => comparison.groups.sort_by(|a, b| (&a.kind, &a.directory).cmp(&(&b.kind, &b.directory))),
+            CompareSort::Size => comparison.groups.sort_by(|a, b| {
+                b.changes.len().cmp(&a.changes.len()).then_with(|| a.directory.cmp(&b.directory))
+            }),
+        }
+        comparison
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    pub fn to_html(&self, project: &str, compare_query: &CompareQuery) -> String {
+        let params = &compare_query.params;
+        let value = |param: &Option<String>| html::escape(param.as_deref().unwrap_or(""));
+        let mut output = format!(
+            "<!DOCTYPE html>\n<meta charset=\"UTF-8\">\n<title>{0} &rarr; {1} - {2} - SourceDigger</title>\n<link rel=\"stylesheet\" href=\"/static/results-inner.css\">\n<h1>Changes from {0} to {1}</h1>\n\
+            <form class=filters><input name=q placeholder=\"name, kind:, action:, path:, sig:\" value=\"{3}\"> \
+            <input name=include placeholder=paths value=\"{4}\"> <input name=exclude placeholder=\"excluded paths\" value=\"{5}\"> \
+            <select name=sort>",
+            html::escape(&self.from),
+            html::escape(&self.to),
+            html::escape(project),
+            value(&params.q),
+            value(&params.include),
+            value(&params.exclude),
+        );
+        for (name, sort) in [("directory", CompareSort::Directory), ("kind", CompareSort::Kind), ("size", CompareSort::Size)].iter() {
+            output += &format!(
+                "<option{}>{}</option>",
+                if *sort == compare_query.sort { " selected" } else { "" },
+                name
+            );
+        }
+        output += &format!(
+            "</select> <button>Filter</button></form>\n\
+            <p class=summary><span class=a>+{} added</span> <span class=m>~{} modified</span> <span class=r>-{} removed</span></p>\n",
+            self.added,
+            self.modified,
+            self.removed,
+        );
+        if self.timed_out {
+            output += &format!(
+                "<p class=summary>Comparing took too long, so only the changes up to {} are shown</p>\n",
+                html::escape(&self.reached)
+            );
+        }
+
+        for group in self.groups.iter() {
+            output += &format!(
+                "<h2 class=h>{} &middot; {} ({})</h2>\n",
+                if group.directory.is_empty() { "(top level)".to_string() } else { html::escape(&group.directory) },
+                html::escape(&group.kind),
+                group.changes.len()
+            );
+            let kind_letter = history::symbol_type_letter(history::parse_symbol_type(&group.kind));
+            for change in group.changes.iter() {
+                output += &change_to_html(project, kind_letter, change);
+            }
+        }
+
+        output
+    }
+}
+
+fn change_to_html(project: &str, kind_letter: &str, change: &Change) -> String {
+    let (sign, definitions) = match change.action {
+        DiffAction::Add => ("+", &change.new),
+        DiffAction::Remove => ("-", &change.old),
+        DiffAction::Modify => ("~", &change.new),
+    };
+    let mut output = String::new();
+    for definition in definitions.iter() {
+        output += &format!(
+            "<div class={0}><a class={1}>{1}</a><a class={0}>{2}</a><a href=\"/{3}?q={4}\"><span></span><span>{5}</span></a>&nbsp;<code>{6}</code><hr><a href=\"{7}\">{8}:{9}</a></div>\n",
+            change.action.letter(),
+            kind_letter,
+            sign,
+            project,
+            url::form_urlencoded::byte_serialize(change.name.as_bytes()).collect::<String>(),
+            html::escape(&change.name),
+            html::escape(&definition.signature),
+            definition.source_link,
+            html::escape(&definition.file),
+            definition.line
+        );
+    }
+    // Modifications also show what the signature used to be, unless only the location changed
+    if change.action == DiffAction::Modify {
+        for old in change.old.iter().filter(|old| change.new.iter().all(|new| new.signature != old.signature)) {
+            output += &format!("<div class=was><code>was: {}</code><hr><a href=\"{}\">{}:{}</a></div>\n",
+                html::escape(&old.signature),
+                old.source_link,
+                html::escape(&old.file),
+                old.line
+            );
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(q: &str, sort: Option<&str>) -> CompareParams {
+        CompareParams { q: Some(q.to_string()), a: None, t: None, include: None, exclude: None, sort: sort.map(str::to_string) }
+    }
+
+    fn groups(comparison: &Comparison) -> Vec<(&str, &str, Vec<&str>)> {
+        comparison.groups.iter()
+            .map(|g| (g.directory.as_str(), g.kind.as_str(), g.changes.iter().map(|c| c.name.as_str()).collect()))
+            .collect()
+    }
+
+    #[test]
+    fn grouped_net_changes() {
+        let db_path = std::env::temp_dir().join(format!("sourcedigger-compare-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&db_path);
+        std::fs::create_dir_all(db_path.join("diffs")).unwrap();
+        let diffs = [
+            ("v1", "a\tkept\tFunction\tmm/a.c\t1\tint {name}(void)\na\tgone\tFunction\tmm/a.c\t5\tint {name}(void)\na\tVALUE\tDefine\tmm/a.h\t1\t 1\n"),
+            ("v2", "a\ttemporary\tFunction\tfs/b.c\t1\tint {name}(void)\nr\tgone\tFunction\tmm/a.c\t5\tint {name}(void)\n\
+                    a\tnew_a\tFunction\tfs/c.c\t1\tint {name}(void)\na\tnew_b\tFunction\tfs/c.c\t2\tint {name}(void)\n"),
+            ("v3", "r\ttemporary\tFunction\tfs/b.c\t1\tint {name}(void)\nm\tVALUE\tDefine\tmm/a.h\t1\t 2\n"),
+        ];
+        for (version, diff) in diffs.iter() {
+            std::fs::write(db_path.join("diffs").join(version), diff).unwrap();
+        }
+        history::write_versions(&db_path, &["v1".to_string(), "v2".to_string(), "v3".to_string()]);
+        let project_data = ProjectRepo {
+            name: "Test".to_string(),
+            origin: String::new(),
+            source_viewer: "/test/source/{tag}/{path}#L{line}".to_string(),
+            version_code: None,
+            repo_path: None,
+            references: false,
+            code_search: false,
+        };
+        let compare = |q: &str, sort: Option<&str>| {
+            let compare_query = CompareQuery::parse("v1...v3", params(q, sort)).unwrap();
+            let (changes, timed_out_at) = history::net_changes_until(&db_path, "v1", "v3", None).unwrap();
+            Comparison::from_changes(&project_data, &compare_query, &changes, timed_out_at)
+        };
+
+        // `temporary` was added and removed in between, so it cancels out
+        let comparison = compare("", None);
+        assert_eq!((comparison.added, comparison.modified, comparison.removed, comparison.timed_out), (2, 1, 1, false));
+        assert_eq!(groups(&comparison), vec![
+            ("fs/", "Function", vec!["new_a", "new_b"]),
+            ("mm/", "Define", vec!["VALUE"]),
+            ("mm/", "Function", vec!["gone"]),
+        ]);
+        assert_eq!(comparison.groups[1].changes[0].new[0].source_link, "/test/source/v3/mm/a.h#L1");
+
+        assert_eq!(groups(&compare("", Some("kind"))).iter().map(|g| g.1).collect::<Vec<_>>(), vec!["Define", "Function", "Function"]);
+        assert_eq!(groups(&compare("", Some("size")))[0].0, "fs/");
+        assert_eq!(groups(&compare("kind:f action:r", None)), vec![("mm/", "Function", vec!["gone"])]);
+        assert!(CompareQuery::parse("v1...v3", params("", Some("name"))).is_err());
+        std::fs::remove_dir_all(&db_path).unwrap();
+    }
+}
@@ -0,0 +1,369 @@
+use crate::history::{self, DiffAction, DiffRecord};
+use crate::{html, source};
+use ctags::SymbolType;
+use std::path::Path;
+
+/// Longer definitions are shown whole on both sides, without lining them up
+const MAX_DIFFED_LINES: usize = 1000;
+
+/// Definitions are cut off after this many lines
+const MAX_DEFINITION_LINES: usize = 2000;
+
+/**
+ * The source of one version of a definition
+ */
+pub struct Definition {
+    pub version: String,
+    pub file: String,
+    /// Of the first line
+    pub line: usize,
+    pub lines: Vec<String>,
+}
+
+/**
+ * A modified symbol's definition before and after
+ */
+pub struct DefinitionDiff {
+    pub name: String,
+    pub symbol_type: SymbolType,
+    pub old: Definition,
+    pub new: Definition,
+}
+
+enum DiffLine<'a> {
+    Same(&'a str),
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+/// Where `word` first appears in the line, not as part of a longer identifier
+fn word_index(line: &str, word: &str) -> Option<usize> {
+    line.match_indices(word).map(|(idx, _)| idx).find(|idx| {
+        let before = line[..*idx].chars().next_back();
+        let after = line[idx + word.len()..].chars().next();
+        !before.is_some_and(|c| c.is_alphanumeric() || c == '_') && !after.is_some_and(|c| c.is_alphanumeric() || c == '_')
+    })
+}
+
+fn without_whitespace(text: &str) -> String {
+    text.chars().filter(|c| !c.is_whitespace()).collect()
+}
+
+/// Whether the definition described by the record starts on line `i`, rather than e.g. a call.
+/// Functions are recognized by their arguments as the indexer recorded them, which may span a few lines.
+fn is_definition(lines: &[&str], i: usize, record: &DiffRecord) -> bool {
+    let line = lines[i];
+    let trimmed = line.trim_start();
+    if trimmed.starts_with("/*") || trimmed.starts_with('*') || trimmed.starts_with("//") {
+        return false;
+    }
+    let name = &record.name;
+    let after_name = match word_index(line, name) {
+        Some(idx) => &line[idx + name.len()..],
+        None => return false,
+    };
+    match record.symbol_type {
+        SymbolType::Function => {
+            // Arguments that weren't found to the end are recorded as ", ???)"
+            let recorded = record.data.extra.split_once("{name}").map(|x| x.1).unwrap_or("");
+            let arguments = without_whitespace(recorded.split(", ???)").next().unwrap_or(""));
+            let following = std::iter::once(after_name).chain(lines.iter().skip(i + 1).take(20).cloned()).collect::<String>();
+            let following = without_whitespace(&following);
+            following.starts_with(&arguments) && !following[arguments.len()..].starts_with(';')
+        }
+        SymbolType::Define => trimmed.starts_with('#') && trimmed[1..].trim_start().starts_with("define"),
+        SymbolType::Struct => trimmed.contains("struct") && !line.trim_end().ends_with(';'),
+        // File scope, and not a function of the same name
+        SymbolType::Variable => !line.starts_with(char::is_whitespace) && !after_name.trim_start().starts_with('('),
+        _ => true,
+    }
+}
+
+/// Where the definition starts: the line from the diff record if the definition is there,
+/// otherwise the closest one it's on, since lines move without the record changing
+fn find_start(lines: &[&str], record: &DiffRecord) -> Option<usize> {
+    let hint = (record.data.line as usize).saturating_sub(1).min(lines.len().saturating_sub(1));
+    (0..lines.len())
+        .flat_map(|distance| vec![hint.checked_sub(distance), Some(hint + distance)])
+        .flatten()
+        .filter(|i| *i < lines.len())
+        .find(|i| is_definition(lines, *i, record))
+}
+
+/// The lines of the definition starting at `start`: up to the closing brace of a body,
+/// the end of a macro's continuation lines, or the terminating semicolon
+fn definition_end(lines: &[&str], start: usize, symbol_type: SymbolType) -> usize {
+    let last = (start + MAX_DEFINITION_LINES).min(lines.len()) - 1;
+    if symbol_type == SymbolType::Define {
+        return (start..=last).find(|i| !lines[*i].trim_end().ends_with('\\')).unwrap_or(last);
+    }
+
+    let mut depth = 0i32;
+    let mut seen_body = false;
+    for (i, line) in lines.iter().enumerate().take(last + 1).skip(start) {
+        // Comments and strings with braces in them are rare enough at this level
+        let code = line.split("//").next().unwrap_or("");
+        for c in code.chars() {
+            match c {
+                '{' => {
+                    depth += 1;
+                    seen_body = true;
+                }
+                '}' => depth -= 1,
+                ';' if depth == 0 => return i,
+                _ => {}
+            }
+            if seen_body && depth <= 0 {
+                return i;
+            }
+        }
+    }
+    last
+}
+
+impl Definition {
+    fn read(project: &str, version: &str, record: &DiffRecord) -> Result<Self, String> {
+        let content = source::read_file(project, version, &record.data.file)?;
+        let text = String::from_utf8_lossy(&content);
+        let lines = text.lines().collect::<Vec<_>>();
+        let start = find_start(&lines, record)
+            .ok_or_else(|| format!("{} isn't in {} at {}", record.name, record.data.file, version))?;
+        let end = definition_end(&lines, start, record.symbol_type);
+        Ok(Definition {
+            version: version.to_string(),
+            file: record.data.file.clone(),
+            line: start + 1,
+            lines: lines[start..=end].iter().map(|l| l.to_string()).collect(),
+        })
+    }
+}
+
+/// A longest common subsequence diff, with the removed lines of each change before the added ones
+fn diff_lines<'a>(old: &'a [String], new: &'a [String]) -> Vec<DiffLine<'a>> {
+    if old.len() > MAX_DIFFED_LINES || new.len() > MAX_DIFFED_LINES {
+        return old.iter().map(|l| DiffLine::Removed(l)).chain(new.iter().map(|l| DiffLine::Added(l))).collect();
+    }
+
+    // The common subsequence lengths of every pair of suffixes
+    let mut lengths = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lengths[i][j] = if old[i] == new[j] {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+
+    let mut diff = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            diff.push(DiffLine::Same(&old[i]));
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || lengths[i + 1][j] >= lengths[i][j + 1]) {
+            diff.push(DiffLine::Removed(&old[i]));
+            i += 1;
+        } else {
+            diff.push(DiffLine::Added(&new[j]));
+            j += 1;
+        }
+    }
+    diff
+}
+
+const EMPTY_CELLS: &str = "<td class=ln></td><td></td>";
+
+/// A line number and a line of one side
+fn split_cell(class: &str, number: usize, text: &str) -> String {
+    format!("<td class=ln>{}</td><td class={}><pre>{}</pre></td>", number, class, html::escape(text))
+}
+
+impl DefinitionDiff {
+    /// The definition modified in `version`, and the one it replaced. `kind` is a symbol type letter and
+    /// `file` the new definition's file, for symbols with several definitions.
+    pub fn load(project: &str, name: &str, version: &str, kind: Option<&str>, file: Option<&str>) -> Result<Self, String> {
+        let db_path = Path::new("sourcedigger-db").join(project);
+        let versions = history::read_versions(&db_path);
+        let idx = history::version_index(&versions, version)
+            .ok_or_else(|| format!("Unknown version `{}`", version))?;
+        let record = history::read_diff(&db_path, &versions[idx]).into_iter()
+            .find(|r| {
+                r.action == DiffAction::Modify
+                    && r.name == name
+                    && kind.is_none_or(|k| history::symbol_type_letter(r.symbol_type) == k)
+                    && file.is_none_or(|f| r.data.file == f)
+            })
+            .ok_or_else(|| format!("{} wasn't modified in {}", name, version))?;
+        let previous_version = idx.checked_sub(1)
+            .map(|idx| versions[idx].clone())
+            .ok_or_else(|| format!("{} is the first version", version))?;
+
+        // The definitions the version started from, the one in the same file unless it moved
+        let symbols = history::symbols_at(&db_path, &versions, idx - 1, |r| r.name == name && r.symbol_type == record.symbol_type);
+        let definitions = symbols.symbols.get(&(name.to_string(), record.symbol_type)).map_or(&[][..], Vec::as_slice);
+        let previous = definitions.iter().find(|d| d.file == record.data.file)
+            .or_else(|| if definitions.len() == 1 { definitions.first() } else { None })
+            .map(|data| DiffRecord { action: DiffAction::Add, name: name.to_string(), symbol_type: record.symbol_type, data: data.clone() })
+            .ok_or_else(|| format!("No earlier definition of {} before {}", name, version))?;
+
+        Ok(DefinitionDiff {
+            name: name.to_string(),
+            symbol_type: record.symbol_type,
+            old: Definition::read(project, &previous_version, &previous)?,
+            new: Definition::read(project, version, &record)?,
+        })
+    }
+
+    /// `split` shows the two definitions side by side instead of a unified diff
+    pub fn to_html(&self, project: &str, split: bool) -> String {
+        let source_link = |definition: &Definition| format!(
+            "<a href=\"/{}/source/{}/{}#L{}\">{} {}:{}</a>",
+            project,
+            url::form_urlencoded::byte_serialize(definition.version.as_bytes()).collect::<String>(),
+            html::escape(&definition.file),
+            definition.line,
+            html::escape(&definition.version),
+            html::escape(&definition.file),
+            definition.line
+        );
+        let mut output = format!(
+            "<!DOCTYPE html>\n<meta charset=\"UTF-8\">\n<title>{0} {1} - SourceDigger</title>\n<link rel=\"stylesheet\" href=\"/static/results-inner.css\">\n<h1 class=source>{0} <small>{2} &rarr; {3}</small></h1>\n<p class=release-links><a href=\"?{4}\">unified</a> <a href=\"?{5}\">side by side</a></p>\n",
+            html::escape(&self.name),
+            html::escape(&self.new.version),
+            source_link(&self.old),
+            source_link(&self.new),
+            self.layout_params(false),
+            self.layout_params(true),
+        );
+
+        let diff = diff_lines(&self.old.lines, &self.new.lines);
+        let (mut old_line, mut new_line) = (self.old.line, self.new.line);
+        if !split {
+            output += "<pre class=definition-diff>";
+            for line in diff.iter() {
+                let (class, sign, text, old_number, new_number) = match line {
+                    DiffLine::Same(text) => ("", ' ', text, Some(old_line), Some(new_line)),
+                    DiffLine::Removed(text) => ("r", '-', text, Some(old_line), None),
+                    DiffLine::Added(text) => ("a", '+', text, None, Some(new_line)),
+                };
+                output += &format!(
+                    "<span class=\"line {}\"><span class=ln>{}</span><span class=ln>{}</span>{} {}</span>\n",
+                    class,
+                    old_number.map(|n| n.to_string()).unwrap_or_default(),
+                    new_number.map(|n| n.to_string()).unwrap_or_default(),
+                    sign,
+                    html::escape(text)
+                );
+                if old_number.is_some() {
+                    old_line += 1;
+                }
+                if new_number.is_some() {
+                    new_line += 1;
+                }
+            }
+            return output + "</pre>\n";
+        }
+
+        // Removed and added lines of the same change are lined up next to each other
+        output += "<table class=definition-diff>\n";
+        let mut i = 0;
+        while i < diff.len() {
+            if let DiffLine::Same(text) = diff[i] {
+                output += &format!(
+                    "<tr><td class=ln>{}</td><td><pre>{2}</pre></td><td class=ln>{1}</td><td><pre>{2}</pre></td></tr>\n",
+                    old_line, new_line, html::escape(text)
+                );
+                old_line += 1;
+                new_line += 1;
+                i += 1;
+                continue;
+            }
+            let removed = diff[i..].iter().take_while(|l| matches!(l, DiffLine::Removed(_))).count();
+            let added = diff[i + removed..].iter().take_while(|l| matches!(l, DiffLine::Added(_))).count();
+            for row in 0..removed.max(added) {
+                let old_cell = match diff.get(i + row) {
+                    Some(DiffLine::Removed(text)) if row < removed => split_cell("r", old_line + row, text),
+                    _ => EMPTY_CELLS.to_string(),
+                };
+                let new_cell = match diff.get(i + removed + row) {
+                    Some(DiffLine::Added(text)) if row < added => split_cell("a", new_line + row, text),
+                    _ => EMPTY_CELLS.to_string(),
+                };
+                output += &format!("<tr>{}{}</tr>\n", old_cell, new_cell);
+            }
+            old_line += removed;
+            new_line += added;
+            i += removed + added;
+        }
+        output + "</table>\n"
+    }
+
+    fn layout_params(&self, split: bool) -> String {
+        let mut params = url::form_urlencoded::Serializer::new(String::new());
+        params.append_pair("version", &self.new.version);
+        params.append_pair("t", history::symbol_type_letter(self.symbol_type));
+        params.append_pair("file", &self.new.file);
+        if split {
+            params.append_pair("layout", "split");
+        }
+        params.finish()
+    }
+}
+
+/// The link shown on modified rows of the search results
+pub fn link(project: &str, version: &str, record: &DiffRecord) -> String {
+    let mut params = url::form_urlencoded::Serializer::new(String::new());
+    params.append_pair("version", version);
+    params.append_pair("t", history::symbol_type_letter(record.symbol_type));
+    params.append_pair("file", &record.data.file);
+    format!(
+        "/{}/definition/{}?{}",
+        project,
+        url::form_urlencoded::byte_serialize(record.name.as_bytes()).collect::<String>(),
+        params.finish()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(line: &str) -> DiffRecord {
+        DiffRecord::parse(line).unwrap()
+    }
+
+    #[test]
+    fn definitions_are_found_by_their_signature() {
+        let lines = [
+            "static void *kmalloc(size_t size,",
+            "                     gfp_t flags)",
+            "{",
+            "}",
+            "void f(void)",
+            "{",
+            "\tp = kmalloc(size, GFP_KERNEL);",
+            "}",
+        ];
+        let kmalloc = record("m\tkmalloc\tFunction\tmm/slab.c\t7\tvoid* {name}(size_t size, gfp_t flags)");
+        assert_eq!(find_start(&lines, &kmalloc), Some(0));
+        assert_eq!(definition_end(&lines, 0, SymbolType::Function), 3);
+
+        let lines = ["#include <x.h>", "/* PAGE_SIZE */", "# define PAGE_SIZE 4096", "int x = PAGE_SIZE;"];
+        assert_eq!(find_start(&lines, &record("m\tPAGE_SIZE\tDefine\tpage.h\t4\t 4096")), Some(2));
+    }
+
+    #[test]
+    fn changed_lines_come_after_the_removed_ones() {
+        let old = ["a", "b", "c"].iter().map(|l| l.to_string()).collect::<Vec<_>>();
+        let new = ["a", "x", "c", "d"].iter().map(|l| l.to_string()).collect::<Vec<_>>();
+        let diff = diff_lines(&old, &new).into_iter().map(|line| match line {
+            DiffLine::Same(l) => format!(" {}", l),
+            DiffLine::Removed(l) => format!("-{}", l),
+            DiffLine::Added(l) => format!("+{}", l),
+        }).collect::<Vec<_>>();
+        assert_eq!(diff, vec![" a", "-b", "+x", " c", "+d"]);
+    }
+}
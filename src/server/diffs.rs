@@ -1,166 +1,1034 @@
-use std::io::{BufRead, BufReader, Read};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Lines};
 use std::path::Path;
-use std::sync::mpsc::Receiver;
-use std::sync::RwLock;
-use std::time::Instant;
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use std::collections::HashMap;
+use ctags::SymbolType;
 use regex::Regex;
+use serde::Serialize;
+use subprocess::ExitStatus;
 use crate::data::ProjectRepo;
-use std::fs::File;
+use crate::history::{self, DiffAction, DiffRecord};
+use crate::diff_store::DiffStore;
+use crate::{autocomplete, definition_diff, html, lifetime, name_index, projects, query, source, usages};
 
-lazy_static! {
-    static ref PROJECTS: RwLock<HashMap<String, ProjectRepo>> = RwLock::new(HashMap::new());
+/**
+ * The filters shared by the HTML results and the API
+ */
+pub struct DiffQuery {
+    /// The query as it was typed, for linking to further pages
+    pub text: String,
+    /// A glob or a regex, `None` matches every name
+    pub name: Option<String>,
+    /// Action letters, any of "arm"
+    pub actions: String,
+    /// Symbol type letters, any of "fvdsx"
+    pub types: String,
+    /// File path globs from `path:` and from the `include` parameter.
+    /// A record has to match one glob of each group, so the parameter can narrow down the query.
+    pub include_paths: Vec<Vec<Regex>>,
+    /// File path globs from `-path:` and the `exclude` parameter
+    pub exclude_paths: Vec<Regex>,
+    /// The `include` and `exclude` parameters, for linking to further pages
+    pub include: Option<String>,
+    pub exclude: Option<String>,
+    /// Text the signature has to contain, case insensitively
+    pub signature: Option<String>,
+    /// When searching signatures rather than names, the pattern the signature has to match.
+    /// Modifications only match if the previous signature didn't, e.g. functions that started taking a `struct folio *`.
+    pub signature_pattern: Option<Regex>,
+    /// The `mode` parameter was "signature", for linking to further pages
+    pub signature_mode: bool,
+    pub since: Option<String>,
+    pub until: Option<String>,
+    pub count: u64,
+    pub cursor: Option<Cursor>,
 }
 
-pub fn get_diffs(project: String, query: String, actions: String, types: String, _count: u64) -> Receiver<String> {
-    assert!(!project.contains("/"));
-    let (send, recv) = std::sync::mpsc::channel();
-
-    std::thread::spawn(move || {
-        let start = Instant::now();
+/**
+ * The search parameters as they appear in URLs
+ */
+pub struct SearchParams {
+    pub q: String,
+    pub a: Option<String>,
+    pub t: Option<String>,
+    /// The first and last version to search, `since:` and `until:` in the query take precedence
+    pub from: Option<String>,
+    pub to: Option<String>,
+    /// Comma separated file path globs, added to `path:` and `-path:` in the query
+    pub include: Option<String>,
+    pub exclude: Option<String>,
+    /// "name" (the default) or "signature", what the pattern of the query is matched against
+    pub mode: Option<String>,
+    pub count: Option<u64>,
+    pub cursor: Option<String>,
+}
 
-        send.send("<link rel=\"stylesheet\" href=/static/results-inner.css>".to_string()).unwrap();
+/// The globs of the query and of a comma separated parameter
+pub fn path_globs(from_query: &[String], param: &Option<String>) -> Result<Vec<Regex>, String> {
+    from_query.iter()
+        .map(String::as_str)
+        .chain(param.iter().flat_map(|p| p.split(',')).filter(|glob| !glob.is_empty()))
+        .map(query::path_glob)
+        .collect()
+}
 
-        let actions = if actions == "arm" {
-            ".".to_string()
+impl DiffQuery {
+    /// Parses the search box query. Its `kind:` and `action:` fields take precedence over `t` and `a`.
+    pub fn parse(project: &str, params: SearchParams) -> Result<Self, String> {
+        let fields = query::parse_fields(&params.q)?;
+        let signature_mode = match params.mode.as_deref() {
+            None | Some("name") => false,
+            Some("signature") => true,
+            Some(mode) => return Err(format!("Unknown mode `{}`, expected name or signature", mode)),
+        };
+        let (name, signature_pattern) = if signature_mode {
+            (fields.name, fields.pattern.as_ref().map(|p| query::signature_regex(p)).transpose()?)
+        } else if fields.pattern.is_some() && fields.name.is_some() {
+            return Err("Give the name either as `name:` or as a plain pattern, not both".to_string());
         } else {
-            format!("[{}]", actions)
+            let name = fields.pattern.or(fields.name);
+            if let Some(name) = &name {
+                query::name_regex(name).map_err(|e| format!("Invalid name pattern `{}`: {}", name, e))?;
+            }
+            (name, None)
         };
+        let diff_query = DiffQuery {
+            name,
+            signature_pattern,
+            signature_mode,
+            actions: fields.actions.or(params.a).unwrap_or("arm".into()),
+            types: fields.kinds.or(params.t).unwrap_or("fvd".into()),
+            include_paths: vec![path_globs(&fields.paths, &None)?, path_globs(&[], &params.include)?]
+                .into_iter()
+                .filter(|globs| !globs.is_empty())
+                .collect(),
+            exclude_paths: path_globs(&fields.excluded_paths, &params.exclude)?,
+            include: params.include,
+            exclude: params.exclude,
+            signature: fields.signature.map(|s| s.to_lowercase()),
+            since: fields.since.or(params.from),
+            until: fields.until.or(params.to),
+            text: params.q,
+            count: match params.count {
+                Some(0) => return Err("`count` has to be at least 1".to_string()),
+                count => count.unwrap_or(1000),
+            },
+            cursor: params.cursor.as_ref()
+                .map(|c| Cursor::parse(c).ok_or_else(|| format!("Invalid cursor `{}`", c)))
+                .transpose()?,
+        };
+        let versions = history::read_versions(&Path::new("sourcedigger-db").join(project));
+        let (since, until) = diff_query.version_window(&versions)?;
+        if let Some(cursor) = &diff_query.cursor {
+            if versions.get(since..=until).and_then(|window| history::version_index(window, &cursor.version)).is_none() {
+                return Err(format!("The cursor's version `{}` isn't among the searched versions", cursor.version));
+            }
+        }
+        Ok(diff_query)
+    }
 
-        let types_query = types.chars().map(|c| match c {
-            'f' => "Function",
-            'd' => "Define",
-            'v' => "Variable",
-            _ => "",
-        }).collect::<Vec<_>>().join("|");
+    /// Every record of the symbols with exactly these names (give or take their case), up to `until`
+    pub fn exact_names(names: &[&str], until: Option<String>) -> Self {
+        let pattern = names.iter().map(|name| regex::escape(name)).collect::<Vec<_>>().join("|");
+        DiffQuery {
+            text: pattern.clone(),
+            name: Some(format!("({})", pattern)),
+            actions: "arm".to_string(),
+            types: String::new(),
+            include_paths: vec![],
+            exclude_paths: vec![],
+            include: None,
+            exclude: None,
+            signature: None,
+            signature_pattern: None,
+            signature_mode: false,
+            since: None,
+            until,
+            count: u64::MAX,
+            cursor: None,
+        }
+    }
 
-        let types_query = if types_query.len() == 0 {
-            ".*".to_string()
-        } else {
-            format!("({})", types_query)
+    pub fn name_pattern(&self) -> &str {
+        self.name.as_deref().unwrap_or(".*")
+    }
+
+    /// The indices of the first and last version to search, in the project's version order
+    fn version_window(&self, versions: &[String]) -> Result<(usize, usize), String> {
+        let resolve = |version: &Option<String>, default: usize| match version {
+            Some(version) => history::version_index(versions, version)
+                .ok_or_else(|| format!("Unknown version `{}`", version)),
+            None => Ok(default),
         };
+        let since = resolve(&self.since, 0)?;
+        let until = resolve(&self.until, versions.len().saturating_sub(1))?;
+        if since > until {
+            return Err(format!("{} is newer than {}", versions[since], versions[until]));
+        }
+        Ok((since, until))
+    }
+}
 
-        let query_expander = Regex::new("(^|[^.\\]])([*+])").unwrap();
+/**
+ * The matching records of one version
+ */
+pub struct VersionHits {
+    pub version: String,
+    pub records: Vec<DiffRecord>,
+}
 
-        let query = query_expander.replace_all(&query, "$1.$2");
-        let rg_query = if query.chars().all(|x| x.is_alphanumeric() || x == '_') {
-            format!(r"^{}\t{}\t{}", &actions, &query, &types_query)
-        } else {
-            format!(r"^{}\t({})\t{}", &actions, &query, &types_query)
-        };
+/// A record as returned by the API
+#[derive(Serialize)]
+struct DiffHit<'a> {
+    version: &'a str,
+    action: DiffAction,
+    name: &'a str,
+    kind: String,
+    file: &'a str,
+    line: u64,
+    signature: String,
+    source_link: String,
+}
+
+impl<'a> DiffHit<'a> {
+    fn new(project_data: &ProjectRepo, version: &'a str, record: &'a DiffRecord) -> Self {
+        DiffHit {
+            version,
+            action: record.action,
+            name: &record.name,
+            kind: format!("{:?}", record.symbol_type),
+            file: &record.data.file,
+            line: record.data.line,
+            signature: history::format_signature(&record.name, record.symbol_type, &record.data.extra),
+            source_link: project_data.source_link(version, &record.data.file, record.data.line),
+        }
+    }
+}
+
+/// Results are ordered by version, so diffs are scanned a few versions at a time, newest first,
+/// until enough results were found
+const VERSIONS_PER_SCAN: usize = 32;
+const SEARCH_TIMEOUT: Duration = Duration::from_secs(10);
 
-        // let replace_dots = Regex::new(r#"."#).unwrap();
-        let rg_query = rg_query.replace(".", "[^\\t]");
+/// Where to continue from, `<version>:<offset>` in URLs
+pub struct Cursor {
+    pub version: String,
+    /// How many of the version's results were already shown
+    pub offset: usize,
+}
 
-        let rg_args = [
-            "10s", // FIXME: hardcoded query timeout
-            "rg", "--heading", "--smart-case", &rg_query
+impl Cursor {
+    pub fn parse(cursor: &str) -> Option<Self> {
+        // Tag names can't contain ':'
+        let mut parts = cursor.rsplitn(2, ':');
+        let offset = parts.next()?.parse().ok()?;
+        let version = parts.next()?.to_string();
+        Some(Cursor { version, offset })
+    }
+}
+
+impl std::fmt::Display for Cursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}", self.version, self.offset)
+    }
+}
+
+#[derive(Serialize, Debug, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Truncation {
+    Limit,
+    Timeout,
+}
+
+/**
+ * Whether there are more results than were returned
+ */
+pub struct SearchStatus {
+    pub truncated: Option<Truncation>,
+    pub next_cursor: Option<Cursor>,
+}
+
+/**
+ * ripgrep over the TSV diffs of a few versions, read as it finds the records.
+ * With a single thread it searches the versions in the order they're given,
+ * so a version's records are complete once the next one's start.
+ */
+struct VersionScan {
+    process: subprocess::Popen,
+    lines: Lines<BufReader<File>>,
+    /// The version of the next block of records, once its heading was read
+    next_version: Option<String>,
+    version_count: usize,
+    start: Instant,
+}
+
+impl VersionScan {
+    fn new(db_path: &Path, rg_query: &str, versions: &[String], time_left: Duration) -> Self {
+        let timeout = format!("{:.1}s", time_left.as_secs_f64());
+        // ripgrep leaves the heading out when it searches a single file, unless it has --with-filename
+        let mut rg_args = vec![
+            timeout.as_str(),
+            "rg", "--heading", "--with-filename", "--smart-case", "-j1", rg_query, "--"
         ];
+        rg_args.extend(versions.iter().map(String::as_str));
 
-        println!("rg_args: {:?}", &rg_args);
-        let mut rg_proc = subprocess::Exec::cmd("timeout")
+        let mut process = subprocess::Exec::cmd("timeout")
             .args(&rg_args)
             .stdout(subprocess::Redirection::Pipe)
-            .cwd(Path::new("sourcedigger-db").join(&project).join("diffs"))
+            .cwd(db_path.join("diffs"))
             .popen()
             .unwrap();
+        let lines = BufReader::new(process.stdout.take().unwrap()).lines();
+        VersionScan { process, lines, next_version: None, version_count: versions.len(), start: Instant::now() }
+    }
 
-        let mut output = HashMap::new();
-        let rg_out = rg_proc.stdout.take().unwrap();
-        let rg_lines: Vec<String> = BufReader::new(&rg_out).lines().map(|l| l.unwrap()).collect();
+    /// The matching records of the next version searched, `None` if the scan timed out before it was done with it
+    fn read(&mut self, diff_query: &DiffQuery, version: &str) -> Option<Vec<DiffRecord>> {
+        if self.next_version.is_none() {
+            match self.lines.next() {
+                Some(Ok(heading)) => self.next_version = Some(heading),
+                _ => return if self.timed_out() { None } else { Some(vec![]) },
+            }
+        }
+        // Versions without matches don't have a block
+        if self.next_version.as_deref() != Some(version) {
+            return Some(vec![]);
+        }
+        self.next_version = None;
+
+        let mut records = vec![];
+        loop {
+            match self.lines.next() {
+                // Blocks are separated by an empty line
+                Some(Ok(line)) if line.is_empty() => break,
+                Some(Ok(line)) => records.extend(DiffRecord::parse(&line).filter(|r| record_matches(diff_query, r))),
+                // The last block is only complete if ripgrep wasn't stopped in the middle of it
+                _ if self.timed_out() => return None,
+                _ => break,
+            }
+        }
+        Some(records)
+    }
 
-        println!("- rg took {:?}", start.elapsed());
+    fn timed_out(&mut self) -> bool {
+        // `timeout` exits with 124 when the command timed out
+        self.process.wait().unwrap() == ExitStatus::Exited(124)
+    }
+}
 
-        let project_data: Option<ProjectRepo> = PROJECTS.read().unwrap().get(&project).map(|r| r.clone());
-        let project_data = if let Some(data) = project_data {
-            data
-        } else {
-            let mut projects = PROJECTS.write().unwrap();
+impl Drop for VersionScan {
+    fn drop(&mut self) {
+        // Dropping the process waits for it, so stop the rest of the scan when the results are complete.
+        // `timeout` passes the signal on to ripgrep.
+        if self.process.poll().is_none() {
+            let _ = self.process.terminate();
+        }
+        println!("- rg over {} versions took {:?}", self.version_count, self.start.elapsed());
+    }
+}
 
-            let mut data_string = String::new();
-            File::open(Path::new("sourcedigger-db").join(&project).join("config.toml"))
-                .expect("Failed to open config")
-                .read_to_string(&mut data_string)
-                .expect("Failed to read config");
+fn record_matches(diff_query: &DiffQuery, record: &DiffRecord) -> bool {
+    diff_query.actions.contains(record.action.letter())
+        && (diff_query.types.is_empty() || diff_query.types.contains(history::symbol_type_letter(record.symbol_type)))
+        && diff_query.include_paths.iter().all(|globs| globs.iter().any(|path| path.is_match(&record.data.file)))
+        && !diff_query.exclude_paths.iter().any(|path| path.is_match(&record.data.file))
+        && diff_query.signature.as_ref().is_none_or(|signature| {
+            history::format_signature(&record.name, record.symbol_type, &record.data.extra).to_lowercase().contains(signature.as_str())
+        })
+        && diff_query.signature_pattern.as_ref().is_none_or(|pattern| {
+            pattern.is_match(&history::format_signature(&record.name, record.symbol_type, &record.data.extra))
+        })
+}
 
-            let project_data: ProjectRepo = toml::from_str(&data_string).expect("Invalid config");
-            projects.insert(project.clone(), project_data.clone());
-            project_data
-        };
+/// Every record of the symbols with exactly this name, oldest version first.
+/// `None` if the search timed out before it got to the oldest version.
+pub fn name_history(project: &str, name: &str) -> Option<Vec<VersionHits>> {
+    name_histories(project, &[name], None, Instant::now() + SEARCH_TIMEOUT)
+        .and_then(|mut histories| histories.remove(name))
+}
 
-        for subslice in rg_lines.split(|s| s == "") {
-            let (tag_name, lines) = match subslice.split_first() {
+/// The `name_history` of each of these names up to `until`, `None` if looking them up took past the deadline
+fn name_histories(project: &str, names: &[&str], until: Option<&str>, deadline: Instant) -> Option<HashMap<String, Vec<VersionHits>>> {
+    let mut histories: HashMap<String, Vec<VersionHits>> = names.iter()
+        .map(|name| (name.to_string(), vec![]))
+        .collect();
+    let status = search_until(project, &DiffQuery::exact_names(names, until.map(str::to_string)), deadline, |hits| {
+        for record in hits.records {
+            // Names are matched case insensitively
+            let history = match histories.get_mut(&record.name) {
+                Some(history) => history,
                 None => continue,
-                Some(s) => s,
             };
-            let mut tag_output = format!("<h2 class=h>{}</h2>\n", &tag_name);
-
-            for line in lines {
-                let mut components = line.split('\t');
-                let (action, name, sym_type, file, line, extra) = (
-                    components.next().unwrap(),
-                    components.next().unwrap(),
-                    components.next().unwrap(),
-                    components.next().unwrap(),
-                    components.next().unwrap(),
-                    components.next().unwrap_or(""),
-                );
-
-                let mut extra_split = extra.split("{name}");
-
-                let (prefix, suffix) = match sym_type.as_ref() {
-                    "Function" => (
-                        extra_split.next().unwrap_or(""),
-                        extra_split.next().unwrap_or("")
-                    ),
-                    "Define" => ("#define ", ""),
-                    "Variable" => ("unknown_t ", ""),
-                    _ => ("", "")
+            match history.last_mut() {
+                Some(last) if last.version == hits.version => last.records.push(record),
+                _ => history.push(VersionHits { version: hits.version.clone(), records: vec![record] }),
+            }
+        }
+    });
+    if status.truncated.is_some() {
+        return None;
+    }
+    for history in histories.values_mut() {
+        history.reverse();
+    }
+    Some(histories)
+}
+
+/// The definition a modification in `version` replaced, from the symbol's `name_history`
+pub fn previous_definition<'a>(history: &'a [VersionHits], version: &str, record: &DiffRecord) -> Option<&'a DiffRecord> {
+    let mut same_file = None;
+    let mut latest = None;
+    for hits in history.iter().take_while(|hits| hits.version != version) {
+        for r in hits.records.iter().filter(|r| r.symbol_type == record.symbol_type) {
+            if r.action == DiffAction::Remove {
+                if r.data.file == record.data.file {
+                    same_file = None;
+                }
+                continue;
+            }
+            if r.data.file == record.data.file {
+                same_file = Some(r);
+            }
+            latest = Some(r);
+        }
+    }
+    // A single definition may have moved from another file
+    same_file.or(latest)
+}
+
+/**
+ * Where the records of a search are read from, fastest first
+ */
+enum Source {
+    /// The records listed by the name index, read from the binary diffs
+    IndexedStore {
+        store: Arc<DiffStore>,
+        stored_versions: HashMap<String, (usize, usize)>,
+        name_regex: Regex,
+        /// The indices of the records among their version's, by version
+        records: HashMap<String, Vec<usize>>,
+    },
+    /// The records listed by the name index, read from the TSV diffs
+    Indexed {
+        name_regex: Regex,
+        /// Byte offsets of the records, by version
+        offsets: HashMap<String, Vec<u64>>,
+    },
+    /// Every record of the binary diffs, for databases without a name index.
+    /// Names are interned, so each one is only matched once.
+    Store {
+        store: Arc<DiffStore>,
+        stored_versions: HashMap<String, (usize, usize)>,
+        name_regex: Regex,
+        name_matches: HashMap<u32, bool>,
+    },
+    /// ripgrep over the TSV diffs, a few versions at a time
+    Scan { rg_query: String },
+}
+
+impl Source {
+    fn new(project: &str, diff_query: &DiffQuery, versions: &[String], rg_query: String) -> Result<Self, regex::Error> {
+        let db_path = Path::new("sourcedigger-db").join(project);
+        let store = projects::diff_store(project);
+        let name_index = projects::name_index(project);
+        if store.is_none() && name_index.is_none() {
+            return Ok(Source::Scan { rg_query });
+        }
+        let name_regex = query::name_regex(diff_query.name_pattern())?;
+
+        let index_postings = name_index.map(|index| {
+            let lookup_start = Instant::now();
+            let postings = index.lookup(&query::literal_prefix(diff_query.name_pattern()), &name_regex);
+            println!("- name index lookup took {:?}", lookup_start.elapsed());
+            postings
+        });
+        match (store, index_postings) {
+            // Indexes written before the binary diffs don't know where their records are in them
+            (Some(store), Some(postings)) if postings.iter().all(|(_, _, record)| record.is_some()) => {
+                let mut records: HashMap<String, Vec<usize>> = HashMap::new();
+                for (version, _, record) in postings {
+                    if let Some(version) = versions.get(version as usize) {
+                        records.entry(version.clone()).or_default().push(record.unwrap() as usize);
+                    }
+                }
+                Ok(Source::IndexedStore { stored_versions: store.versions(), store, name_regex, records })
+            }
+            (store, Some(postings)) if store.is_none() || db_path.join("diffs").exists() => {
+                let mut offsets: HashMap<String, Vec<u64>> = HashMap::new();
+                for (version, offset, _) in postings {
+                    if let Some(version) = versions.get(version as usize) {
+                        offsets.entry(version.clone()).or_default().push(offset);
+                    }
+                }
+                Ok(Source::Indexed { name_regex, offsets })
+            }
+            (Some(store), _) => Ok(Source::Store { stored_versions: store.versions(), store, name_regex, name_matches: HashMap::new() }),
+            (None, _) => Ok(Source::Scan { rg_query }),
+        }
+    }
+
+    /// The matching records of a version, for every source but `Scan`
+    fn read_version(&mut self, db_path: &Path, diff_query: &DiffQuery, version: &str) -> Vec<DiffRecord> {
+        match self {
+            Source::IndexedStore { store, stored_versions, name_regex, records } => {
+                let (first_record, record_count) = match (stored_versions.get(version), records.get(version)) {
+                    (Some(range), Some(_)) => *range,
+                    _ => return vec![],
+                };
+                records[version].iter()
+                    .filter(|i| **i < record_count)
+                    .filter_map(|i| store.record(first_record + i))
+                    .map(|record| store.to_diff_record(&record))
+                    // A store rewritten after the index would have other records there
+                    .filter(|r| name_regex.is_match(&r.name))
+                    .filter(|r| record_matches(diff_query, r))
+                    .collect()
+            }
+            Source::Indexed { name_regex, offsets } => match offsets.get(version) {
+                Some(offsets) => name_index::read_lines_at(&db_path.join("diffs").join(version), offsets).iter()
+                    .filter_map(|l| DiffRecord::parse(l))
+                    // A diff rewritten after the index would have other records at these offsets
+                    .filter(|r| name_regex.is_match(&r.name))
+                    .filter(|r| record_matches(diff_query, r))
+                    .collect(),
+                None => vec![],
+            },
+            Source::Store { store, stored_versions, name_regex, name_matches } => {
+                let (first_record, record_count) = match stored_versions.get(version) {
+                    Some(range) => *range,
+                    None => return vec![],
                 };
+                let mut records = vec![];
+                for record in (first_record..first_record + record_count).filter_map(|i| store.record(i)) {
+                    let name_matches = *name_matches.entry(record.name)
+                        .or_insert_with(|| name_regex.is_match(store.string(record.name)));
+                    if name_matches {
+                        let record = store.to_diff_record(&record);
+                        if record_matches(diff_query, &record) {
+                            records.push(record);
+                        }
+                    }
+                }
+                records
+            }
+            Source::Scan { .. } => unreachable!("ripgrep scans several versions at once"),
+        }
+    }
+}
+
+/// Runs the query over the project's diffs, passing the results to `on_hits` newest version first.
+/// Uses the name index and the binary diffs when the project has them, and ripgrep otherwise.
+pub fn search<F: FnMut(VersionHits)>(project: &str, diff_query: &DiffQuery, on_hits: F) -> SearchStatus {
+    search_until(project, diff_query, Instant::now() + SEARCH_TIMEOUT, on_hits)
+}
 
-                tag_output += &format!(
-                    "<div class={0}><a href=# class={6}>{6}</a><a href=# class={0}>{7}</a><a href=\"diffs?q={1}\"><span>{2}</span><span>{1}</span>{3}</a><hr><a href=\"{8}\">{4}:{5}</a></div>\n",
-                    match action.as_ref() {
-                        "a" => "a",
-                        "r" => "r",
-                        "m" => "m",
-                        _ => "u"
-                    },
-                    &name,
-                    prefix,
-                    suffix,
-                    &file,
-                    &line,
-                    match sym_type.as_ref() {
-                        "Function" => "f",
-                        "Define" => "d",
-                        "Variable" => "v",
-                        _ => sym_type.as_ref()
-                    },
-                    match action.as_ref() {
-                        "a" => "+",
-                        "r" => "-",
-                        "m" => "~",
-                        _ => "u"
-                    },
-                    project_data.source_viewer
-                        .replace("{tag}", &tag_name)
-                        .replace("{path}", &file)
-                        .replace("{line}", &format!("{}", line))
-                )
-            }
-
-            output.insert(tag_name.clone(), tag_output);
-        }
-
-        let mut sorted_tags: Vec<String> = output.keys().cloned().collect();
-        alphanumeric_sort::sort_path_slice_rev(&mut sorted_tags);
-
-        for tag in sorted_tags {
-            send.send(output.remove(&tag).unwrap()).unwrap();
+/// `search`, giving up at `deadline` rather than after the search timeout
+fn search_until<F: FnMut(VersionHits)>(project: &str, diff_query: &DiffQuery, deadline: Instant, mut on_hits: F) -> SearchStatus {
+    assert!(!project.contains("/"));
+    let db_path = Path::new("sourcedigger-db").join(project);
+
+    let actions = if diff_query.actions == "arm" {
+        ".".to_string()
+    } else {
+        format!("[{}]", diff_query.actions)
+    };
+
+    let types_query = diff_query.types.chars().map(|c| match c {
+        'f' => "Function",
+        'd' => "Define",
+        'v' => "Variable",
+        's' => "Struct",
+        'x' => "Export",
+        _ => "",
+    }).collect::<Vec<_>>().join("|");
+
+    let types_query = if types_query.is_empty() {
+        ".*".to_string()
+    } else {
+        format!("({})", types_query)
+    };
+
+    let query = query::expand_glob(diff_query.name_pattern());
+    let rg_query = if query.chars().all(|x| x.is_alphanumeric() || x == '_') {
+        format!(r"^{}\t{}\t{}", &actions, &query, &types_query)
+    } else {
+        format!(r"^{}\t({})\t{}", &actions, &query, &types_query)
+    };
+
+    // let replace_dots = Regex::new(r#"."#).unwrap();
+    let rg_query = rg_query.replace(".", "[^\\t]");
+    println!("rg_query: {:?}", &rg_query);
+
+    let mut versions = history::read_versions(&db_path);
+    let mut source = match Source::new(project, diff_query, &versions, rg_query) {
+        Ok(source) => source,
+        Err(_) => return SearchStatus { truncated: None, next_cursor: None },
+    };
+    // Versions outside the window aren't read at all
+    let (since, until) = match diff_query.version_window(&versions) {
+        Ok(window) if !versions.is_empty() => window,
+        _ => return SearchStatus { truncated: None, next_cursor: None },
+    };
+    let mut versions = versions.drain(since..=until).collect::<Vec<_>>();
+    versions.reverse();
+    let (first_idx, mut skip) = match &diff_query.cursor {
+        Some(cursor) => match history::version_index(&versions, &cursor.version) {
+            Some(idx) => (idx, cursor.offset),
+            None => (versions.len(), 0),
+        },
+        None => (0, 0),
+    };
+
+    let mut remaining = diff_query.count as usize;
+    let mut histories: HashMap<String, Vec<VersionHits>> = HashMap::new();
+    // The ripgrep scan of the versions up to `scanned_until`
+    let mut scan = None;
+    let mut scanned_until = first_idx;
+    for (i, version) in versions.iter().enumerate().skip(first_idx) {
+        let time_left = deadline.saturating_duration_since(Instant::now());
+        // The results of a partial scan might be missing versions, so start over from here
+        let timed_out = || SearchStatus {
+            truncated: Some(Truncation::Timeout),
+            next_cursor: Some(Cursor { version: version.clone(), offset: skip }),
+        };
+        let mut records = match &mut source {
+            Source::Scan { rg_query } => {
+                if i == scanned_until {
+                    if time_left.as_millis() == 0 {
+                        return timed_out();
+                    }
+                    scanned_until = (i + VERSIONS_PER_SCAN).min(versions.len());
+                    scan = Some(VersionScan::new(&db_path, rg_query, &versions[i..scanned_until], time_left));
+                }
+                match scan.as_mut().unwrap().read(diff_query, version) {
+                    Some(records) => records,
+                    None => return timed_out(),
+                }
+            }
+            source => {
+                if time_left.as_millis() == 0 {
+                    return timed_out();
+                }
+                source.read_version(&db_path, diff_query, version)
+            }
+        };
+        if records.is_empty() {
+            skip = 0;
+            continue;
+        }
+        if let Some(pattern) = &diff_query.signature_pattern {
+            // The previous definitions of the version's modifications are looked up together
+            let mut names = records.iter()
+                .filter(|record| record.action == DiffAction::Modify && !histories.contains_key(&record.name))
+                .map(|record| record.name.as_str())
+                .collect::<Vec<_>>();
+            names.sort();
+            names.dedup();
+            if !names.is_empty() {
+                match name_histories(project, &names, Some(version), deadline) {
+                    Some(found) => histories.extend(found),
+                    None => return timed_out(),
+                }
+            }
+            records.retain(|record| {
+                if record.action != DiffAction::Modify {
+                    return true;
+                }
+                !previous_definition(&histories[&record.name], version, record).is_some_and(|previous| {
+                    pattern.is_match(&history::format_signature(&previous.name, previous.symbol_type, &previous.data.extra))
+                })
+            });
+        }
+        let offset = skip;
+        skip = 0;
+        records.drain(..offset.min(records.len()));
+        if records.is_empty() {
+            continue;
+        }
+        // The page is full, and there's at least one more result
+        if remaining == 0 {
+            return SearchStatus {
+                truncated: Some(Truncation::Limit),
+                next_cursor: Some(Cursor { version: version.clone(), offset }),
+            };
+        }
+
+        if records.len() > remaining {
+            records.truncate(remaining);
+            on_hits(VersionHits { version: version.clone(), records });
+            return SearchStatus {
+                truncated: Some(Truncation::Limit),
+                next_cursor: Some(Cursor { version: version.clone(), offset: offset + remaining }),
+            };
+        }
+        remaining -= records.len();
+        on_hits(VersionHits { version: version.clone(), records });
+    }
+
+    SearchStatus { truncated: None, next_cursor: None }
+}
+
+fn send_lifetime_summary(send: &Sender<String>, project: &str, query: &str) {
+    // "Since when does it exist, and is it still there?"
+    const LIFETIME_SUMMARY_COUNT: u64 = 5;
+    let lifetimes = lifetime::find_lifetimes(project, query, LIFETIME_SUMMARY_COUNT);
+    if !lifetimes.is_empty() {
+        let mut summary = "<ul class=lifetimes>".to_string();
+        for lifetime in lifetimes.iter() {
+            summary += &format!(
+                "<li><a href=\"/{}/symbol/{}\" target=_top><b>{}</b></a>: {}</li>",
+                project,
+                url::form_urlencoded::byte_serialize(lifetime.name.as_bytes()).collect::<String>(),
+                html::escape(&lifetime.name),
+                html::escape(&lifetime.summary())
+            );
+        }
+        if lifetimes.len() as u64 == LIFETIME_SUMMARY_COUNT {
+            summary += "<li>&hellip;</li>";
+        }
+        summary += "</ul>\n";
+        send.send(summary).unwrap();
+    }
+}
+
+fn send_suggestions(send: &Sender<String>, project: &str, query: &str) {
+    const SUGGESTION_COUNT: u64 = 5;
+    let suggestions = autocomplete::did_you_mean(project, query, SUGGESTION_COUNT);
+    if !suggestions.is_empty() {
+        let links = suggestions.iter()
+            .map(|name| format!(
+                "<a href=\"/{}?q={}\" target=_top>{}</a>",
+                project,
+                url::form_urlencoded::byte_serialize(name.as_bytes()).collect::<String>(),
+                html::escape(name)
+            ))
+            .collect::<Vec<_>>()
+            .join(", ");
+        send.send(format!("<p class=suggestions>No results, did you mean {}?</p>\n", links)).unwrap();
+    }
+}
+
+/// One result row, also used by the release notes
+pub fn record_to_html(project: &str, project_data: &ProjectRepo, version: &str, record: &DiffRecord) -> String {
+    let mut extra_split = record.data.extra.split("{name}");
+
+    let (prefix, suffix) = match record.symbol_type {
+        SymbolType::Function => (
+            extra_split.next().unwrap_or(""),
+            extra_split.next().unwrap_or("")
+        ),
+        SymbolType::Define => ("#define ", record.data.extra.as_str()),
+        SymbolType::Variable => ("unknown_t ", ""),
+        SymbolType::Struct => ("struct ", ""),
+        SymbolType::Export => ("EXPORT_SYMBOL(", ")"),
+        SymbolType::Unknown => ("", "")
+    };
+
+    // The definition diff needs the sources, from the project's repository or the extracted objects.
+    // Added and removed symbols link to their references instead, if they were extracted.
+    let diff_link = if record.action == DiffAction::Modify && source::has_sources(project) {
+        format!("<a class=diff href=\"{}\" target=_top>diff</a>", definition_diff::link(project, version, record))
+    } else if record.action != DiffAction::Modify && project_data.references {
+        let (label, removed) = if record.action == DiffAction::Remove { ("callers", true) } else { ("uses", false) };
+        format!("<a class=diff href=\"{}\" target=_top>{}</a>", usages::link(project, version, &record.name, removed), label)
+    } else {
+        String::new()
+    };
+
+    format!(
+        "<div class={0}><a href=# class={6}>{6}</a><a href=# class={0}>{7}</a><a href=\"/{10}/diffs?q={1}\"><span>{2}</span><span>{1}</span>{3}</a><hr>{9}<a href=\"{8}\">{4}:{5}</a></div>\n",
+        match record.action {
+            DiffAction::Add => "a",
+            DiffAction::Remove => "r",
+            DiffAction::Modify => "m",
+        },
+        html::escape(&record.name),
+        html::escape(prefix),
+        html::escape(suffix),
+        html::escape(&record.data.file),
+        record.data.line,
+        history::symbol_type_letter(record.symbol_type),
+        match record.action {
+            DiffAction::Add => "+",
+            DiffAction::Remove => "-",
+            DiffAction::Modify => "~",
+        },
+        project_data.source_link(version, &record.data.file, record.data.line),
+        diff_link,
+        project
+    )
+}
+
+/// Shown in place of the results when the query can't be parsed
+pub fn error_html(message: &str) -> String {
+    format!(
+        "<link rel=\"stylesheet\" href=/static/results-inner.css>\n<pre class=error>{}</pre>\n",
+        html::escape(message)
+    )
+}
+
+pub fn error_json(message: &str) -> String {
+    serde_json::json!({ "error": message }).to_string()
+}
+
+/**
+ * How many results are in each directory, for narrowing down the search
+ */
+#[derive(Default)]
+struct DirectoryFacets {
+    /// Keyed by the directory of each file
+    counts: HashMap<String, usize>,
+}
+
+#[derive(Serialize)]
+struct DirectoryCount {
+    directory: String,
+    count: usize,
+    /// The path glob that narrows the search down to the directory's results
+    include: String,
+}
+
+impl DirectoryFacets {
+    fn add(&mut self, file: &str) {
+        let directory = match file.rfind('/') {
+            Some(idx) => &file[..idx + 1],
+            None => "",
+        };
+        *self.counts.entry(directory.to_string()).or_insert(0) += 1;
+    }
+
+    /// The subdirectories of the directory all results have in common, the busiest first
+    fn top(&self, count: usize) -> Vec<DirectoryCount> {
+        let mut directories = self.counts.keys();
+        let mut common = match directories.next() {
+            Some(first) => first.as_str(),
+            None => return vec![],
+        };
+        for directory in directories {
+            while !directory.starts_with(common) {
+                common = match common[..common.len() - 1].rfind('/') {
+                    Some(idx) => &common[..idx + 1],
+                    None => "",
+                };
+            }
+        }
+
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        for (directory, n) in self.counts.iter() {
+            let subdirectory = match directory[common.len()..].find('/') {
+                Some(idx) => &directory[..common.len() + idx + 1],
+                None => common,
+            };
+            *counts.entry(subdirectory).or_insert(0) += n;
+        }
+        let mut counts = counts.into_iter()
+            .map(|(directory, count)| DirectoryCount {
+                directory: directory.to_string(),
+                count,
+                // The files of the common directory itself, without its subdirectories
+                include: if directory == common { format!("{}*", directory) } else { directory.to_string() },
+            })
+            .collect::<Vec<_>>();
+        counts.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.directory.cmp(&b.directory)));
+        counts.truncate(count);
+        counts
+    }
+}
+
+const FACET_COUNT: usize = 10;
+
+/// The parameters of the search, except for paging
+fn search_url_params(diff_query: &DiffQuery, include: Option<&str>) -> url::form_urlencoded::Serializer<'static, String> {
+    let mut params = url::form_urlencoded::Serializer::new(String::new());
+    params.append_pair("q", &diff_query.text);
+    params.append_pair("a", &diff_query.actions);
+    params.append_pair("t", &diff_query.types);
+    if let Some(since) = &diff_query.since {
+        params.append_pair("from", since);
+    }
+    if let Some(until) = &diff_query.until {
+        params.append_pair("to", until);
+    }
+    if let Some(include) = include.or(diff_query.include.as_deref()) {
+        params.append_pair("include", include);
+    }
+    if let Some(exclude) = &diff_query.exclude {
+        params.append_pair("exclude", exclude);
+    }
+    if diff_query.signature_mode {
+        params.append_pair("mode", "signature");
+    }
+    params
+}
+
+fn facets_html(project: &str, diff_query: &DiffQuery, facets: &DirectoryFacets) -> String {
+    let top = facets.top(FACET_COUNT);
+    // A single directory doesn't narrow anything down
+    if top.len() < 2 {
+        return String::new();
+    }
+    // Counted over the results shown, further pages aren't searched yet
+    let mut output = "<ul class=facets><li>Directories of these results:</li>".to_string();
+    for facet in top.iter() {
+        let directory = if facet.directory.is_empty() { "/" } else { &facet.directory };
+        output += &format!(
+            "<li><a href=\"/{}?{}\" target=_top>{}</a> {}</li>",
+            project,
+            html::escape(&search_url_params(diff_query, Some(&facet.include)).finish()),
+            html::escape(directory),
+            facet.count
+        );
+    }
+    output += "</ul>\n";
+    output
+}
+
+/// A link for loading the next page, handled by results.js
+fn more_results_link(diff_query: &DiffQuery, status: &SearchStatus) -> String {
+    let cursor = match &status.next_cursor {
+        Some(cursor) => cursor,
+        None => return String::new(),
+    };
+    let reason = match status.truncated {
+        Some(Truncation::Timeout) => "The search took too long".to_string(),
+        _ => format!("Showing {} results per page", diff_query.count),
+    };
+    let mut params = search_url_params(diff_query, None);
+    params.append_pair("count", &format!("{}", diff_query.count));
+    params.append_pair("cursor", &cursor.to_string());
+    format!(
+        "<p class=more>{} &mdash; <a class=more href=\"diffs?{}\">load older versions</a></p>\n",
+        reason,
+        html::escape(&params.finish())
+    )
+}
+
+pub fn get_diffs(project: String, diff_query: DiffQuery) -> Receiver<String> {
+    assert!(!project.contains("/"));
+    let (send, recv) = std::sync::mpsc::channel();
+
+    std::thread::spawn(move || {
+        let start = Instant::now();
+
+        // Further pages are appended to the first one
+        if diff_query.cursor.is_none() {
+            send.send("<link rel=\"stylesheet\" href=/static/results-inner.css>".to_string()).unwrap();
+            if let Some(name) = &diff_query.name {
+                send_lifetime_summary(&send, &project, name);
+            }
+        }
+
+        let project_data = projects::get(&project);
+        let mut hit_count = 0;
+        let mut facets = DirectoryFacets::default();
+        let status = search(&project, &diff_query, |hits| {
+            let mut tag_output = format!("<h2 class=h>{}</h2>\n", html::escape(&hits.version));
+            for record in hits.records.iter() {
+                tag_output += &record_to_html(&project, &project_data, &hits.version, record);
+                facets.add(&record.data.file);
+            }
+            hit_count += hits.records.len();
+            send.send(tag_output).unwrap();
+        });
+        if let (0, None, None, Some(name)) = (hit_count, &diff_query.cursor, &status.truncated, &diff_query.name) {
+            send_suggestions(&send, &project, name);
+        }
+        // Moved to the top by results.js, once every result is known
+        if diff_query.cursor.is_none() {
+            send.send(facets_html(&project, &diff_query, &facets)).unwrap();
+        }
+        send.send(more_results_link(&diff_query, &status)).unwrap();
+        println!("- request took {:?}", start.elapsed());
+    });
+
+    recv
+}
+
+/// The same results as `get_diffs`, either as one JSON object or as newline delimited JSON,
+/// where the last line has the `truncated`, `next_cursor` and `facets` fields.
+pub fn get_diffs_json(project: String, diff_query: DiffQuery, ndjson: bool) -> Receiver<String> {
+    assert!(!project.contains("/"));
+    let (send, recv) = std::sync::mpsc::channel();
+
+    std::thread::spawn(move || {
+        let start = Instant::now();
+        let project_data = projects::get(&project);
+
+        if !ndjson {
+            send.send("{\"results\":[".to_string()).unwrap();
+        }
+        let mut is_first = true;
+        let mut facets = DirectoryFacets::default();
+        let status = search(&project, &diff_query, |hits| {
+            let mut chunk = String::new();
+            for record in hits.records.iter() {
+                facets.add(&record.data.file);
+                let hit = serde_json::to_string(&DiffHit::new(&project_data, &hits.version, record)).unwrap();
+                if ndjson {
+                    chunk += &hit;
+                    chunk += "\n";
+                } else {
+                    if !is_first {
+                        chunk += ",";
+                    }
+                    chunk += &hit;
+                }
+                is_first = false;
+            }
+            send.send(chunk).unwrap();
+        });
+
+        let status_fields = format!(
+            "\"truncated\":{},\"next_cursor\":{},\"facets\":{}",
+            serde_json::to_string(&status.truncated).unwrap(),
+            serde_json::to_string(&status.next_cursor.map(|c| c.to_string())).unwrap(),
+            serde_json::to_string(&facets.top(FACET_COUNT)).unwrap()
+        );
+        if ndjson {
+            send.send(format!("{{{}}}\n", status_fields)).unwrap();
+        } else {
+            send.send(format!("],{}}}", status_fields)).unwrap();
         }
         println!("- request took {:?}", start.elapsed());
     });
 
     recv
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursors_round_trip() {
+        let cursor = Cursor::parse("v5.10-rc1:42").unwrap();
+        assert_eq!((cursor.version.as_str(), cursor.offset), ("v5.10-rc1", 42));
+        assert_eq!(cursor.to_string(), "v5.10-rc1:42");
+        assert!(Cursor::parse("v5.10").is_none());
+        assert!(Cursor::parse("v5.10:x").is_none());
+    }
+
+    #[test]
+    fn facets_link_to_their_files() {
+        let mut facets = DirectoryFacets::default();
+        for file in ["Makefile", "mm/slab.c", "mm/slub.c", "drivers/net/tun.c"].iter() {
+            facets.add(file);
+        }
+        let top = facets.top(FACET_COUNT).into_iter()
+            .map(|facet| (facet.directory, facet.count, facet.include))
+            .collect::<Vec<_>>();
+        assert_eq!(top, vec![
+            ("mm/".to_string(), 2, "mm/".to_string()),
+            ("".to_string(), 1, "*".to_string()),
+            ("drivers/".to_string(), 1, "drivers/".to_string()),
+        ]);
+
+        let mut facets = DirectoryFacets::default();
+        facets.add("drivers/Kconfig");
+        facets.add("drivers/net/tun.c");
+        assert_eq!(facets.top(FACET_COUNT)[0].include, "drivers/*");
+    }
+}
@@ -0,0 +1,156 @@
+use crate::lifetimes::Lifetime;
+use crate::{history, html, lifetime, projects, query};
+use serde::Serialize;
+use std::path::Path;
+
+/**
+ * The symbols of one project matching a global search
+ */
+#[derive(Serialize)]
+pub struct ProjectHits {
+    pub project: String,
+    /// The earliest and latest versions any matching symbol is present in
+    pub first_version: Option<String>,
+    pub last_version: Option<String>,
+    pub symbols: Vec<SymbolSpan>,
+    /// More symbols match than were asked for
+    pub truncated: bool,
+}
+
+#[derive(Serialize)]
+pub struct SymbolSpan {
+    pub name: String,
+    pub kind: String,
+    pub first_version: String,
+    /// The latest version it's present in
+    pub last_version: String,
+    /// Whether it's still in the latest indexed version
+    pub present: bool,
+    pub file: String,
+    pub line: u64,
+    pub signature: String,
+}
+
+fn to_span(lifetime: Lifetime, versions: &[String]) -> SymbolSpan {
+    let last_version = match &lifetime.removed {
+        Some(removed) => history::version_index(versions, removed)
+            .and_then(|idx| idx.checked_sub(1))
+            .map(|idx| versions[idx].clone())
+            .unwrap_or_else(|| lifetime.introduced.clone()),
+        None => versions.last().cloned().unwrap_or_else(|| lifetime.introduced.clone()),
+    };
+    SymbolSpan {
+        present: lifetime.removed.is_none(),
+        name: lifetime.name,
+        kind: lifetime.symbol_type,
+        first_version: lifetime.introduced,
+        last_version,
+        file: lifetime.file,
+        line: lifetime.line,
+        signature: lifetime.signature,
+    }
+}
+
+/// The first `count` symbols of a project's matching lifetimes, with the versions spanned by all of them.
+/// `None` without a match.
+fn project_hits(project: String, versions: &[String], lifetimes: Vec<Lifetime>, count: u64) -> Option<ProjectHits> {
+    if lifetimes.is_empty() {
+        return None;
+    }
+    let version_idx = |v: &str| history::version_index(versions, v).unwrap_or(0);
+    let truncated = lifetimes.len() as u64 > count;
+    let mut symbols = lifetimes.into_iter().map(|l| to_span(l, versions)).collect::<Vec<_>>();
+    let first_version = symbols.iter().map(|s| &s.first_version).min_by_key(|v| version_idx(v)).cloned();
+    let last_version = symbols.iter().map(|s| &s.last_version).max_by_key(|v| version_idx(v)).cloned();
+    symbols.truncate(count as usize);
+    Some(ProjectHits { project, first_version, last_version, symbols, truncated })
+}
+
+/// Runs the query (a glob or a regex, as in the search box) against every project's lifetimes.
+/// Projects without a match are left out.
+pub fn search(query: &str, count: u64) -> Result<Vec<ProjectHits>, String> {
+    query::name_regex(query).map_err(|e| format!("Invalid name pattern `{}`: {}", query, e))?;
+    if count == 0 {
+        return Err("`count` has to be at least 1".to_string());
+    }
+
+    let mut results = vec![];
+    for project in projects::list() {
+        let versions = history::read_versions(&Path::new("sourcedigger-db").join(&project));
+        // Every match, so the versions they span don't depend on how many are shown
+        let lifetimes = lifetime::find_lifetimes(&project, query, u64::MAX);
+        results.extend(project_hits(project, &versions, lifetimes, count));
+    }
+    Ok(results)
+}
+
+pub fn to_html(query: &str, results: &[ProjectHits]) -> String {
+    let mut output = format!(
+        "<!DOCTYPE html>\n<meta charset=\"UTF-8\">\n<title>{0} - SourceDigger</title>\n<link rel=\"stylesheet\" href=\"/static/results-inner.css\">\n<h1>Every project</h1>\n\
+        <form class=filters action=/search><input name=q placeholder=\"name, glob or regex\" value=\"{0}\"> <button>Search</button></form>\n",
+        html::escape(query)
+    );
+    if results.is_empty() {
+        return output + "<p class=summary>No project has a matching symbol</p>\n";
+    }
+
+    let encoded_query = url::form_urlencoded::byte_serialize(query.as_bytes()).collect::<String>();
+    for hits in results.iter() {
+        output += &format!(
+            "<h2 class=h><a href=\"/{0}?q={1}\">{2}</a> <small>{3}{4} symbol{5}, {6} &ndash; {7}</small></h2>\n",
+            hits.project,
+            encoded_query,
+            html::escape(&projects::get(&hits.project).name),
+            if hits.truncated { "over " } else { "" },
+            hits.symbols.len(),
+            if hits.symbols.len() == 1 { "" } else { "s" },
+            html::escape(hits.first_version.as_deref().unwrap_or("")),
+            html::escape(hits.last_version.as_deref().unwrap_or("")),
+        );
+        for symbol in hits.symbols.iter() {
+            output += &format!(
+                "<div class={0}><a class={1}>{1}</a><a href=\"/{2}?q={3}\"><span></span><span>{4}</span></a>&nbsp;<code>{5}</code><hr><a>{6} &ndash; {7}</a></div>\n",
+                if symbol.present { "a" } else { "r" },
+                history::symbol_type_letter(history::parse_symbol_type(&symbol.kind)),
+                hits.project,
+                url::form_urlencoded::byte_serialize(symbol.name.as_bytes()).collect::<String>(),
+                html::escape(&symbol.name),
+                html::escape(&symbol.signature),
+                html::escape(&symbol.first_version),
+                html::escape(&symbol.last_version),
+            );
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lifetime(name: &str, introduced: &str, removed: Option<&str>) -> Lifetime {
+        Lifetime {
+            name: name.to_string(),
+            symbol_type: "Function".to_string(),
+            introduced: introduced.to_string(),
+            removed: removed.map(str::to_string),
+            gaps: vec![],
+            file: "a.c".to_string(),
+            line: 1,
+            signature: format!("int {}(void)", name),
+        }
+    }
+
+    #[test]
+    fn versions_cover_the_truncated_symbols() {
+        let versions = ["v1", "v2", "v3", "v4"].iter().map(|v| v.to_string()).collect::<Vec<_>>();
+        let lifetimes = vec![lifetime("a", "v2", Some("v3")), lifetime("b", "v1", None)];
+        let hits = project_hits("p".to_string(), &versions, lifetimes, 1).unwrap();
+        assert_eq!(hits.symbols.iter().map(|s| s.name.as_str()).collect::<Vec<_>>(), vec!["a"]);
+        assert_eq!(hits.symbols[0].last_version, "v2");
+        assert!(hits.truncated);
+        assert_eq!((hits.first_version.as_deref(), hits.last_version.as_deref()), (Some("v1"), Some("v4")));
+
+        assert!(project_hits("p".to_string(), &versions, vec![], 1).is_none());
+    }
+}
@@ -0,0 +1,81 @@
+/// How many typos to tolerate, longer names can have more
+pub fn max_typos(query: &str) -> usize {
+    (query.chars().count() / 4).clamp(1, 3)
+}
+
+/// Case insensitive edit distance, counting a swap of two adjacent characters as one edit.
+/// Gives up early, returning `None` once it's sure to be more than `max`.
+pub fn edit_distance(a: &str, b: &str, max: usize) -> Option<usize> {
+    let a = a.to_lowercase().chars().collect::<Vec<_>>();
+    let b = b.to_lowercase().chars().collect::<Vec<_>>();
+    if (a.len() as isize - b.len() as isize).unsigned_abs() > max {
+        return None;
+    }
+
+    // Three rows of the usual dynamic programming table
+    let mut before_prev: Vec<usize> = vec![0; b.len() + 1];
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    let mut cur = vec![0; b.len() + 1];
+    for i in 1..=a.len() {
+        cur[0] = i;
+        for j in 1..=b.len() {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            cur[j] = (prev[j] + 1).min(cur[j - 1] + 1).min(prev[j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                cur[j] = cur[j].min(before_prev[j - 2] + 1);
+            }
+        }
+        if cur.iter().min().copied().unwrap_or(0) > max {
+            return None;
+        }
+        std::mem::swap(&mut before_prev, &mut prev);
+        std::mem::swap(&mut prev, &mut cur);
+    }
+    Some(prev[b.len()]).filter(|d| *d <= max)
+}
+
+/// If every character of the query appears in the name in order (case insensitively),
+/// how many characters of the name were skipped between the first and last match.
+/// e.g. `spnlck` is in `spin_lock` with 3 skipped.
+pub fn subsequence_gaps(query: &str, name: &str) -> Option<usize> {
+    let mut query_chars = query.chars().flat_map(char::to_lowercase).peekable();
+    let mut gaps = 0;
+    let mut started = false;
+    for c in name.chars().flat_map(char::to_lowercase) {
+        match query_chars.peek() {
+            Some(q) if *q == c => {
+                query_chars.next();
+                started = true;
+            }
+            Some(_) if started => gaps += 1,
+            Some(_) => {}
+            None => break,
+        }
+    }
+    if query_chars.peek().is_none() {
+        Some(gaps)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn typos_are_counted() {
+        assert_eq!(edit_distance("kmaloc", "kmalloc", 1), Some(1));
+        assert_eq!(edit_distance("kmlaloc", "kmalloc", 1), Some(1));
+        assert_eq!(edit_distance("KMALLOC", "kmalloc", 1), Some(0));
+        assert_eq!(edit_distance("kzalloc_node", "kmalloc", 3), None);
+        assert_eq!(edit_distance("", "ab", 2), Some(2));
+    }
+
+    #[test]
+    fn subsequences_count_skipped_characters() {
+        assert_eq!(subsequence_gaps("spnlck", "spin_lock"), Some(3));
+        assert_eq!(subsequence_gaps("SPIN", "spin_lock"), Some(0));
+        assert_eq!(subsequence_gaps("lockspin", "spin_lock"), None);
+    }
+}
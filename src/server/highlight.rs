@@ -0,0 +1,126 @@
+use crate::html;
+
+const C_KEYWORDS: [&str; 44] = [
+    "auto", "break", "case", "char", "const", "continue", "default", "do", "double", "else", "enum", "extern",
+    "float", "for", "goto", "if", "inline", "int", "long", "register", "restrict", "return", "short", "signed",
+    "sizeof", "static", "struct", "switch", "typedef", "union", "unsigned", "void", "volatile", "while", "_Bool",
+    "bool", "true", "false", "NULL", "asm", "__asm__", "typeof", "__attribute__", "__inline__",
+];
+
+/// Whether a file is highlighted as C, by its extension
+pub fn is_c_like(path: &str) -> bool {
+    let extension = path.rsplit('.').next().unwrap_or("");
+    ["c", "h", "cc", "cpp", "cxx", "hh", "hpp", "S"].contains(&extension)
+}
+
+/**
+ * Highlights C one line at a time, remembering block comments that continue on the next line.
+ * Tokens are wrapped in `<span>`s: `c` comments, `s` strings, `k` keywords, `n` numbers and `p` preprocessor directives.
+ */
+#[derive(Default)]
+pub struct CHighlighter {
+    in_comment: bool,
+}
+
+fn span(class: &str, text: &str) -> String {
+    format!("<span class={}>{}</span>", class, html::escape(text))
+}
+
+fn is_identifier_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_'
+}
+
+fn is_identifier_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+impl CHighlighter {
+    /// `link` returns the URL an identifier links to, if any
+    pub fn line<F: Fn(&str) -> Option<String>>(&mut self, line: &str, link: F) -> String {
+        let mut output = String::new();
+        let mut rest = line;
+
+        if self.in_comment {
+            match rest.find("*/") {
+                Some(end) => {
+                    output += &span("c", &rest[..end + 2]);
+                    rest = &rest[end + 2..];
+                    self.in_comment = false;
+                }
+                None => return span("c", rest),
+            }
+        }
+
+        // The directive itself, e.g. `#include` or `# define`
+        let trimmed = rest.trim_start();
+        if let Some(after_hash) = trimmed.strip_prefix('#') {
+            let indent = rest.len() - trimmed.len();
+            let after_hash = after_hash.trim_start();
+            let word_len = after_hash.find(|c: char| !is_identifier_char(c)).unwrap_or(after_hash.len());
+            let directive_len = indent + (trimmed.len() - after_hash.len()) + word_len;
+            output += &span("p", &rest[..directive_len]);
+            let is_include = &after_hash[..word_len] == "include";
+            rest = &rest[directive_len..];
+            if is_include {
+                let path = rest.trim_start();
+                if path.starts_with('<') {
+                    let end = path.find('>').map(|e| e + 1).unwrap_or(path.len());
+                    output += &html::escape(&rest[..rest.len() - path.len()]);
+                    output += &span("s", &path[..end]);
+                    rest = &path[end..];
+                }
+            }
+        }
+
+        while let Some(c) = rest.chars().next() {
+            let token_len = if rest.starts_with("//") {
+                output += &span("c", rest);
+                rest.len()
+            } else if let Some(comment) = rest.strip_prefix("/*") {
+                match comment.find("*/") {
+                    Some(end) => {
+                        output += &span("c", &rest[..end + 4]);
+                        end + 4
+                    }
+                    None => {
+                        self.in_comment = true;
+                        output += &span("c", rest);
+                        rest.len()
+                    }
+                }
+            } else if c == '"' || c == '\'' {
+                let mut escaped = false;
+                let end = rest.char_indices().skip(1)
+                    .find(|(_, ch)| {
+                        let closes = *ch == c && !escaped;
+                        escaped = *ch == '\\' && !escaped;
+                        closes
+                    })
+                    .map(|(i, _)| i + 1)
+                    .unwrap_or(rest.len());
+                output += &span("s", &rest[..end]);
+                end
+            } else if c.is_ascii_digit() {
+                let end = rest.find(|ch: char| !(is_identifier_char(ch) || ch == '.')).unwrap_or(rest.len());
+                output += &span("n", &rest[..end]);
+                end
+            } else if is_identifier_start(c) {
+                let end = rest.find(|ch: char| !is_identifier_char(ch)).unwrap_or(rest.len());
+                let identifier = &rest[..end];
+                if C_KEYWORDS.contains(&identifier) {
+                    output += &span("k", identifier);
+                } else if let Some(url) = link(identifier) {
+                    output += &format!("<a href=\"{}\">{}</a>", url, html::escape(identifier));
+                } else {
+                    output += identifier;
+                }
+                end
+            } else {
+                output += &html::escape(&rest[..c.len_utf8()]);
+                c.len_utf8()
+            };
+            rest = &rest[token_len..];
+        }
+        output
+    }
+}
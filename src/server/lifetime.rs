@@ -0,0 +1,47 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use crate::lifetimes::Lifetime;
+use crate::{projects, query, sorted_lines};
+
+/// Looks up the lifetimes of the symbols matching a search box query, in the order of the file.
+/// The name index tells which names match, without it every line is checked.
+pub fn find_lifetimes(project: &str, query: &str, count: u64) -> Vec<Lifetime> {
+    assert!(!project.contains("/"));
+    let path = Path::new("sourcedigger-db").join(project).join("lifetimes");
+    let file = match File::open(&path) {
+        Ok(file) => file,
+        Err(_) => return vec![],
+    };
+    let name_regex = match query::name_regex(query) {
+        Ok(name_regex) => name_regex,
+        Err(_) => return vec![],
+    };
+    let count = count as usize;
+
+    if let Some(name_index) = projects::name_index(project) {
+        let file_len = file.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+        let mut file = BufReader::new(file);
+        let mut names = name_index.find_names(&query::literal_prefix(query), &name_regex).collect::<Vec<_>>();
+        names.sort();
+        let mut lifetimes = vec![];
+        for name in names {
+            if lifetimes.len() >= count {
+                break;
+            }
+            // The file is sorted by name
+            let lines = sorted_lines::find_lines(&mut file, 0, file_len, name);
+            lifetimes.extend(lines.iter().filter_map(|line| Lifetime::parse(line)));
+        }
+        lifetimes.truncate(count);
+        lifetimes
+    } else {
+        BufReader::new(file).lines()
+            .map_while(Result::ok)
+            .filter(|line| name_regex.is_match(line.split('\t').next().unwrap_or("")))
+            .filter_map(|line| Lifetime::parse(&line))
+            .take(count)
+            .collect()
+    }
+}
+
@@ -1,20 +1,64 @@
 #![feature(proc_macro_hygiene, decl_macro)]
 
 mod autocomplete;
+mod blame;
+mod code_search;
+mod compare;
+mod definition_diff;
 mod diffs;
+mod federated;
+mod fuzzy;
+mod highlight;
+mod lifetime;
+mod projects;
+mod query;
+mod release;
+mod source;
+mod stats;
+mod surface;
+mod symbols_at;
+mod timeline;
+mod usages;
 mod streamed_string_list_response;
 mod cached_file;
 use cached_file::CachedFile;
 #[path="../data.rs"]
 mod data;
+#[path="../history.rs"]
+mod history;
+#[path="../html.rs"]
+mod html;
+#[path="../lifetimes.rs"]
+mod lifetimes;
+#[path="../break_report.rs"]
+mod break_report;
+#[path="../compat_header.rs"]
+mod compat_header;
+#[path="../name_index.rs"]
+mod name_index;
+#[path="../diff_store.rs"]
+mod diff_store;
+#[path="../version_stats.rs"]
+mod version_stats;
+#[path="../version_files.rs"]
+mod version_files;
+#[path="../references.rs"]
+mod references;
+#[path="../sorted_lines.rs"]
+mod sorted_lines;
+#[path="../code_index.rs"]
+mod code_index;
 
 use inflector::Inflector;
-use rocket_contrib::serve::StaticFiles;
 use rocket_contrib::templates::Template;
 use std::collections::HashMap;
+use std::fmt::Write;
 use streamed_string_list_response::StreamedStringListResponse;
 use url::form_urlencoded;
 use rocket::response::NamedFile;
+use rocket::response::content;
+use rocket::http::ContentType;
+use rocket::response::status::{BadRequest, NotFound};
 use std::path::{Path, PathBuf};
 
 #[macro_use]
@@ -22,36 +66,97 @@ extern crate rocket;
 #[macro_use]
 extern crate lazy_static;
 
-#[get("/<project>/autocomplete?<q>&<count>")]
+/// `fuzzy` tolerates typos, but only takes plain names rather than globs or regexes
+#[get("/<project>/autocomplete?<q>&<count>&<fuzzy>")]
 fn autocomplete_view(
     project: String,
     q: Option<String>,
     count: Option<u64>,
-) -> CachedFile<StreamedStringListResponse> {
-    CachedFile(StreamedStringListResponse::new(autocomplete::complete(
-        project,
-        q.unwrap_or(".*".into()),
-        count.unwrap_or(100),
-    )))
+    fuzzy: Option<bool>,
+) -> CachedFile<content::Plain<String>> {
+    let names = if fuzzy.unwrap_or(false) {
+        autocomplete::complete_fuzzy(&project, &q.unwrap_or_default(), count.unwrap_or(100))
+    } else {
+        autocomplete::complete(&project, &q.unwrap_or(".*".into()), count.unwrap_or(100))
+    };
+    CachedFile(content::Plain(names.iter().fold(String::new(), |mut output, name| {
+        let _ = writeln!(output, "{}", name);
+        output
+    })))
 }
 
-#[get("/<project>/diffs?<q>&<a>&<t>&<count>")]
+/// Invalid queries are answered with the parse error
+#[allow(clippy::too_many_arguments)]
+#[get("/<project>/diffs?<q>&<a>&<t>&<from>&<to>&<include>&<exclude>&<mode>&<count>&<cursor>")]
 fn search_view(
     project: String,
     q: String,
     a: Option<String>,
     t: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    include: Option<String>,
+    exclude: Option<String>,
+    mode: Option<String>,
+    count: Option<u64>,
+    cursor: Option<String>,
+) -> Result<CachedFile<StreamedStringListResponse>, BadRequest<content::Html<String>>> {
+    let diff_query = diffs::DiffQuery::parse(&project, diffs::SearchParams { q, a, t, from, to, include, exclude, mode, count, cursor })
+        .map_err(|e| BadRequest(Some(content::Html(diffs::error_html(&e)))))?;
+    Ok(CachedFile(StreamedStringListResponse::new(diffs::get_diffs(project, diff_query))))
+}
+
+/// `format` is either "json" (the default) or "ndjson"
+#[allow(clippy::too_many_arguments)]
+#[get("/api/<project>/diffs?<q>&<a>&<t>&<from>&<to>&<include>&<exclude>&<mode>&<count>&<cursor>&<format>", rank = 1)]
+fn search_api(
+    project: String,
+    q: String,
+    a: Option<String>,
+    t: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    include: Option<String>,
+    exclude: Option<String>,
+    mode: Option<String>,
+    count: Option<u64>,
+    cursor: Option<String>,
+    format: Option<String>,
+) -> Result<CachedFile<StreamedStringListResponse>, BadRequest<content::Json<String>>> {
+    let diff_query = diffs::DiffQuery::parse(&project, diffs::SearchParams { q, a, t, from, to, include, exclude, mode, count, cursor })
+        .map_err(|e| BadRequest(Some(content::Json(diffs::error_json(&e)))))?;
+    let ndjson = format.as_deref() == Some("ndjson");
+    let content_type = if ndjson {
+        ContentType::new("application", "x-ndjson")
+    } else {
+        ContentType::JSON
+    };
+    Ok(CachedFile(StreamedStringListResponse::with_content_type(
+        diffs::get_diffs_json(project, diff_query, ndjson),
+        content_type,
+    )))
+}
+
+#[get("/<project>/at/<version>?<q>&<count>")]
+fn symbols_at_view(
+    project: String,
+    version: String,
+    q: String,
     count: Option<u64>,
 ) -> CachedFile<StreamedStringListResponse> {
-    CachedFile(StreamedStringListResponse::new(diffs::get_diffs(
+    CachedFile(StreamedStringListResponse::new(symbols_at::search_at(
         project,
+        version,
         q,
-        a.unwrap_or("arm".into()),
-        t.unwrap_or("fvd".into()),
-        count.unwrap_or(u64::max_value()),
+        count.unwrap_or(1000),
     )))
 }
 
+#[get("/<project>/symbol/<name>")]
+fn timeline_view(project: String, name: String) -> CachedFile<StreamedStringListResponse> {
+    CachedFile(StreamedStringListResponse::new(timeline::get_timeline(project, name)))
+}
+
 #[get("/<_project>/diffs")]
 fn empty_search_view(
     _project: String,
@@ -60,8 +165,19 @@ fn empty_search_view(
     CachedFile(Template::render("welcome", &context))
 }
 
-#[get("/<project>?<q>&<a>&<t>")]
-fn project_view(project: String, q: Option<String>, a: Option<String>, t: Option<String>) -> CachedFile<Template> {
+#[allow(clippy::too_many_arguments)]
+#[get("/<project>?<q>&<a>&<t>&<from>&<to>&<include>&<exclude>&<mode>")]
+fn project_view(
+    project: String,
+    q: Option<String>,
+    a: Option<String>,
+    t: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    include: Option<String>,
+    exclude: Option<String>,
+    mode: Option<String>,
+) -> CachedFile<Template> {
     let mut context = HashMap::<String, String>::new();
     context.insert("project".into(), project.clone());
     context.insert("Project".into(), project.to_title_case());
@@ -76,6 +192,21 @@ fn project_view(project: String, q: Option<String>, a: Option<String>, t: Option
     if let Some(t) = &t {
         diff_params.append_pair("t", t);
     }
+    if let Some(from) = &from {
+        diff_params.append_pair("from", from);
+    }
+    if let Some(to) = &to {
+        diff_params.append_pair("to", to);
+    }
+    if let Some(include) = &include {
+        diff_params.append_pair("include", include);
+    }
+    if let Some(exclude) = &exclude {
+        diff_params.append_pair("exclude", exclude);
+    }
+    if let Some(mode) = &mode {
+        diff_params.append_pair("mode", mode);
+    }
 
     context.insert("diff_params".into(), diff_params.finish());
     context.insert("q".into(), q.clone().unwrap_or("".into()));
@@ -87,19 +218,277 @@ fn project_view(project: String, q: Option<String>, a: Option<String>, t: Option
     CachedFile(Template::render("results", &context))
 }
 
+#[get("/<project>/breaks?<from>&<to>")]
+fn breaks_view(project: String, from: String, to: String) -> Result<CachedFile<content::Html<String>>, NotFound<String>> {
+    assert!(!project.contains("/"));
+    break_report::BreakReport::compute(&Path::new("sourcedigger-db").join(&project), &from, &to)
+        .map(|report| CachedFile(content::Html(report.to_html(Some(&project)))))
+        .map_err(NotFound)
+}
+
+// API routes are ranked below project pages, whose paths look the same to the router
+#[get("/api/<project>/breaks?<from>&<to>", rank = 1)]
+fn breaks_api(project: String, from: String, to: String) -> Result<CachedFile<content::Json<String>>, NotFound<String>> {
+    assert!(!project.contains("/"));
+    break_report::BreakReport::compute(&Path::new("sourcedigger-db").join(&project), &from, &to)
+        .map(|report| CachedFile(content::Json(report.to_json())))
+        .map_err(NotFound)
+}
+
+/// `range` is `<from>...<to>`, e.g. `v6.1...v6.6`
+#[allow(clippy::too_many_arguments)]
+#[get("/<project>/compare/<range>?<q>&<a>&<t>&<include>&<exclude>&<sort>")]
+fn compare_view(
+    project: String,
+    range: String,
+    q: Option<String>,
+    a: Option<String>,
+    t: Option<String>,
+    include: Option<String>,
+    exclude: Option<String>,
+    sort: Option<String>,
+) -> Result<CachedFile<content::Html<String>>, BadRequest<content::Html<String>>> {
+    assert!(!project.contains("/"));
+    let compare_query = compare::CompareQuery::parse(&range, compare::CompareParams { q, a, t, include, exclude, sort })
+        .map_err(|e| BadRequest(Some(content::Html(diffs::error_html(&e)))))?;
+    compare::Comparison::compute(&project, &compare_query)
+        .map(|comparison| CachedFile(content::Html(comparison.to_html(&project, &compare_query))))
+        .map_err(|e| BadRequest(Some(content::Html(diffs::error_html(&e)))))
+}
+
+#[allow(clippy::too_many_arguments)]
+#[get("/api/<project>/compare/<range>?<q>&<a>&<t>&<include>&<exclude>&<sort>", rank = 1)]
+fn compare_api(
+    project: String,
+    range: String,
+    q: Option<String>,
+    a: Option<String>,
+    t: Option<String>,
+    include: Option<String>,
+    exclude: Option<String>,
+    sort: Option<String>,
+) -> Result<CachedFile<content::Json<String>>, BadRequest<content::Json<String>>> {
+    assert!(!project.contains("/"));
+    let compare_query = compare::CompareQuery::parse(&range, compare::CompareParams { q, a, t, include, exclude, sort })
+        .map_err(|e| BadRequest(Some(content::Json(diffs::error_json(&e)))))?;
+    compare::Comparison::compute(&project, &compare_query)
+        .map(|comparison| CachedFile(content::Json(comparison.to_json())))
+        .map_err(|e| BadRequest(Some(content::Json(diffs::error_json(&e)))))
+}
+
+/// `format` is either "html" (the default) or "markdown"
+#[get("/<project>/release/<version>?<format>")]
+fn release_view(project: String, version: String, format: Option<String>) -> Result<CachedFile<content::Content<String>>, NotFound<String>> {
+    assert!(!project.contains("/"));
+    let notes = release::ReleaseNotes::load(&project, &version).map_err(NotFound)?;
+    match format.as_deref() {
+        None | Some("html") => Ok(CachedFile(content::Content(ContentType::HTML, notes.to_html(&project)))),
+        Some("markdown") => Ok(CachedFile(content::Content(
+            ContentType::with_params("text", "markdown", ("charset", "utf-8")),
+            notes.to_markdown(&project),
+        ))),
+        Some(format) => Err(NotFound(format!("Unknown format: {}", format))),
+    }
+}
+
+/// `directory` restricts the counts to one top-level directory, e.g. `drivers/`
+#[get("/<project>/stats?<directory>")]
+fn stats_view(project: String, directory: Option<String>) -> Result<CachedFile<content::Html<String>>, NotFound<String>> {
+    let directory = directory.as_deref();
+    stats::load(&project, directory, directory.is_none())
+        .map(|versions| CachedFile(content::Html(stats::to_html(&project, directory, &versions))))
+        .map_err(NotFound)
+}
+
+/// `directories` adds the counts of every top-level directory to each version
+#[get("/api/<project>/stats?<directory>&<directories>", rank = 1)]
+fn stats_api(project: String, directory: Option<String>, directories: Option<bool>) -> Result<CachedFile<content::Json<String>>, NotFound<String>> {
+    stats::load(&project, directory.as_deref(), directories.unwrap_or(false))
+        .map(|versions| CachedFile(content::Json(serde_json::to_string(&versions).unwrap())))
+        .map_err(NotFound)
+}
+
+#[get("/api/<project>/lifetime?<q>&<count>", rank = 1)]
+fn lifetime_api(project: String, q: String, count: Option<u64>) -> CachedFile<content::Json<String>> {
+    let lifetimes = lifetime::find_lifetimes(&project, &q, count.unwrap_or(100));
+    CachedFile(content::Json(serde_json::to_string(&lifetimes).unwrap()))
+}
+
+/// `symbols` is a comma or whitespace separated list
+#[get("/<project>/compat_header?<from>&<to>&<symbols>")]
+fn compat_header_view(project: String, from: String, to: String, symbols: String) -> Result<CachedFile<content::Plain<String>>, NotFound<String>> {
+    assert!(!project.contains("/"));
+    let db_path = Path::new("sourcedigger-db").join(&project);
+    let symbols = symbols
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|s| !s.is_empty())
+        .map(String::from)
+        .collect::<Vec<_>>();
+    data::ProjectRepo::load(&db_path)
+        .and_then(|config| compat_header::compat_header(&db_path, &config, &symbols, &from, &to))
+        .map(|header| CachedFile(content::Plain(header)))
+        .map_err(NotFound)
+}
+
+/// Searches every project at once, `count` is per project
+#[get("/search?<q>&<count>")]
+fn federated_view(q: String, count: Option<u64>) -> Result<CachedFile<content::Html<String>>, BadRequest<content::Html<String>>> {
+    federated::search(&q, count.unwrap_or(100))
+        .map(|results| CachedFile(content::Html(federated::to_html(&q, &results))))
+        .map_err(|e| BadRequest(Some(content::Html(diffs::error_html(&e)))))
+}
+
+#[get("/api/search?<q>&<count>")]
+fn federated_api(q: String, count: Option<u64>) -> Result<CachedFile<content::Json<String>>, BadRequest<content::Json<String>>> {
+    federated::search(&q, count.unwrap_or(100))
+        .map(|results| CachedFile(content::Json(serde_json::to_string(&results).unwrap())))
+        .map_err(|e| BadRequest(Some(content::Json(diffs::error_json(&e)))))
+}
+
+/// `left` and `right` are `project` or `project:version`, e.g. `glibc:glibc-2.35` and `musl`
+#[get("/surface?<left>&<right>&<q>&<t>&<exact>")]
+fn surface_view(
+    left: String,
+    right: String,
+    q: Option<String>,
+    t: Option<String>,
+    exact: Option<bool>,
+) -> Result<CachedFile<content::Html<String>>, BadRequest<content::Html<String>>> {
+    surface::SurfaceQuery::parse(q.clone(), t.clone(), exact)
+        .and_then(|surface_query| surface::SurfaceComparison::compute(&left, &right, &surface_query))
+        .map(|comparison| CachedFile(content::Html(comparison.to_html(q.as_deref(), t.as_deref(), exact.unwrap_or(false)))))
+        .map_err(|e| BadRequest(Some(content::Html(diffs::error_html(&e)))))
+}
+
+#[get("/api/surface?<left>&<right>&<q>&<t>&<exact>")]
+fn surface_api(
+    left: String,
+    right: String,
+    q: Option<String>,
+    t: Option<String>,
+    exact: Option<bool>,
+) -> Result<CachedFile<content::Json<String>>, BadRequest<content::Json<String>>> {
+    surface::SurfaceQuery::parse(q, t, exact)
+        .and_then(|surface_query| surface::SurfaceComparison::compute(&left, &right, &surface_query))
+        .map(|comparison| CachedFile(content::Json(comparison.to_json())))
+        .map_err(|e| BadRequest(Some(content::Json(diffs::error_json(&e)))))
+}
+
+/// Reads the file from the project's bare repository or the extracted objects, lines are linked as `#L<line>`
+#[get("/<project>/source/<version>/<path..>")]
+fn source_view(project: String, version: String, path: PathBuf) -> Result<CachedFile<content::Html<String>>, NotFound<String>> {
+    assert!(!project.contains("/"));
+    let path = path.to_string_lossy().to_string();
+    source::read_file(&project, &version, &path)
+        .map(|content| CachedFile(content::Html(source::to_html(&project, &version, &path, &content))))
+        .map_err(NotFound)
+}
+
+/// The symbols of a file at `version`, with the versions that introduced and last changed them
+#[get("/<project>/blame/<version>/<path..>")]
+fn blame_view(project: String, version: String, path: PathBuf) -> Result<CachedFile<content::Html<String>>, NotFound<String>> {
+    assert!(!project.contains("/"));
+    let path = path.to_string_lossy().to_string();
+    blame::Blame::compute(&project, &version, &path)
+        .map(|blame| CachedFile(content::Html(blame.to_html(&project))))
+        .map_err(NotFound)
+}
+
+#[get("/api/<project>/blame/<version>/<path..>", rank = 1)]
+fn blame_api(project: String, version: String, path: PathBuf) -> Result<CachedFile<content::Json<String>>, NotFound<String>> {
+    assert!(!project.contains("/"));
+    let path = path.to_string_lossy().to_string();
+    blame::Blame::compute(&project, &version, &path)
+        .map(|blame| CachedFile(content::Json(blame.to_json())))
+        .map_err(NotFound)
+}
+
+/// Lines of `version`'s files (the latest by default) containing `q`, a regex with `regex=true`.
+/// `path` takes comma separated path globs.
+#[get("/<project>/code?<q>&<version>&<regex>&<path>&<count>")]
+fn code_search_view(
+    project: String,
+    q: String,
+    version: Option<String>,
+    regex: Option<bool>,
+    path: Option<String>,
+    count: Option<usize>,
+) -> Result<CachedFile<content::Html<String>>, BadRequest<content::Html<String>>> {
+    assert!(!project.contains("/"));
+    let is_regex = regex.unwrap_or(false);
+    code_search::CodeQuery::parse(&q, is_regex, path.clone(), count)
+        .and_then(|code_query| {
+            code_search::CodeResults::search(&project, version.as_deref(), &code_query)
+                .map(|results| results.to_html(&project, &code_query, &q, is_regex, path.as_deref()))
+        })
+        .map(|html| CachedFile(content::Html(html)))
+        .map_err(|e| BadRequest(Some(content::Html(diffs::error_html(&e)))))
+}
+
+#[get("/api/<project>/code?<q>&<version>&<regex>&<path>&<count>", rank = 1)]
+fn code_search_api(
+    project: String,
+    q: String,
+    version: Option<String>,
+    regex: Option<bool>,
+    path: Option<String>,
+    count: Option<usize>,
+) -> Result<CachedFile<content::Json<String>>, BadRequest<content::Json<String>>> {
+    assert!(!project.contains("/"));
+    code_search::CodeQuery::parse(&q, regex.unwrap_or(false), path, count)
+        .and_then(|code_query| code_search::CodeResults::search(&project, version.as_deref(), &code_query))
+        .map(|results| CachedFile(content::Json(results.to_json())))
+        .map_err(|e| BadRequest(Some(content::Json(diffs::error_json(&e)))))
+}
+
+/// Where `name` is used at `version` (the latest by default), or the version before it with `before`
+#[get("/<project>/references/<name>?<version>&<before>")]
+fn usages_view(project: String, name: String, version: Option<String>, before: Option<bool>) -> Result<CachedFile<content::Html<String>>, NotFound<String>> {
+    assert!(!project.contains("/"));
+    usages::Usages::find(&project, &name, version.as_deref(), before.unwrap_or(false))
+        .map(|usages| CachedFile(content::Html(usages.to_html(&project))))
+        .map_err(NotFound)
+}
+
+#[get("/api/<project>/references/<name>?<version>&<before>", rank = 1)]
+fn usages_api(project: String, name: String, version: Option<String>, before: Option<bool>) -> Result<CachedFile<content::Json<String>>, NotFound<String>> {
+    assert!(!project.contains("/"));
+    usages::Usages::find(&project, &name, version.as_deref(), before.unwrap_or(false))
+        .map(|usages| CachedFile(content::Json(usages.to_json())))
+        .map_err(NotFound)
+}
+
+/// The definition of a symbol modified in `version`, diffed against the one before.
+/// `t` and `file` pick a definition when there are several, `layout` is "unified" (the default) or "split".
+#[get("/<project>/definition/<name>?<version>&<t>&<file>&<layout>")]
+fn definition_diff_view(
+    project: String,
+    name: String,
+    version: String,
+    t: Option<String>,
+    file: Option<String>,
+    layout: Option<String>,
+) -> Result<CachedFile<content::Html<String>>, NotFound<String>> {
+    assert!(!project.contains("/"));
+    let split = layout.as_deref() == Some("split");
+    definition_diff::DefinitionDiff::load(&project, &name, &version, t.as_deref(), file.as_deref())
+        .map(|diff| CachedFile(content::Html(diff.to_html(&project, split))))
+        .map_err(NotFound)
+}
+
 #[get("/<project>/project_logo.png")]
 fn project_logo_view(project: String) -> Option<CachedFile<NamedFile>> {
-    NamedFile::open(Path::new("sourcedigger-db").join(project).join("logo.png")).ok().map(|nf| CachedFile(nf))
+    NamedFile::open(Path::new("sourcedigger-db").join(project).join("logo.png")).ok().map(CachedFile)
 }
 
 #[get("/<project>/project_logo.svg")]
 fn project_logo_svg_view(project: String) -> Option<CachedFile<NamedFile>> {
-    NamedFile::open(Path::new("sourcedigger-db").join(project).join("logo.svg")).ok().map(|nf| CachedFile(nf))
+    NamedFile::open(Path::new("sourcedigger-db").join(project).join("logo.svg")).ok().map(CachedFile)
 }
 
 #[get("/robots.txt")]
 fn robots_txt_view() -> Option<CachedFile<NamedFile>> {
-    NamedFile::open("static/robots.txt").ok().map(|nf| CachedFile(nf))
+    NamedFile::open("static/robots.txt").ok().map(CachedFile)
 }
 
 #[get("/")]
@@ -110,16 +499,17 @@ fn index_view() -> CachedFile<Template> {
 
 #[get("/static/<file..>", rank=10)]
 fn files(file: PathBuf) -> Option<CachedFile<NamedFile>> {
-    NamedFile::open(Path::new("static/").join(file)).ok().map(|nf| CachedFile(nf))
+    NamedFile::open(Path::new("static/").join(file)).ok().map(CachedFile)
 }
 
 fn main() {
+    autocomplete::load_all();
     rocket::ignite()
         .attach(Template::fairing())
         // .mount("/static", StaticFiles::from("./static"))
         .mount(
             "/",
-            routes![index_view, autocomplete_view, empty_search_view, search_view, project_view, project_logo_view, project_logo_svg_view, robots_txt_view, files],
+            routes![index_view, autocomplete_view, empty_search_view, search_view, search_api, symbols_at_view, timeline_view, project_view, breaks_view, breaks_api, compare_view, compare_api, release_view, stats_view, stats_api, federated_view, federated_api, surface_view, surface_api, source_view, blame_view, blame_api, usages_view, usages_api, code_search_view, code_search_api, definition_diff_view, lifetime_api, compat_header_view, project_logo_view, project_logo_svg_view, robots_txt_view, files],
         )
         .launch();
 }
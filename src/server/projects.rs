@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+use crate::autocomplete::Completions;
+use crate::code_index::CodeIndex;
+use crate::data::ProjectRepo;
+use crate::diff_store::DiffStore;
+use crate::name_index::NameIndex;
+
+/**
+ * A file of a project's database as it was when it was last loaded, `None` if it wasn't there
+ */
+struct Loaded<T> {
+    modified: Option<SystemTime>,
+    value: Option<Arc<T>>,
+}
+
+type Cache<T> = RwLock<HashMap<String, Loaded<T>>>;
+
+lazy_static! {
+    static ref PROJECTS: RwLock<HashMap<String, ProjectRepo>> = RwLock::new(HashMap::new());
+    static ref NAME_INDEXES: Cache<NameIndex> = RwLock::new(HashMap::new());
+    static ref DIFF_STORES: Cache<DiffStore> = RwLock::new(HashMap::new());
+    static ref CODE_INDEXES: Cache<CodeIndex> = RwLock::new(HashMap::new());
+    static ref COMPLETIONS: Cache<Completions> = RwLock::new(HashMap::new());
+}
+
+/// Every project in `sourcedigger-db`, sorted by name
+pub fn list() -> Vec<String> {
+    let mut projects = match std::fs::read_dir("sourcedigger-db") {
+        Ok(read_dir) => read_dir
+            .map(|entry| entry.unwrap())
+            .filter(|entry| entry.path().join("config.toml").exists())
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>(),
+        Err(_) => vec![],
+    };
+    projects.sort();
+    projects
+}
+
+/// The project's `config.toml`, cached after the first read
+pub fn get(project: &str) -> ProjectRepo {
+    let project_data: Option<ProjectRepo> = PROJECTS.read().unwrap().get(project).cloned();
+    if let Some(data) = project_data {
+        data
+    } else {
+        let mut projects = PROJECTS.write().unwrap();
+
+        let project_data = ProjectRepo::load(&Path::new("sourcedigger-db").join(project)).unwrap();
+        projects.insert(project.to_string(), project_data.clone());
+        project_data
+    }
+}
+
+/// The modification time of a database file, which the indexer replaces rather than modifies
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+/// The cached `file_name` of a project, loaded again when it was replaced since
+fn load_cached<T, F: Fn(&Path) -> Option<T>>(cache: &Cache<T>, project: &str, file_name: &str, load: F) -> Option<Arc<T>> {
+    let db_path = Path::new("sourcedigger-db").join(project);
+    let modified = modified(&db_path.join(file_name));
+    if let Some(loaded) = cache.read().unwrap().get(project) {
+        if loaded.modified == modified {
+            return loaded.value.clone();
+        }
+    }
+    let mut cache = cache.write().unwrap();
+
+    let value = load(&db_path).map(Arc::new);
+    cache.insert(project.to_string(), Loaded { modified, value: value.clone() });
+    value
+}
+
+/// The project's symbol name index. `None` if it wasn't built yet.
+pub fn name_index(project: &str) -> Option<Arc<NameIndex>> {
+    load_cached(&NAME_INDEXES, project, "name_index", NameIndex::load)
+}
+
+/// The project's binary diffs, mapped into memory. `None` if they weren't converted yet.
+pub fn diff_store(project: &str) -> Option<Arc<DiffStore>> {
+    load_cached(&DIFF_STORES, project, "diffs.bin", DiffStore::open)
+}
+
+/// The project's trigram index of its sources, mapped into memory. `None` if it wasn't built.
+pub fn code_index(project: &str) -> Option<Arc<CodeIndex>> {
+    // New segments are renamed into the directory, which updates its modification time
+    load_cached(&CODE_INDEXES, project, "code_index", CodeIndex::open)
+}
+
+/// The project's symbol names for autocompletion, ranked by the `lifetimes` file
+pub fn completions(project: &str) -> Arc<Completions> {
+    load_cached(&COMPLETIONS, project, "lifetimes", |db_path| Some(Completions::load(db_path))).unwrap()
+}
+
+impl ProjectRepo {
+    pub fn source_link(&self, tag_name: &str, file: &str, line: u64) -> String {
+        self.source_viewer
+            .replace("{tag}", tag_name)
+            .replace("{path}", file)
+            .replace("{line}", &format!("{}", line))
+    }
+}
@@ -0,0 +1,245 @@
+use regex::Regex;
+
+/// Search box queries may be globs, e.g. `insert*` means `insert.*`
+pub fn expand_glob(query: &str) -> String {
+    let query_expander = Regex::new("(^|[^.\\]])([*+])").unwrap();
+    query_expander.replace_all(query, "$1.$2").to_string()
+}
+
+/// Turns a search box query (a glob or a regex) into a regex matching whole symbol names.
+/// Like `rg --smart-case`, it's case insensitive unless the query has uppercase letters.
+pub fn name_regex(query: &str) -> Result<Regex, regex::Error> {
+    let query = expand_glob(query);
+    let flags = if query.chars().any(char::is_uppercase) { "" } else { "(?i)" };
+    Regex::new(&format!("{}^(?:{})$", flags, query))
+}
+
+/// The literal text every name matching the query starts with, for narrowing down index lookups
+pub fn literal_prefix(query: &str) -> String {
+    let query = expand_glob(query);
+    if query.contains('|') {
+        return String::new();
+    }
+    let mut prefix = query.chars()
+        .take_while(|c| c.is_alphanumeric() || *c == '_')
+        .collect::<String>();
+    // A quantifier makes the last character optional
+    if let Some('?') | Some('*') | Some('{') = query[prefix.len()..].chars().next() {
+        prefix.pop();
+    }
+    prefix
+}
+
+/**
+ * A search box query split into its fields, e.g.
+ * `name:kmalloc* kind:f action:+ path:mm/ since:v4.0 until:v5.0 sig:gfp_t`.
+ * A word without a field is the pattern, which is the name unless searching signatures,
+ * so plain globs and regexes still work.
+ */
+#[derive(Debug, Default, PartialEq)]
+pub struct QueryFields {
+    pub pattern: Option<String>,
+    pub name: Option<String>,
+    /// Symbol type letters, any of "fvdsx"
+    pub kinds: Option<String>,
+    /// Action letters, any of "arm"
+    pub actions: Option<String>,
+    /// File path globs, a record has to be in one of them
+    pub paths: Vec<String>,
+    /// File path globs from `-path:`, a record can't be in any of them
+    pub excluded_paths: Vec<String>,
+    pub since: Option<String>,
+    pub until: Option<String>,
+    /// Text the signature has to contain
+    pub signature: Option<String>,
+}
+
+/// Splits on whitespace, except inside double quotes, e.g. `sig:"gfp_t flags"`
+fn tokenize(query: &str) -> Result<Vec<String>, String> {
+    let mut tokens = vec![];
+    let mut token = String::new();
+    let mut in_quotes = false;
+    for c in query.chars() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            c if c.is_whitespace() && !in_quotes => {
+                if !token.is_empty() {
+                    tokens.push(std::mem::take(&mut token));
+                }
+            }
+            c => token.push(c),
+        }
+    }
+    if in_quotes {
+        return Err("Unterminated quote".to_string());
+    }
+    if !token.is_empty() {
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+fn parse_kinds(value: &str) -> Result<String, String> {
+    let mut kinds = String::new();
+    for kind in value.split(',') {
+        let kind_lower = kind.to_lowercase();
+        let letters = match kind_lower.as_str() {
+            "function" | "functions" => "f",
+            "define" | "defines" | "macro" | "macros" => "d",
+            "variable" | "variables" => "v",
+            "struct" | "structs" => "s",
+            "export" | "exports" => "x",
+            letters if !letters.is_empty() && letters.chars().all(|c| "fdvsx".contains(c)) => letters,
+            _ => return Err(format!(
+                "Unknown kind `{}`, expected function, define, variable, struct, export or their letters f, d, v, s, x",
+                kind
+            )),
+        };
+        kinds += letters;
+    }
+    Ok(kinds)
+}
+
+fn parse_actions(value: &str) -> Result<String, String> {
+    let mut actions = String::new();
+    for action in value.split(',') {
+        actions += match action.to_lowercase().as_str() {
+            "+" | "a" | "add" | "added" => "a",
+            "-" | "r" | "remove" | "removed" => "r",
+            "~" | "m" | "modify" | "modified" => "m",
+            _ => return Err(format!("Unknown action `{}`, expected + (added), - (removed) or ~ (modified)", action)),
+        };
+    }
+    Ok(actions)
+}
+
+pub fn parse_fields(query: &str) -> Result<QueryFields, String> {
+    let field_regex = Regex::new("^(-?[a-z]+):(.*)$").unwrap();
+    let mut fields = QueryFields::default();
+    for token in tokenize(query)? {
+        let (field, value) = match field_regex.captures(&token) {
+            Some(caps) => (caps[1].to_string(), caps[2].to_string()),
+            None => (String::new(), token.clone()),
+        };
+        if value.is_empty() {
+            return Err(format!("`{}:` needs a value", field));
+        }
+        let duplicate = match field.as_str() {
+            "" => fields.pattern.replace(value).is_some(),
+            "name" => fields.name.replace(value).is_some(),
+            "kind" => fields.kinds.replace(parse_kinds(&value)?).is_some(),
+            "action" => fields.actions.replace(parse_actions(&value)?).is_some(),
+            "path" => {
+                path_glob(&value)?;
+                fields.paths.push(value);
+                false
+            }
+            "-path" => {
+                path_glob(&value)?;
+                fields.excluded_paths.push(value);
+                false
+            }
+            "since" => fields.since.replace(value).is_some(),
+            "until" => fields.until.replace(value).is_some(),
+            "sig" => fields.signature.replace(value).is_some(),
+            _ => return Err(format!(
+                "Unknown field `{}:`, expected name:, kind:, action:, path:, -path:, since:, until: or sig:",
+                field
+            )),
+        };
+        if duplicate && field.is_empty() {
+            return Err("Only one pattern is allowed, use `|` to search for several".to_string());
+        } else if duplicate {
+            return Err(format!("`{}:` is given more than once", field));
+        }
+    }
+    if let Some(name) = &fields.name {
+        name_regex(name).map_err(|e| format!("Invalid name pattern `{}`: {}", name, e))?;
+    }
+    Ok(fields)
+}
+
+/// Turns a file path glob into a regex. `*` doesn't cross directories, `**` does.
+/// A glob without wildcards, or ending with `/`, matches everything under that directory,
+/// e.g. `drivers/net` or `fs/*/`.
+pub fn path_glob(glob: &str) -> Result<Regex, String> {
+    let mut regex = "^".to_string();
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                regex += ".*";
+            }
+            '*' => regex += "[^/]*",
+            '?' => regex += "[^/]",
+            c => regex += &regex::escape(&c.to_string()),
+        }
+    }
+    if glob.ends_with('/') {
+        // A directory, anything under it
+    } else if !glob.contains(['*', '?']) {
+        regex += "(/|$)";
+    } else {
+        regex += "$";
+    }
+    Regex::new(&regex).map_err(|e| format!("Invalid path glob `{}`: {}", glob, e))
+}
+
+/// Signature patterns are regexes matching anywhere in the signature, smart-case like names
+pub fn signature_regex(pattern: &str) -> Result<Regex, String> {
+    let flags = if pattern.chars().any(char::is_uppercase) { "" } else { "(?i)" };
+    Regex::new(&format!("{}{}", flags, pattern))
+        .map_err(|e| format!("Invalid signature pattern `{}`: {}", pattern, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fields_are_split() {
+        let fields = parse_fields("kmalloc* kind:f,Define action:+,~ path:mm/ sig:\"gfp_t flags\" since:v4.0").unwrap();
+        assert_eq!(fields, QueryFields {
+            pattern: Some("kmalloc*".to_string()),
+            kinds: Some("fd".to_string()),
+            actions: Some("am".to_string()),
+            paths: vec!["mm/".to_string()],
+            since: Some("v4.0".to_string()),
+            signature: Some("gfp_t flags".to_string()),
+            ..Default::default()
+        });
+    }
+
+    #[test]
+    fn invalid_fields_are_rejected() {
+        assert!(parse_fields("a b").is_err());
+        assert!(parse_fields("since:v1 since:v2").is_err());
+        assert!(parse_fields("kind:").is_err());
+        assert!(parse_fields("color:red").is_err());
+        assert!(parse_fields("sig:\"gfp_t").is_err());
+        assert!(parse_fields("name:(").is_err());
+    }
+
+    #[test]
+    fn kinds_are_letters() {
+        assert_eq!(parse_kinds("functions,macro,x"), Ok("fdx".to_string()));
+        assert_eq!(parse_kinds("FV"), Ok("fv".to_string()));
+        assert!(parse_kinds("fz").is_err());
+        assert!(parse_kinds("f,").is_err());
+    }
+
+    #[test]
+    fn path_globs_match_directories() {
+        let matches = |glob: &str, path: &str| path_glob(glob).unwrap().is_match(path);
+        assert!(matches("drivers/net", "drivers/net/loopback.c"));
+        assert!(matches("drivers/net", "drivers/net"));
+        assert!(!matches("drivers/net", "drivers/netdevsim/dev.c"));
+        assert!(matches("fs/*/", "fs/ext4/inode.c"));
+        assert!(!matches("fs/*.c", "fs/ext4/inode.c"));
+        assert!(matches("fs/**.c", "fs/ext4/inode.c"));
+        assert!(matches("mm/slab?.c", "mm/slab2.c"));
+        assert!(!matches("a.c", "abc"));
+        assert_eq!(parse_fields("-path:mm/kasan").unwrap().excluded_paths, vec!["mm/kasan"]);
+    }
+}
@@ -0,0 +1,264 @@
+use crate::history::{self, DiffAction, DiffRecord};
+use crate::version_stats::top_level_directory;
+use crate::{diffs, html, projects};
+use ctags::SymbolType;
+use std::path::Path;
+
+/// The kinds in the order they're listed, with their headings
+const KINDS: [(SymbolType, &str); 5] = [
+    (SymbolType::Function, "Functions"),
+    (SymbolType::Struct, "Structs"),
+    (SymbolType::Define, "Defines"),
+    (SymbolType::Variable, "Variables"),
+    (SymbolType::Export, "Exports"),
+];
+
+const ACTIONS: [(DiffAction, &str); 3] = [
+    (DiffAction::Add, "Added"),
+    (DiffAction::Modify, "Modified"),
+    (DiffAction::Remove, "Removed"),
+];
+
+/**
+ * One version's diff, as a changelog of its symbols
+ */
+pub struct ReleaseNotes {
+    pub version: String,
+    pub previous: Option<String>,
+    pub next: Option<String>,
+    pub counts: Counts,
+    pub groups: Vec<ReleaseGroup>,
+}
+
+/**
+ * The records under one top-level directory, sorted by kind, action and name
+ */
+pub struct ReleaseGroup {
+    /// Empty for files at the top level
+    pub directory: String,
+    pub counts: Counts,
+    pub records: Vec<DiffRecord>,
+}
+
+/**
+ * Record counts by kind (in `KINDS` order) and action (in `ACTIONS` order)
+ */
+#[derive(Default)]
+pub struct Counts {
+    counts: [[usize; 3]; 5],
+}
+
+impl Counts {
+    fn add(&mut self, record: &DiffRecord) {
+        if let (Some(kind), Some(action)) = (kind_idx(record.symbol_type), action_idx(record.action)) {
+            self.counts[kind][action] += 1;
+        }
+    }
+
+    fn action_total(&self, action: usize) -> usize {
+        self.counts.iter().map(|by_action| by_action[action]).sum()
+    }
+
+    fn kind_total(&self, kind: usize) -> usize {
+        self.counts[kind].iter().sum()
+    }
+
+    fn total(&self) -> usize {
+        (0..ACTIONS.len()).map(|action| self.action_total(action)).sum()
+    }
+
+    /// e.g. "+12 ~3 -4"
+    fn summary(&self) -> String {
+        format!("+{} ~{} -{}", self.action_total(0), self.action_total(1), self.action_total(2))
+    }
+}
+
+fn kind_idx(symbol_type: SymbolType) -> Option<usize> {
+    KINDS.iter().position(|(kind, _)| *kind == symbol_type)
+}
+
+fn action_idx(action: DiffAction) -> Option<usize> {
+    ACTIONS.iter().position(|(a, _)| *a == action)
+}
+
+impl ReleaseNotes {
+    pub fn load(project: &str, version: &str) -> Result<Self, String> {
+        let db_path = Path::new("sourcedigger-db").join(project);
+        let versions = history::read_versions(&db_path);
+        let idx = history::version_index(&versions, version).ok_or_else(|| format!("Unknown version: {}", version))?;
+        Ok(Self::from_records(
+            versions[idx].clone(),
+            idx.checked_sub(1).map(|i| versions[i].clone()),
+            versions.get(idx + 1).cloned(),
+            history::read_diff(&db_path, &versions[idx]),
+        ))
+    }
+
+    fn from_records(version: String, previous: Option<String>, next: Option<String>, mut records: Vec<DiffRecord>) -> Self {
+        records.sort_by(|a, b| {
+            (top_level_directory(&a.data.file), kind_idx(a.symbol_type), action_idx(a.action), &a.name)
+                .cmp(&(top_level_directory(&b.data.file), kind_idx(b.symbol_type), action_idx(b.action), &b.name))
+        });
+
+        let mut notes = ReleaseNotes { version, previous, next, counts: Counts::default(), groups: vec![] };
+        for record in records {
+            notes.counts.add(&record);
+            let directory = top_level_directory(&record.data.file);
+            if notes.groups.last().is_none_or(|group| group.directory != directory) {
+                notes.groups.push(ReleaseGroup { directory: directory.to_string(), counts: Counts::default(), records: vec![] });
+            }
+            let group = notes.groups.last_mut().unwrap();
+            group.counts.add(&record);
+            group.records.push(record);
+        }
+        // The busiest directories first
+        notes.groups.sort_by(|a, b| b.records.len().cmp(&a.records.len()).then_with(|| a.directory.cmp(&b.directory)));
+        notes
+    }
+
+    fn since(&self) -> String {
+        match &self.previous {
+            Some(previous) => format!("since {}", previous),
+            None => "in the first indexed version".to_string(),
+        }
+    }
+
+    pub fn to_html(&self, project: &str) -> String {
+        let project_data = projects::get(project);
+        let mut output = format!(
+            "<!DOCTYPE html>\n<meta charset=\"UTF-8\">\n<title>{0} - {1} - SourceDigger</title>\n<link rel=\"stylesheet\" href=\"/static/results-inner.css\">\n<h1>{1} {0}</h1>\n<p class=summary>{2} changes {3}: <span class=a>+{4} added</span> <span class=m>~{5} modified</span> <span class=r>-{6} removed</span></p>\n",
+            html::escape(&self.version),
+            html::escape(&project_data.name),
+            self.counts.total(),
+            html::escape(&self.since()),
+            self.counts.action_total(0),
+            self.counts.action_total(1),
+            self.counts.action_total(2),
+        );
+
+        output += "<p class=release-links>";
+        if let Some(previous) = &self.previous {
+            output += &format!(
+                "<a href=\"{0}\">&larr; {1}</a> <a href=\"/{2}/compare/{0}...{3}\">compare</a> ",
+                url_escape(previous), html::escape(previous), project, url_escape(&self.version)
+            );
+        }
+        if let Some(next) = &self.next {
+            output += &format!("<a href=\"{}\">{} &rarr;</a> ", url_escape(next), html::escape(next));
+        }
+        output += &format!("<a href=\"{}?format=markdown\">markdown</a></p>\n", url_escape(&self.version));
+
+        output += "<table class=counts>\n<tr><th></th>";
+        for (_, heading) in ACTIONS.iter() {
+            output += &format!("<th>{}</th>", heading);
+        }
+        output += "</tr>\n";
+        for (kind, (_, heading)) in KINDS.iter().enumerate() {
+            if self.counts.kind_total(kind) == 0 {
+                continue;
+            }
+            output += &format!("<tr><th>{}</th>", heading);
+            for count in self.counts.counts[kind].iter() {
+                output += &format!("<td>{}</td>", count);
+            }
+            output += "</tr>\n";
+        }
+        output += "</table>\n";
+
+        for group in self.groups.iter() {
+            output += &format!(
+                "<h2 class=h>{} <small>{}</small></h2>\n",
+                if group.directory.is_empty() { "(top level)".to_string() } else { html::escape(&group.directory) },
+                group.counts.summary()
+            );
+            for record in group.records.iter() {
+                output += &diffs::record_to_html(project, &project_data, &self.version, record);
+            }
+        }
+
+        output
+    }
+
+    /// For pasting into upgrade notes, readable as plain text too
+    pub fn to_markdown(&self, project: &str) -> String {
+        let project_data = projects::get(project);
+        let mut output = format!(
+            "# {} {}\n\n{} changes {}: {} added, {} modified, {} removed\n\n",
+            project_data.name,
+            self.version,
+            self.counts.total(),
+            self.since(),
+            self.counts.action_total(0),
+            self.counts.action_total(1),
+            self.counts.action_total(2),
+        );
+
+        output += "| | Added | Modified | Removed |\n|---|---:|---:|---:|\n";
+        for (kind, (_, heading)) in KINDS.iter().enumerate() {
+            if self.counts.kind_total(kind) == 0 {
+                continue;
+            }
+            let counts = &self.counts.counts[kind];
+            output += &format!("| {} | {} | {} | {} |\n", heading, counts[0], counts[1], counts[2]);
+        }
+
+        for group in self.groups.iter() {
+            output += &format!(
+                "\n## {} ({})\n",
+                if group.directory.is_empty() { "(top level)" } else { group.directory.as_str() },
+                group.counts.summary()
+            );
+            let mut last_heading = None;
+            for record in group.records.iter() {
+                let heading = (kind_idx(record.symbol_type), action_idx(record.action));
+                if last_heading != Some(heading) {
+                    last_heading = Some(heading);
+                    if let (Some(kind), Some(action)) = heading {
+                        output += &format!("\n### {} {}\n\n", ACTIONS[action].1, KINDS[kind].1.to_lowercase());
+                    }
+                }
+                output += &format!(
+                    "- `{}` ({}:{})\n",
+                    history::format_signature(&record.name, record.symbol_type, &record.data.extra).replace('`', "'"),
+                    record.data.file,
+                    record.data.line
+                );
+            }
+        }
+
+        output
+    }
+}
+
+fn url_escape(s: &str) -> String {
+    url::form_urlencoded::byte_serialize(s.as_bytes()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn groups_and_counts() {
+        let records = [
+            "a\tkmalloc\tFunction\tmm/slab.c\t1\tvoid *{name}(size_t)",
+            "r\told_fn\tFunction\tdrivers/a.c\t1\tint {name}(void)",
+            "a\tnew_fn\tFunction\tdrivers/b.c\t1\tint {name}(void)",
+            "m\tpage\tStruct\tdrivers/c.c\t1\tint a;",
+            "a\tVERSION\tDefine\tMakefile\t1\t",
+        ].iter().map(|line| DiffRecord::parse(line).unwrap()).collect();
+        let notes = ReleaseNotes::from_records("v2".to_string(), Some("v1".to_string()), None, records);
+
+        assert_eq!((notes.counts.total(), notes.counts.summary()), (5, "+3 ~1 -1".to_string()));
+        assert_eq!(notes.counts.counts[kind_idx(SymbolType::Function).unwrap()], [2, 0, 1]);
+        let groups = notes.groups.iter()
+            .map(|group| (group.directory.as_str(), group.counts.summary(), group.records.iter().map(|r| r.name.as_str()).collect::<Vec<_>>()))
+            .collect::<Vec<_>>();
+        // The busiest directory first, then by kind, action and name
+        assert_eq!(groups, vec![
+            ("drivers/", "+1 ~1 -1".to_string(), vec!["new_fn", "old_fn", "page"]),
+            ("", "+1 ~0 -0".to_string(), vec!["VERSION"]),
+            ("mm/", "+1 ~0 -0".to_string(), vec!["kmalloc"]),
+        ]);
+    }
+}
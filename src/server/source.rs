@@ -0,0 +1,121 @@
+use crate::highlight::{self, CHighlighter};
+use crate::{autocomplete, html, projects, version_files};
+use git2::{ObjectType, Oid, Repository, Tree};
+use std::path::Path;
+
+/// Files bigger than this are shown without highlighting
+const MAX_HIGHLIGHTED_SIZE: usize = 2 * 1024 * 1024;
+
+/// The bare repository from the project's `repo_path`, `None` if it has none
+fn open_repo(project: &str) -> Result<Option<Repository>, String> {
+    match projects::get(project).repo_path {
+        Some(repo_path) => Repository::open_bare(&repo_path)
+            .map(Some)
+            .map_err(|e| format!("Failed to open the repository of {}: {}", project, e)),
+        None => Ok(None),
+    }
+}
+
+/// Whether the files can be read, from the repository or else from the extracted `objects/`
+pub fn has_sources(project: &str) -> bool {
+    projects::get(project).repo_path.is_some() || Path::new("sourcedigger-db").join(project).join("objects").is_dir()
+}
+
+/// Versions are the last component of their tag's name, e.g. `v1.0` for `release/v1.0`,
+/// so tags in a namespace are found by looking through the tags
+fn version_tree<'repo>(repo: &'repo Repository, version: &str) -> Result<Tree<'repo>, String> {
+    let tag_name = match repo.find_reference(&format!("refs/tags/{}", version)) {
+        Ok(_) => version.to_string(),
+        Err(_) => repo.tag_names(None)
+            .map_err(|e| e.to_string())?
+            .iter()
+            .flatten()
+            .find(|tag| tag.rsplit('/').next() == Some(version))
+            .map(String::from)
+            .ok_or_else(|| format!("Unknown version: {}", version))?,
+    };
+    repo.find_reference(&format!("refs/tags/{}", tag_name))
+        .and_then(|reference| reference.peel_to_tree())
+        .map_err(|e| format!("Failed to read {}: {}", version, e))
+}
+
+fn find_blob(repo: &Repository, version: &str, path: &str) -> Result<Oid, String> {
+    let tree = version_tree(repo, version)?;
+    let entry = tree.get_path(std::path::Path::new(path)).map_err(|_| format!("No {} in {}", path, version))?;
+    if entry.kind() != Some(ObjectType::Blob) {
+        return Err(format!("{} isn't a file", path));
+    }
+    Ok(entry.id())
+}
+
+/// Without the repository, paths are looked up in the version's `files/` list
+fn find_extracted_blob(project: &str, version: &str, path: &str) -> Result<String, String> {
+    let db_path = Path::new("sourcedigger-db").join(project);
+    if !db_path.join("objects").is_dir() {
+        return Err(format!("{} has neither a `repo_path` nor extracted sources, so its sources can't be shown", project));
+    }
+    version_files::read_version_files(&db_path, version)
+        .ok_or_else(|| format!("The file list of {} wasn't written, updating the index writes it", version))?
+        .into_iter()
+        .find(|(file, _)| file == path)
+        .map(|(_, oid)| oid)
+        .ok_or_else(|| format!("No {} in {}", path, version))
+}
+
+/// The hex oid of the blob of `path` at `version`, which names its `tags/` and `objects/` files
+pub fn blob_id(project: &str, version: &str, path: &str) -> Result<String, String> {
+    match open_repo(project)? {
+        Some(repo) => find_blob(&repo, version, path).map(|oid| oid.to_string()),
+        None => find_extracted_blob(project, version, path),
+    }
+}
+
+/// The contents of `path` at `version`
+pub fn read_file(project: &str, version: &str, path: &str) -> Result<Vec<u8>, String> {
+    match open_repo(project)? {
+        Some(repo) => {
+            let blob = repo.find_blob(find_blob(&repo, version, path)?).map_err(|e| e.to_string())?;
+            Ok(blob.content().to_vec())
+        }
+        None => {
+            let oid = find_extracted_blob(project, version, path)?;
+            std::fs::read(Path::new("sourcedigger-db").join(project).join("objects").join(&oid))
+                .map_err(|e| format!("Failed to read {} at {}: {}", path, version, e))
+        }
+    }
+}
+
+/// Line numbered, with the project's known symbols linking to their search
+pub fn to_html(project: &str, version: &str, path: &str, content: &[u8]) -> String {
+    let mut output = format!(
+        "<!DOCTYPE html>\n<meta charset=\"UTF-8\">\n<title>{1} - {0} - SourceDigger</title>\n<link rel=\"stylesheet\" href=\"/static/results-inner.css\">\n<h1 class=source>{1} <small>{0}</small></h1>\n<p class=release-links><a href=\"/{2}/blame/{3}/{4}\">blame</a></p>\n",
+        html::escape(version),
+        html::escape(path),
+        project,
+        url::form_urlencoded::byte_serialize(version.as_bytes()).collect::<String>(),
+        html::escape(path),
+    );
+    if content.contains(&0) {
+        return output + "<p class=summary>Binary file</p>\n";
+    }
+
+    let text = String::from_utf8_lossy(content);
+    let highlight = highlight::is_c_like(path) && content.len() <= MAX_HIGHLIGHTED_SIZE;
+    let completions = autocomplete::get(project);
+    let link = |identifier: &str| {
+        if completions.contains(identifier) {
+            Some(format!("/{}?q={}", project, identifier))
+        } else {
+            None
+        }
+    };
+    let mut highlighter = CHighlighter::default();
+
+    output += "<pre class=source>";
+    for (i, line) in text.lines().enumerate() {
+        let code = if highlight { highlighter.line(line, link) } else { html::escape(line) };
+        output += &format!("<span class=line id=L{0}><a class=ln href=#L{0}>{0}</a>{1}</span>\n", i + 1, code);
+    }
+    output += "</pre>\n";
+    output
+}
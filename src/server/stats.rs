@@ -0,0 +1,157 @@
+use crate::{history, html};
+use crate::version_stats::{self, KindStats};
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// The kinds charted on the stats page, with their line colors
+const CHARTED_KINDS: [(&str, &str); 5] = [
+    ("Function", "#aff"),
+    ("Struct", "#adf"),
+    ("Define", "#ffa"),
+    ("Variable", "#daf"),
+    ("Export", "#fda"),
+];
+
+const TOP_CHURN: usize = 10;
+
+/**
+ * A version's counts by kind, as returned by the API
+ */
+#[derive(Serialize)]
+pub struct VersionStats {
+    pub version: String,
+    /// Definitions of every kind
+    pub total: usize,
+    /// Records in the version's diff
+    pub churn: usize,
+    pub kinds: BTreeMap<String, KindStats>,
+    /// The same by top-level directory, only when asked for
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub directories: Option<BTreeMap<String, BTreeMap<String, KindStats>>>,
+}
+
+/// Every version's stats in index order, optionally only those of one top-level directory
+pub fn load(project: &str, directory: Option<&str>, with_directories: bool) -> Result<Vec<VersionStats>, String> {
+    assert!(!project.contains("/"));
+    let db_path = Path::new("sourcedigger-db").join(project);
+    let lines = version_stats::read_stats(&db_path)
+        .ok_or_else(|| format!("No statistics for {}, they're written by the indexer", project))?;
+
+    // Every version gets a row, with zero counts where nothing was in the directory
+    let mut versions = history::read_versions(&db_path).into_iter()
+        .map(|version| VersionStats {
+            version,
+            total: 0,
+            churn: 0,
+            kinds: BTreeMap::new(),
+            directories: if with_directories { Some(BTreeMap::new()) } else { None },
+        })
+        .collect::<Vec<_>>();
+    let mut idx = 0;
+    for line in lines {
+        if directory.is_some_and(|directory| directory != line.directory) {
+            continue;
+        }
+        // Lines are in version order, so the row is found by moving forward
+        while idx < versions.len() && versions[idx].version != line.version {
+            idx += 1;
+        }
+        let version = match versions.get_mut(idx) {
+            Some(version) => version,
+            None => break,
+        };
+        version.total += line.stats.total;
+        version.churn += line.stats.churn();
+        version.kinds.entry(line.kind.clone()).or_default().add(&line.stats);
+        if let Some(directories) = &mut version.directories {
+            directories.entry(line.directory).or_default().insert(line.kind, line.stats);
+        }
+    }
+    Ok(versions)
+}
+
+/// A line per kind, scaled to the biggest count of that kind
+fn chart_svg(versions: &[VersionStats], kind: &str, color: &str) -> String {
+    let (width, height) = (1000.0, 60.0);
+    let counts = versions.iter()
+        .map(|v| v.kinds.get(kind).map_or(0, |k| k.total))
+        .collect::<Vec<_>>();
+    let max = counts.iter().copied().max().unwrap_or(0).max(1) as f64;
+    let step = width / (counts.len().max(2) - 1) as f64;
+    let points = counts.iter().enumerate()
+        .map(|(i, count)| format!("{:.1},{:.1}", i as f64 * step, height - *count as f64 / max * height))
+        .collect::<Vec<_>>()
+        .join(" ");
+    format!(
+        "<svg viewBox=\"0 0 {} {}\" preserveAspectRatio=none><polyline fill=none stroke=\"{}\" stroke-width=2 vector-effect=non-scaling-stroke points=\"{}\"/></svg>",
+        width, height, color, points
+    )
+}
+
+pub fn to_html(project: &str, directory: Option<&str>, versions: &[VersionStats]) -> String {
+    let mut output = format!(
+        "<!DOCTYPE html>\n<meta charset=\"UTF-8\">\n<title>Statistics - {0} - SourceDigger</title>\n<link rel=\"stylesheet\" href=\"/static/results-inner.css\">\n<h1>{0} statistics{1}</h1>\n",
+        html::escape(project),
+        directory.map(|d| format!(" for {}", html::escape(d))).unwrap_or_default(),
+    );
+    let (first, latest) = match (versions.first(), versions.last()) {
+        (Some(first), Some(latest)) => (first, latest),
+        _ => return output + "<p class=summary>Nothing indexed yet</p>\n",
+    };
+
+    output += &format!(
+        "<p class=summary>{} versions from {} to {}, <a href=\"/api/{}/stats\">as JSON</a></p>\n<div class=charts>\n",
+        versions.len(),
+        html::escape(&first.version),
+        html::escape(&latest.version),
+        project
+    );
+    for (kind, color) in CHARTED_KINDS.iter() {
+        let latest_count = latest.kinds.get(*kind).map_or(0, |k| k.total);
+        if versions.iter().all(|v| v.kinds.get(*kind).is_none_or(|k| k.total == 0)) {
+            continue;
+        }
+        output += &format!("<h3>{}s: {}</h3>\n{}\n", kind, latest_count, chart_svg(versions, kind, color));
+    }
+    output += "</div>\n";
+
+    let mut by_churn = versions.iter().collect::<Vec<_>>();
+    by_churn.sort_by_key(|version| Reverse(version.churn));
+    output += "<h2>Most changes</h2>\n<table class=counts>\n<tr><th>Version</th><th>Changes</th><th>Added</th><th>Removed</th><th>Modified</th></tr>\n";
+    for version in by_churn.iter().take(TOP_CHURN) {
+        let mut sum = KindStats::default();
+        for stats in version.kinds.values() {
+            sum.add(stats);
+        }
+        output += &format!(
+            "<tr><th><a href=\"/{0}/release/{1}\">{2}</a></th><td>{3}</td><td>{4}</td><td>{5}</td><td>{6}</td></tr>\n",
+            project,
+            url::form_urlencoded::byte_serialize(version.version.as_bytes()).collect::<String>(),
+            html::escape(&version.version),
+            version.churn,
+            sum.added,
+            sum.removed,
+            sum.modified
+        );
+    }
+    output += "</table>\n";
+
+    if directory.is_none() {
+        if let Some(directories) = &latest.directories {
+            output += "<h2>Directories</h2>\n<ul class=facets>\n";
+            for (name, kinds) in directories.iter().filter(|(name, _)| !name.is_empty()) {
+                output += &format!(
+                    "<li><a href=\"?directory={}\">{}</a> ({})</li>\n",
+                    url::form_urlencoded::byte_serialize(name.as_bytes()).collect::<String>(),
+                    html::escape(name),
+                    kinds.values().map(|k| k.total).sum::<usize>()
+                );
+            }
+            output += "</ul>\n";
+        }
+    }
+
+    output
+}
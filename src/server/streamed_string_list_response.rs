@@ -12,6 +12,7 @@ pub struct StreamedStringListResponse {
     buffer: VecDeque<u8>,
     start: Instant,
     counter: u64,
+    content_type: ContentType,
 }
 
 // Source: https://github.com/SergioBenitez/Rocket/issues/1298#issuecomment-629516104
@@ -24,7 +25,7 @@ impl Read for StreamedStringListResponse {
                     .iter()
                     .for_each(|c| self.buffer.push_back(*c)),
                 Err(TryRecvError::Empty) => {
-                    if self.buffer.len() == 0 {
+                    if self.buffer.is_empty() {
                         if let Ok(chunk) = self.source.recv() {
                             for c in chunk.as_bytes().iter() {
                                 self.buffer.push_back(*c);
@@ -40,7 +41,7 @@ impl Read for StreamedStringListResponse {
             }
         }
         // If we reached here and the buffer's empty - then the channel is closed.
-        if self.buffer.len() == 0 {
+        if self.buffer.is_empty() {
             println!("streamed {} bytes in {:?}", self.counter, self.start.elapsed());
             return Ok(0);
         }
@@ -61,11 +62,16 @@ impl Read for StreamedStringListResponse {
 
 impl StreamedStringListResponse {
     pub fn new(source: Receiver<String>) -> Self {
+        Self::with_content_type(source, ContentType::HTML)
+    }
+
+    pub fn with_content_type(source: Receiver<String>, content_type: ContentType) -> Self {
         StreamedStringListResponse {
             source,
             buffer: VecDeque::with_capacity(4096),
             start: Instant::now(),
             counter: 0,
+            content_type,
         }
     }
 }
@@ -73,7 +79,7 @@ impl StreamedStringListResponse {
 impl<'r> Responder<'r> for StreamedStringListResponse {
     fn respond_to(self, _: &Request) -> response::Result<'r> {
         Response::build()
-            .header(ContentType::HTML)
+            .header(self.content_type.clone())
             .raw_header("Cache-Control", "max-age=604800") // 7*24*60*60
             .streamed_body(self)
             .ok()
@@ -0,0 +1,355 @@
+use crate::history::{self, SymbolKey, SymbolSet};
+use crate::{html, projects, query};
+use regex::Regex;
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::path::Path;
+
+/**
+ * One side of a comparison, given as `project` or `project:version`. Git doesn't allow `:` in tag names.
+ */
+#[derive(Serialize)]
+pub struct SurfaceSide {
+    pub project: String,
+    pub version: String,
+}
+
+impl SurfaceSide {
+    /// Without a version, the latest one
+    fn parse(side: &str) -> Result<Self, String> {
+        let mut parts = side.splitn(2, ':');
+        let project = parts.next().unwrap_or("").to_string();
+        if project.is_empty() || project.contains('/') || !projects::list().contains(&project) {
+            return Err(format!("Unknown project: {}", project));
+        }
+        let versions = history::read_versions(&Path::new("sourcedigger-db").join(&project));
+        let version = match parts.next() {
+            Some(version) => versions[history::version_index(&versions, version)
+                .ok_or_else(|| format!("Unknown version of {}: {}", project, version))?].clone(),
+            None => versions.last().cloned().ok_or_else(|| format!("{} has no indexed versions", project))?,
+        };
+        Ok(SurfaceSide { project, version })
+    }
+
+    fn symbols(&self, filter: &SurfaceQuery) -> SymbolSet {
+        let db_path = Path::new("sourcedigger-db").join(&self.project);
+        let versions = history::read_versions(&db_path);
+        let idx = history::version_index(&versions, &self.version).unwrap();
+        history::symbols_at(&db_path, &versions, idx, |record| {
+            filter.types.contains(history::symbol_type_letter(record.symbol_type))
+                && filter.name_regex.as_ref().is_none_or(|r| r.is_match(&record.name))
+        })
+    }
+}
+
+/**
+ * Which symbols to compare and how
+ */
+pub struct SurfaceQuery {
+    name_regex: Option<Regex>,
+    /// Symbol type letters, any of "fvdsx"
+    types: String,
+    /// Compare signatures as they're written, instead of ignoring parameter names and `restrict`
+    exact: bool,
+}
+
+impl SurfaceQuery {
+    pub fn parse(q: Option<String>, t: Option<String>, exact: Option<bool>) -> Result<Self, String> {
+        let name_regex = match q.filter(|q| !q.is_empty()) {
+            Some(q) => Some(query::name_regex(&q).map_err(|e| format!("Invalid name pattern `{}`: {}", q, e))?),
+            None => None,
+        };
+        Ok(SurfaceQuery {
+            name_regex,
+            types: t.unwrap_or_else(|| "fvdsx".to_string()),
+            exact: exact.unwrap_or(false),
+        })
+    }
+}
+
+/**
+ * The symbols of one side, or of both when their signatures differ
+ */
+#[derive(Serialize)]
+pub struct SurfaceSymbol {
+    pub name: String,
+    pub kind: String,
+    pub left: Vec<String>,
+    pub right: Vec<String>,
+}
+
+/**
+ * Two projects' symbols at the given versions
+ */
+#[derive(Serialize)]
+pub struct SurfaceComparison {
+    pub left: SurfaceSide,
+    pub right: SurfaceSide,
+    pub only_left: Vec<SurfaceSymbol>,
+    pub only_right: Vec<SurfaceSymbol>,
+    pub different: Vec<SurfaceSymbol>,
+    /// How many symbols both have with the same signatures
+    pub same: usize,
+}
+
+/// Builtin type words, which are never parameter names
+const TYPE_WORDS: [&str; 13] = [
+    "void", "char", "short", "int", "long", "float", "double", "signed", "unsigned", "_Bool", "struct", "union", "enum",
+];
+
+/// Words that only qualify a type, which can't make a type on their own
+const QUALIFIERS: [&str; 3] = ["const", "volatile", "register"];
+
+/// Splits a parameter list at the commas that aren't nested in parentheses
+fn split_parameters(parameters: &str) -> Vec<&str> {
+    let mut split = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in parameters.char_indices() {
+        match c {
+            '(' | '[' => depth += 1,
+            ')' | ']' => depth -= 1,
+            ',' if depth == 0 => {
+                split.push(&parameters[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    split.push(&parameters[start..]);
+    split
+}
+
+/// A function pointer without its name or its parameters' names, e.g. `int (*)(const void*)`
+/// for `int (*compar)(const void *a)`. Other parentheses, e.g. of attributes, are left as they are.
+fn function_pointer_type(parameter: &str) -> String {
+    let collapsed = parameter.split_whitespace().collect::<Vec<_>>().join(" ");
+    let (open, close) = match collapsed.find('(').and_then(|open| Some((open, open + collapsed[open..].find(')')?))) {
+        Some(bounds) => bounds,
+        None => return collapsed,
+    };
+    let declarator = collapsed[open + 1..close].trim();
+    if !declarator.starts_with('*') {
+        return collapsed;
+    }
+    let name_start = declarator.trim_end_matches(|c: char| c.is_alphanumeric() || c == '_').len();
+    let declarator = if QUALIFIERS.contains(&&declarator[name_start..]) {
+        declarator.replace(' ', "")
+    } else {
+        declarator[..name_start].replace(' ', "")
+    };
+    let rest = &collapsed[close + 1..];
+    let rest = match rest.trim().strip_prefix('(').and_then(|r| r.strip_suffix(')')) {
+        Some(parameters) => format!(
+            "({})",
+            split_parameters(parameters).into_iter().map(parameter_type).collect::<Vec<_>>().join(", ")
+        ),
+        None => rest.to_string(),
+    };
+    format!("{}({}){}", &collapsed[..open], declarator, rest)
+}
+
+/// A parameter without its name, e.g. `const char *` for `const char *restrict src`
+fn parameter_type(parameter: &str) -> String {
+    if parameter.contains('(') {
+        return function_pointer_type(parameter);
+    }
+    let without_array = match parameter.find('[') {
+        Some(idx) => &parameter[..idx],
+        None => parameter,
+    };
+    let spaced = without_array.replace('*', " * ");
+    let mut tokens = spaced.split_whitespace()
+        .filter(|t| !["restrict", "__restrict", "__restrict__"].contains(t))
+        .collect::<Vec<_>>();
+    // Without a name, e.g. `const foo_t`, the last word is part of the type
+    let type_tokens = tokens.iter().filter(|t| !QUALIFIERS.contains(t)).count();
+    if type_tokens >= 2 {
+        let last = tokens[tokens.len() - 1];
+        let before = tokens[tokens.len() - 2];
+        let is_name = last.chars().all(|c| c.is_alphanumeric() || c == '_')
+            && !TYPE_WORDS.contains(&last)
+            && !["struct", "union", "enum"].contains(&before);
+        if is_name {
+            tokens.pop();
+        }
+    }
+    tokens.join(" ").replace(" *", "*").replace("* ", "*")
+}
+
+/// What has to match for two signatures to count as the same
+fn normalize_signature(signature: &str, exact: bool) -> String {
+    let collapsed = signature.split_whitespace().collect::<Vec<_>>().join(" ");
+    if exact {
+        return collapsed;
+    }
+    let (open, close) = match (collapsed.find('('), collapsed.rfind(')')) {
+        (Some(open), Some(close)) if open < close => (open, close),
+        _ => return collapsed,
+    };
+    let parameters = split_parameters(&collapsed[open + 1..close]).into_iter()
+        .map(parameter_type)
+        .collect::<Vec<_>>()
+        .join(", ");
+    format!("{}({}){}", parameter_type(&collapsed[..open]), parameters, &collapsed[close + 1..])
+}
+
+fn signatures(symbols: &SymbolSet, key: &SymbolKey) -> Vec<String> {
+    let mut signatures = symbols.symbols.get(key).into_iter().flatten()
+        .map(|data| history::format_signature(&key.0, key.1, &data.extra))
+        .collect::<Vec<_>>();
+    signatures.sort();
+    signatures.dedup();
+    signatures
+}
+
+impl SurfaceComparison {
+    pub fn compute(left: &str, right: &str, surface_query: &SurfaceQuery) -> Result<Self, String> {
+        let left = SurfaceSide::parse(left)?;
+        let right = SurfaceSide::parse(right)?;
+        let left_symbols = left.symbols(surface_query);
+        let right_symbols = right.symbols(surface_query);
+
+        let mut comparison = SurfaceComparison { left, right, only_left: vec![], only_right: vec![], different: vec![], same: 0 };
+        let keys = left_symbols.symbols.keys().chain(right_symbols.symbols.keys()).collect::<BTreeSet<_>>();
+        for key in keys {
+            let symbol = SurfaceSymbol {
+                name: key.0.clone(),
+                kind: format!("{:?}", key.1),
+                left: signatures(&left_symbols, key),
+                right: signatures(&right_symbols, key),
+            };
+            if symbol.right.is_empty() {
+                comparison.only_left.push(symbol);
+            } else if symbol.left.is_empty() {
+                comparison.only_right.push(symbol);
+            } else {
+                let normalized = |signatures: &[String]| signatures.iter()
+                    .map(|s| normalize_signature(s, surface_query.exact))
+                    .collect::<BTreeSet<_>>();
+                if normalized(&symbol.left) == normalized(&symbol.right) {
+                    comparison.same += 1;
+                } else {
+                    comparison.different.push(symbol);
+                }
+            }
+        }
+        Ok(comparison)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    pub fn to_html(&self, q: Option<&str>, t: Option<&str>, exact: bool) -> String {
+        let left = format!("{} {}", self.left.project, self.left.version);
+        let right = format!("{} {}", self.right.project, self.right.version);
+        let mut output = format!(
+            "<!DOCTYPE html>\n<meta charset=\"UTF-8\">\n<title>{0} vs {1} - SourceDigger</title>\n<link rel=\"stylesheet\" href=\"/static/results-inner.css\">\n<h1>{0} vs {1}</h1>\n\
+            <form class=filters><input type=hidden name=left value=\"{2}:{3}\"><input type=hidden name=right value=\"{4}:{5}\">\
+            <input name=q placeholder=\"name, glob or regex\" value=\"{6}\"> <input name=t placeholder=kinds value=\"{7}\"> \
+            <label><input type=checkbox name=exact value=true{8}> exact signatures</label> <button>Filter</button></form>\n\
+            <p class=summary><span class=r>{9} only in {10}</span> <span class=a>{11} only in {12}</span> <span class=m>{13} different</span> {14} the same</p>\n",
+            html::escape(&left),
+            html::escape(&right),
+            html::escape(&self.left.project),
+            html::escape(&self.left.version),
+            html::escape(&self.right.project),
+            html::escape(&self.right.version),
+            html::escape(q.unwrap_or("")),
+            html::escape(t.unwrap_or("")),
+            if exact { " checked" } else { "" },
+            self.only_left.len(),
+            html::escape(&self.left.project),
+            self.only_right.len(),
+            html::escape(&self.right.project),
+            self.different.len(),
+            self.same,
+        );
+
+        output += &format!("<h2 class=h>Different signatures ({})</h2>\n<table class=surface>\n", self.different.len());
+        output += &format!("<tr><th></th><th>{}</th><th>{}</th></tr>\n", html::escape(&left), html::escape(&right));
+        for symbol in self.different.iter() {
+            let cell = |signatures: &[String]| signatures.iter()
+                .map(|s| format!("<code>{}</code>", html::escape(s)))
+                .collect::<Vec<_>>()
+                .join("<br>");
+            output += &format!(
+                "<tr><th>{}</th><td>{}</td><td>{}</td></tr>\n",
+                html::escape(&symbol.name),
+                cell(&symbol.left),
+                cell(&symbol.right)
+            );
+        }
+        output += "</table>\n";
+
+        for (title, class, side, symbols, project) in [
+            ("Only in", "r", &left, &self.only_left, &self.left.project),
+            ("Only in", "a", &right, &self.only_right, &self.right.project),
+        ].iter() {
+            output += &format!("<h2 class=h>{} {} ({})</h2>\n", title, html::escape(side), symbols.len());
+            for symbol in symbols.iter() {
+                let signatures = if *class == "r" { &symbol.left } else { &symbol.right };
+                output += &format!(
+                    "<div class={0}><a class={1}>{1}</a><a href=\"/{2}?q={3}\"><span></span><span>{4}</span></a>&nbsp;<code>{5}</code><hr></div>\n",
+                    class,
+                    history::symbol_type_letter(history::parse_symbol_type(&symbol.kind)),
+                    project,
+                    url::form_urlencoded::byte_serialize(symbol.name.as_bytes()).collect::<String>(),
+                    html::escape(&symbol.name),
+                    html::escape(signatures.first().map(String::as_str).unwrap_or("")),
+                );
+            }
+        }
+
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{normalize_signature, parameter_type};
+
+    #[test]
+    fn parameter_names_are_stripped() {
+        assert_eq!(parameter_type("const char *restrict src"), "const char*");
+        assert_eq!(parameter_type("int count"), "int");
+        assert_eq!(parameter_type("unsigned long"), "unsigned long");
+        assert_eq!(parameter_type("struct page *page"), "struct page*");
+        assert_eq!(parameter_type("struct page"), "struct page");
+        assert_eq!(parameter_type("char buf[16]"), "char");
+    }
+
+    #[test]
+    fn unnamed_parameters_keep_their_type() {
+        assert_eq!(parameter_type("const foo_t"), "const foo_t");
+        assert_eq!(parameter_type("size_t"), "size_t");
+        assert_eq!(parameter_type("void"), "void");
+    }
+
+    #[test]
+    fn function_pointer_names_are_stripped() {
+        assert_eq!(parameter_type("int (*compar)(const void *a, const void *b)"), "int (*)(const void*, const void*)");
+        assert_eq!(parameter_type("int (*)(const void *, const void *)"), "int (*)(const void*, const void*)");
+        assert_eq!(parameter_type("void (* const handler)(int)"), "void (*const)(int)");
+        assert_eq!(parameter_type("void (*const)(int)"), "void (*const)(int)");
+        assert_eq!(parameter_type("__attribute__((unused)) int x"), "__attribute__((unused)) int x");
+    }
+
+    #[test]
+    fn signatures_compare_by_parameter_types() {
+        assert_eq!(
+            normalize_signature("void *kmalloc(size_t size,  gfp_t flags)", false),
+            "void*(size_t, gfp_t)"
+        );
+        assert_eq!(
+            normalize_signature("int f(const foo_t, int (*cb)(int))", false),
+            "int(const foo_t, int (*)(int))"
+        );
+        assert_eq!(
+            normalize_signature("void qsort(void *base, size_t n, size_t size, int (*compar)(const void *, const void *))", false),
+            normalize_signature("void qsort(void *, size_t, size_t, int (*cmp)(const void *a, const void *b))", false)
+        );
+        assert_eq!(normalize_signature("int  f(int a)", true), "int f(int a)");
+    }
+}
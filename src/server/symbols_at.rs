@@ -0,0 +1,66 @@
+use std::path::Path;
+use std::sync::mpsc::Receiver;
+use std::time::Instant;
+use crate::{history, html, projects, query};
+
+/// Searches the symbols that exist at a specific version, instead of their changes
+pub fn search_at(project: String, version: String, query: String, count: u64) -> Receiver<String> {
+    assert!(!project.contains("/"));
+    let (send, recv) = std::sync::mpsc::channel();
+
+    std::thread::spawn(move || {
+        let start = Instant::now();
+        let db_path = Path::new("sourcedigger-db").join(&project);
+
+        send.send("<link rel=\"stylesheet\" href=\"/static/results-inner.css\">".to_string()).unwrap();
+
+        let versions = history::read_versions(&db_path);
+        let version_idx = match history::version_index(&versions, &version) {
+            Some(idx) => idx,
+            None => {
+                send.send(format!("<p>Unknown version: {}</p>", html::escape(&version))).unwrap();
+                return;
+            }
+        };
+        let regex = match query::name_regex(&query) {
+            Ok(regex) => regex,
+            Err(e) => {
+                send.send(format!("<pre>{}</pre>", html::escape(&e.to_string()))).unwrap();
+                return;
+            }
+        };
+
+        let symbols = history::symbols_at(&db_path, &versions, version_idx, |r| regex.is_match(&r.name));
+        println!("- reconstruction took {:?}", start.elapsed());
+
+        let project_data = projects::get(&project);
+        let total = symbols.symbols.values().map(Vec::len).sum::<usize>();
+        let mut output = format!(
+            "<h2 class=h>{} &mdash; {} symbols</h2>\n",
+            html::escape(&versions[version_idx]),
+            total
+        );
+        for ((name, symbol_type), datas) in symbols.symbols.iter().take(count as usize) {
+            for data in datas {
+                output += &format!(
+                    "<div class=u><a href=# class={0}>{0}</a><a href=\"/{1}?q={2}\"><span></span><span>{3}</span></a>&nbsp;<code>{4}</code><hr><a href=\"{5}\">{6}:{7}</a></div>\n",
+                    history::symbol_type_letter(*symbol_type),
+                    &project,
+                    url::form_urlencoded::byte_serialize(name.as_bytes()).collect::<String>(),
+                    html::escape(name),
+                    html::escape(&history::format_signature(name, *symbol_type, &data.extra)),
+                    project_data.source_link(&versions[version_idx], &data.file, data.line),
+                    html::escape(&data.file),
+                    data.line
+                );
+            }
+        }
+        if symbols.symbols.len() > count as usize {
+            output += &format!("<p>{} more symbols not shown</p>\n", symbols.symbols.len() - count as usize);
+        }
+        send.send(output).unwrap();
+        println!("- request took {:?}", start.elapsed());
+    });
+
+    recv
+}
@@ -0,0 +1,67 @@
+use std::sync::mpsc::Receiver;
+use std::time::Instant;
+use crate::history::{self, DiffAction, SymbolSet};
+use crate::{diffs, html, projects};
+
+/// One symbol's full history, oldest first
+pub fn get_timeline(project: String, name: String) -> Receiver<String> {
+    assert!(!project.contains("/"));
+    let (send, recv) = std::sync::mpsc::channel();
+
+    std::thread::spawn(move || {
+        let start = Instant::now();
+
+        send.send(format!(
+            "<!DOCTYPE html>\n<meta charset=\"UTF-8\">\n<title>{0} - {1} - SourceDigger</title>\n<link rel=\"stylesheet\" href=\"/static/results-inner.css\">\n<h1 class=source>{0} <small>{1}</small></h1>\n",
+            html::escape(&name),
+            html::escape(&project)
+        )).unwrap();
+
+        let name_history = match diffs::name_history(&project, &name) {
+            Some(name_history) => name_history,
+            None => {
+                send.send("<p class=summary>Looking up the history took too long</p>\n".to_string()).unwrap();
+                return;
+            }
+        };
+
+        let project_data = projects::get(&project);
+        let mut output = String::new();
+        if name_history.is_empty() {
+            output += "<p>No history found</p>\n";
+        }
+
+        // The definitions so far, for telling moves apart from modifications
+        let mut definitions = SymbolSet::default();
+        for hits in name_history {
+            output += &format!("<h3>{}</h3>\n", html::escape(&hits.version));
+
+            for record in hits.records {
+                let in_same_file = definitions.symbols.get(&(record.name.clone(), record.symbol_type))
+                    .is_some_and(|datas| datas.iter().any(|d| d.file == record.data.file));
+                definitions.apply(&record);
+                let (class, description) = match record.action {
+                    DiffAction::Add => ("a", "added"),
+                    DiffAction::Remove => ("r", "removed"),
+                    DiffAction::Modify if !in_same_file => ("m", "moved"),
+                    DiffAction::Modify => ("m", "modified"),
+                };
+                output += &format!(
+                    "<div class={0}><a href=# class={1}>{1}</a><a href=# class={0}>{2}</a><a><code>{3}</code></a><hr><a href=\"{4}\">{5}:{6}</a></div>\n",
+                    class,
+                    history::symbol_type_letter(record.symbol_type),
+                    description,
+                    html::escape(&history::format_signature(&record.name, record.symbol_type, &record.data.extra)),
+                    project_data.source_link(&hits.version, &record.data.file, record.data.line),
+                    html::escape(&record.data.file),
+                    record.data.line
+                );
+            }
+        }
+
+        send.send(output).unwrap();
+        println!("- request took {:?}", start.elapsed());
+    });
+
+    recv
+}
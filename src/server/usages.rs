@@ -0,0 +1,161 @@
+use crate::references::{self, References};
+use crate::version_files;
+use crate::{history, html, projects};
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/**
+ * Where a name is used in one file
+ */
+#[derive(Serialize)]
+pub struct FileUsages {
+    pub path: String,
+    pub calls: Vec<u64>,
+    pub others: Vec<u64>,
+    /// The text of each line, in the order of `calls` then `others`. Empty if the extracted objects aren't there.
+    pub context: Vec<String>,
+}
+
+/**
+ * The references to a name across the files of one version
+ */
+#[derive(Serialize)]
+pub struct Usages {
+    pub name: String,
+    pub version: String,
+    pub files: Vec<FileUsages>,
+    /// Whether looking through the files took too long, leaving some out
+    pub timed_out: bool,
+}
+
+/// Every file of the version is looked at, so big projects can take a while
+const USAGES_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The lines the blob defines `name` on, which aren't references to it
+fn definition_lines(db_path: &Path, oid: &str, name: &str) -> Vec<u64> {
+    let file = match File::open(db_path.join("tags").join(oid)) {
+        Ok(file) => file,
+        Err(_) => return vec![],
+    };
+    let prefix = format!("{}\t", name);
+    BufReader::new(file).lines()
+        .map(|line| line.unwrap())
+        .take_while(|line| !references::is_reference_line(line))
+        .filter(|line| line.starts_with(&prefix))
+        .filter_map(|line| line.split('\t').nth(2).and_then(|l| l.parse().ok()))
+        .collect()
+}
+
+fn line_context(db_path: &Path, oid: &str, lines: &[u64]) -> Vec<String> {
+    let content = match std::fs::read(db_path.join("objects").join(oid)) {
+        Ok(content) => content,
+        Err(_) => return vec![],
+    };
+    let text = String::from_utf8_lossy(&content);
+    let source_lines = text.lines().collect::<Vec<_>>();
+    lines.iter()
+        .map(|line| (*line as usize).checked_sub(1).and_then(|i| source_lines.get(i)).map(|l| l.trim().to_string()).unwrap_or_default())
+        .collect()
+}
+
+impl Usages {
+    /// At `version`, or the latest one. `before` looks at the version before it instead,
+    /// e.g. for the callers of a function that `version` removed.
+    pub fn find(project: &str, name: &str, version: Option<&str>, before: bool) -> Result<Self, String> {
+        let start = Instant::now();
+        let db_path = Path::new("sourcedigger-db").join(project);
+        if !projects::get(project).references {
+            return Err(format!("{} isn't indexed with references, they need `references = true` in its config.toml", project));
+        }
+        let versions = history::read_versions(&db_path);
+        let idx = match version {
+            Some(version) => history::version_index(&versions, version).ok_or_else(|| format!("Unknown version: {}", version))?,
+            None => versions.len().checked_sub(1).ok_or_else(|| format!("{} has no indexed versions", project))?,
+        };
+        let idx = if before {
+            idx.checked_sub(1).ok_or_else(|| format!("{} is the first version", versions[idx]))?
+        } else {
+            idx
+        };
+        let version = &versions[idx];
+        let files = version_files::read_version_files(&db_path, version)
+            .ok_or_else(|| format!("The file list of {} wasn't written, updating the index writes it", version))?;
+
+        let mut usages = Usages { name: name.to_string(), version: version.clone(), files: vec![], timed_out: false };
+        for (path, oid) in files {
+            if start.elapsed() > USAGES_TIMEOUT {
+                usages.timed_out = true;
+                break;
+            }
+            let References { calls, others, .. } = match references::read_references(&db_path, &oid, name) {
+                Some(references) => references,
+                None => continue,
+            };
+            let definitions = definition_lines(&db_path, &oid, name);
+            let calls = calls.into_iter().filter(|l| !definitions.contains(l)).collect::<Vec<_>>();
+            let others = others.into_iter().filter(|l| !definitions.contains(l)).collect::<Vec<_>>();
+            if calls.is_empty() && others.is_empty() {
+                continue;
+            }
+            let context = line_context(&db_path, &oid, &calls.iter().chain(others.iter()).cloned().collect::<Vec<_>>());
+            usages.files.push(FileUsages { path, calls, others, context });
+        }
+        usages.files.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(usages)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    pub fn to_html(&self, project: &str) -> String {
+        let project_data = projects::get(project);
+        let call_count = self.files.iter().map(|f| f.calls.len()).sum::<usize>();
+        let other_count = self.files.iter().map(|f| f.others.len()).sum::<usize>();
+        let mut output = format!(
+            "<!DOCTYPE html>\n<meta charset=\"UTF-8\">\n<title>{0} - {1} - SourceDigger</title>\n<link rel=\"stylesheet\" href=\"/static/results-inner.css\">\n\
+            <h1 class=source>{0} <small>{1}</small></h1>\n<p class=summary>{2} call sites, {3} other references in {4} files</p>\n",
+            html::escape(&self.name),
+            html::escape(&self.version),
+            call_count,
+            other_count,
+            self.files.len(),
+        );
+        if self.timed_out {
+            output += "<p class=summary>Looking through the files took too long, so some are missing</p>\n";
+        }
+        for file in self.files.iter() {
+            output += &format!("<h2 class=h>{} <small>{}</small></h2>\n", html::escape(&file.path), file.calls.len() + file.others.len());
+            let lines = file.calls.iter().map(|l| (*l, "call")).chain(file.others.iter().map(|l| (*l, "ref")));
+            for (i, (line, kind)) in lines.enumerate() {
+                output += &format!(
+                    "<div class=usage><a href=\"{}\">{}</a><a class={2}>{2}</a><code>{3}</code></div>\n",
+                    project_data.source_link(&self.version, &file.path, line),
+                    line,
+                    kind,
+                    html::escape(file.context.get(i).map(String::as_str).unwrap_or("")),
+                );
+            }
+        }
+        output
+    }
+}
+
+/// The link shown on added and removed rows of the search results: where an added symbol is used,
+/// or what used a removed one in the version before
+pub fn link(project: &str, version: &str, name: &str, removed: bool) -> String {
+    let mut params = url::form_urlencoded::Serializer::new(String::new());
+    params.append_pair("version", version);
+    if removed {
+        params.append_pair("before", "true");
+    }
+    format!(
+        "/{}/references/{}?{}",
+        project,
+        url::form_urlencoded::byte_serialize(name.as_bytes()).collect::<String>(),
+        params.finish()
+    )
+}
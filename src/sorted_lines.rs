@@ -0,0 +1,104 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Seek, SeekFrom};
+
+/// Reads the line at `offset`, returning its length with the newline, 0 at the end of the file.
+/// Bytes rather than text, since the binary search can land inside a multibyte character.
+fn read_line_at(file: &mut BufReader<File>, offset: u64, line: &mut Vec<u8>) -> u64 {
+    line.clear();
+    if file.seek(SeekFrom::Start(offset)).is_err() {
+        return 0;
+    }
+    file.read_until(b'\n', line).unwrap_or(0) as u64
+}
+
+fn first_field(line: &[u8]) -> &[u8] {
+    line.split(|b| *b == b'\t' || *b == b'\n').next().unwrap_or(b"")
+}
+
+/**
+ * The lines between the byte offsets `start` and `end` whose first tab separated field is `key`,
+ * in a file where those lines are sorted by it. Only a few lines are read, by binary search.
+ */
+pub fn find_lines(file: &mut BufReader<File>, start: u64, end: u64, key: &str) -> Vec<String> {
+    let key = key.as_bytes();
+    let mut line = vec![];
+    // Every line before `lo` has a smaller key, every line from `hi` on doesn't
+    let (mut lo, mut hi) = (start, end);
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        // The first line starting after `mid`, or the one at `lo` if there's none before `hi`
+        let mut line_start = lo;
+        if mid != lo {
+            let next_line = mid + read_line_at(file, mid, &mut line);
+            if next_line < hi {
+                line_start = next_line;
+            }
+        }
+        let len = read_line_at(file, line_start, &mut line);
+        if len == 0 {
+            break;
+        }
+        if first_field(&line) < key {
+            lo = line_start + len;
+        } else {
+            hi = line_start;
+        }
+    }
+
+    let mut lines = vec![];
+    let mut offset = lo;
+    while offset < end {
+        let len = read_line_at(file, offset, &mut line);
+        if len == 0 || first_field(&line) != key {
+            break;
+        }
+        let text = line.strip_suffix(b"\n").unwrap_or(&line);
+        lines.push(String::from_utf8_lossy(text).into_owned());
+        offset += len;
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_are_found_by_key() {
+        let path = std::env::temp_dir().join(format!("sourcedigger-sorted-lines-{}", std::process::id()));
+        let content = "header\nFOO\t0\na\t1\nkmalloc\t2\nkmalloc\t3\nkmalloc_node\t4\nz\t5\ntrailer\n";
+        std::fs::write(&path, content).unwrap();
+        let (start, end) = (7, content.len() as u64 - 8);
+
+        let mut file = BufReader::new(File::open(&path).unwrap());
+        let mut find = |key: &str| find_lines(&mut file, start, end, key);
+        assert_eq!(find("kmalloc"), vec!["kmalloc\t2", "kmalloc\t3"]);
+        assert_eq!(find("FOO"), vec!["FOO\t0"]);
+        assert_eq!(find("z"), vec!["z\t5"]);
+        assert_eq!(find("kmal"), Vec::<String>::new());
+        assert_eq!(find("header"), Vec::<String>::new());
+        assert_eq!(find("trailer"), Vec::<String>::new());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn lines_with_multibyte_characters_are_found() {
+        let path = std::env::temp_dir().join(format!("sourcedigger-sorted-lines-utf8-{}", std::process::id()));
+        let mut names = (0..300).map(|i| format!("name{:03}", i)).collect::<Vec<_>>();
+        names.push("émoi".to_string());
+        names.sort();
+        let mut content = String::new();
+        for (i, name) in names.iter().enumerate() {
+            content += &format!("{}\tdrivers/café/{}.c\t{}\tvoid {{name}}(const char *résumé)\n", name, "é".repeat(i % 7), i);
+        }
+        std::fs::write(&path, &content).unwrap();
+
+        let mut file = BufReader::new(File::open(&path).unwrap());
+        for name in names.iter() {
+            let lines = find_lines(&mut file, 0, content.len() as u64, name);
+            assert_eq!(lines.len(), 1, "{}", name);
+            assert!(lines[0].starts_with(&format!("{}\t", name)));
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,24 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+/**
+ * `files/<version>` lists the indexed files of a version, `path\tblob oid` per line, so the server
+ * can find a version's blobs in `objects/` and `refs/` without the repository
+ */
+pub fn version_files_path(db_path: &Path, version: &str) -> PathBuf {
+    db_path.join("files").join(version.replace("/", "-"))
+}
+
+/// The (path, hex oid) pairs of a version, `None` if its list wasn't written
+#[allow(dead_code)]
+pub fn read_version_files(db_path: &Path, version: &str) -> Option<Vec<(String, String)>> {
+    let file = File::open(version_files_path(db_path, version)).ok()?;
+    Some(BufReader::new(file).lines()
+        .filter_map(|line| {
+            let line = line.unwrap();
+            let mut parts = line.splitn(2, '\t');
+            Some((parts.next()?.to_string(), parts.next()?.to_string()))
+        })
+        .collect())
+}
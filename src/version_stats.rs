@@ -0,0 +1,164 @@
+use crate::history::{self, DiffAction, SymbolSet};
+use ctags::SymbolType;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+/**
+ * How many definitions of one kind there are in a version, and how many its diff added, removed and modified
+ */
+#[derive(Serialize, Debug, Default, Clone, Copy)]
+pub struct KindStats {
+    pub total: usize,
+    pub added: usize,
+    pub removed: usize,
+    pub modified: usize,
+}
+
+#[allow(dead_code)]
+impl KindStats {
+    pub fn churn(&self) -> usize {
+        self.added + self.removed + self.modified
+    }
+
+    pub fn add(&mut self, other: &KindStats) {
+        self.total += other.total;
+        self.added += other.added;
+        self.removed += other.removed;
+        self.modified += other.modified;
+    }
+}
+
+/**
+ * One line of the `stats` file: `version\tdirectory\tKind\ttotal\tadded\tremoved\tmodified`,
+ * for every version, top-level directory and kind that has any definitions or changes.
+ * Files at the top level have an empty directory.
+ */
+#[allow(dead_code)]
+#[derive(Debug)]
+pub struct StatsLine {
+    pub version: String,
+    pub directory: String,
+    pub kind: String,
+    pub stats: KindStats,
+}
+
+#[allow(dead_code)]
+impl StatsLine {
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut components = line.split('\t');
+        let version = components.next()?.to_string();
+        let directory = components.next()?.to_string();
+        let kind = components.next()?.to_string();
+        let mut numbers = components.map(|n| n.parse::<usize>());
+        let mut next = || numbers.next()?.ok();
+        let stats = KindStats { total: next()?, added: next()?, removed: next()?, modified: next()? };
+        Some(StatsLine { version, directory, kind, stats })
+    }
+}
+
+/// The first path component, e.g. `drivers/` for `drivers/net/tun.c`
+pub fn top_level_directory(file: &str) -> &str {
+    match file.find('/') {
+        Some(idx) => &file[..idx + 1],
+        None => "",
+    }
+}
+
+/// Replays every diff, keeping running totals per directory and kind
+#[allow(dead_code)]
+pub fn write_stats(db_path: &Path, versions: &[String]) {
+    let mut symbols = SymbolSet::default();
+    let mut totals: BTreeMap<(String, SymbolType), usize> = BTreeMap::new();
+    // The server may be reading the old file, so it's replaced rather than overwritten
+    let tmp_path = db_path.join("stats.tmp");
+    let mut out_file = BufWriter::new(File::create(&tmp_path).unwrap());
+
+    let mut diffs = history::DiffReader::new(db_path);
+    for version in versions {
+        let mut changes: BTreeMap<(String, SymbolType), KindStats> = BTreeMap::new();
+        for record in diffs.read(version) {
+            let key = (record.name.clone(), record.symbol_type);
+            // Every definition is recounted, so totals follow symbols moving between directories
+            for data in symbols.symbols.get(&key).into_iter().flatten() {
+                *totals.get_mut(&(top_level_directory(&data.file).to_string(), record.symbol_type)).unwrap() -= 1;
+            }
+            symbols.apply(&record);
+            for data in symbols.symbols.get(&key).into_iter().flatten() {
+                *totals.entry((top_level_directory(&data.file).to_string(), record.symbol_type)).or_insert(0) += 1;
+            }
+
+            let change = changes.entry((top_level_directory(&record.data.file).to_string(), record.symbol_type)).or_default();
+            match record.action {
+                DiffAction::Add => change.added += 1,
+                DiffAction::Remove => change.removed += 1,
+                DiffAction::Modify => change.modified += 1,
+            }
+        }
+
+        let keys = totals.iter()
+            .filter(|(_, total)| **total != 0)
+            .map(|(key, _)| key)
+            .chain(changes.keys())
+            .collect::<BTreeSet<_>>();
+        for key in keys {
+            let mut stats = changes.get(key).copied().unwrap_or_default();
+            stats.total = totals.get(key).copied().unwrap_or(0);
+            out_file.write_all(format!(
+                "{}\t{}\t{:?}\t{}\t{}\t{}\t{}\n",
+                version, key.0, key.1, stats.total, stats.added, stats.removed, stats.modified
+            ).as_bytes()).unwrap();
+        }
+    }
+    out_file.flush().unwrap();
+    drop(out_file);
+    std::fs::rename(&tmp_path, db_path.join("stats")).unwrap();
+}
+
+/// `None` for databases indexed before the stats existed
+#[allow(dead_code)]
+pub fn read_stats(db_path: &Path) -> Option<Vec<StatsLine>> {
+    let file = File::open(db_path.join("stats")).ok()?;
+    Some(BufReader::new(file).lines().filter_map(|l| StatsLine::parse(&l.unwrap())).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn totals_follow_moved_symbols() {
+        let db_path = std::env::temp_dir().join(format!("sourcedigger-stats-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&db_path);
+        std::fs::create_dir_all(db_path.join("diffs")).unwrap();
+        let diffs = [
+            ("v1", "a\tfoo\tFunction\tdrivers/a.c\t1\tint {name}(void)\na\tbar\tFunction\tdrivers/a.c\t5\tint {name}(void)\n"),
+            // Moved to another top-level directory
+            ("v2", "m\tfoo\tFunction\tfs/b.c\t3\tint {name}(void)\n"),
+            ("v3", "r\tbar\tFunction\tdrivers/a.c\t5\tint {name}(void)\n"),
+        ];
+        for (version, diff) in diffs.iter() {
+            std::fs::write(db_path.join("diffs").join(version), diff).unwrap();
+        }
+        let versions = diffs.iter().map(|(version, _)| version.to_string()).collect::<Vec<_>>();
+        write_stats(&db_path, &versions);
+
+        let stats = read_stats(&db_path).unwrap().into_iter()
+            .map(|l| (l.version, l.directory, l.kind, l.stats.total, l.stats.added, l.stats.removed, l.stats.modified))
+            .collect::<Vec<_>>();
+        let row = |version: &str, directory: &str, total, added, removed, modified| {
+            (version.to_string(), directory.to_string(), "Function".to_string(), total, added, removed, modified)
+        };
+        assert_eq!(stats, vec![
+            row("v1", "drivers/", 2, 2, 0, 0),
+            row("v2", "drivers/", 1, 0, 0, 0),
+            row("v2", "fs/", 1, 0, 0, 1),
+            row("v3", "drivers/", 0, 0, 1, 0),
+            row("v3", "fs/", 1, 0, 0, 0),
+        ]);
+        assert!(!db_path.join("stats.tmp").exists());
+        std::fs::remove_dir_all(&db_path).unwrap();
+    }
+}
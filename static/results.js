@@ -3,6 +3,7 @@ let query_input = document.getElementById("query");
 let results_frame = document.getElementById("results");
 let autocomplete_container = document.getElementById("autocomplete");
 let autocomplete_items = document.getElementById("autocomplete-results");
+let fuzzy_input = document.getElementById("fuzzy");
 
 function update_query(changes) {
     if (!changes) {
@@ -51,11 +52,35 @@ query_form.addEventListener("submit", (e) => {
 });
 
 results_frame.addEventListener("load", () => {
+    // Directory facets are only known at the end of the results
+    let facets = results_frame.contentDocument.querySelector("ul.facets");
+    if (facets) {
+        let lifetimes = results_frame.contentDocument.querySelector("ul.lifetimes");
+        if (lifetimes) {
+            lifetimes.after(facets);
+        } else {
+            results_frame.contentDocument.body.prepend(facets);
+        }
+    }
+
     results_frame.contentDocument.body.addEventListener("click", (e) => {
         if (e.target.tagName !== "A") {
             return;
         }
 
+        if (e.target.className === "more") {
+            load_more(e.target);
+            e.preventDefault();
+            return false;
+        }
+
+        // Links out of the results, e.g. suggestions and symbol pages
+        if (e.target.target === "_top") {
+            location.href = e.target.href;
+            e.preventDefault();
+            return false;
+        }
+
         if (results_frame.contentDocument.location.href.split("?")[1] == "") {
             location.href = e.target.href;
         } else {
@@ -77,7 +102,7 @@ results_frame.addEventListener("load", () => {
             } else if (i === 1 && elem.className) {
                 update_query({ "a": elem.className });
             } else if (i === 2) {
-                update_query({ "q": new URL(elem.href).searchParams.get("q") });
+                update_query({ "q": new URL(elem.href).searchParams.get("q"), "mode": "name" });
             } else {
                 location.href = elem.href;
             }
@@ -88,6 +113,14 @@ results_frame.addEventListener("load", () => {
     });
 });
 
+async function load_more(link) {
+    let paragraph = link.parentNode;
+    paragraph.innerText = "Loading...";
+    let html = await (await fetch(link.href)).text();
+    paragraph.insertAdjacentHTML("afterend", html);
+    paragraph.remove();
+}
+
 let timer = null;
 let last_autocomplete_query = "";
 
@@ -95,7 +128,12 @@ async function fetch_autocomplete(query) {
     console.log(query);
     let url = new URL(location.href);
     url.pathname += "/autocomplete";
-    url.searchParams.set("q", query + ".*");
+    if (fuzzy_input.checked) {
+        url.searchParams.set("q", query);
+        url.searchParams.set("fuzzy", "true");
+    } else {
+        url.searchParams.set("q", query + ".*");
+    }
     // console.log(url);
     let results = (await (await fetch(url.toString())).text()).split("\n");
     let links = [];
@@ -136,6 +174,14 @@ query_input.addEventListener("keyup", () => {
     try_autocomplete()
 });
 
+fuzzy_input.checked = localStorage.getItem("fuzzy") === "true";
+fuzzy_input.addEventListener("change", () => {
+    localStorage.setItem("fuzzy", fuzzy_input.checked);
+    last_autocomplete_query = "";
+    query_input.focus();
+    try_autocomplete();
+});
+
 function handle_ac_link(e) {
     update_query({ "q": e.innerText });
 }